@@ -0,0 +1,58 @@
+// Compares emulate_op with emulate_block on a small loop, run with
+//  cargo bench --bench blocks
+// Each way runs SAMPLES times and the median is reported, so one slow run doesn't skew it. The
+// traced leg stands in for the per-op path before blocks, which disassembled and formatted every
+// instruction it ran.
+use rusty8080::State8080;
+use rusty8080::TextTraceSink;
+use std::io;
+use std::time::Duration;
+use std::time::Instant;
+
+const CYCLES: u64 = 20_000_000;
+const SAMPLES: usize = 9;
+
+// Adds up 1 to 0x10 in a, stores the total at 0x2000 and starts again
+const SUM: [u8; 13] = [
+    0x0e, 0x10,       // MVI C, 0x10
+    0x81,             // ADD C
+    0x0d,             // DCR C
+    0xc2, 0x02, 0x00, // JNZ 0x0002
+    0x32, 0x00, 0x20, // STA 0x2000
+    0xc3, 0x00, 0x00, // JMP 0x0000
+];
+
+fn time(traced: bool, step: fn(&mut State8080) -> u32) -> Duration {
+    let mut samples: Vec<Duration> = (0..SAMPLES)
+        .map(|_| {
+            let mut state = State8080::builder().program(SUM.to_vec()).build();
+            if traced {
+                state.set_trace_sink(Box::new(TextTraceSink::new(io::sink())));
+            }
+            let start = Instant::now();
+            while state.cycles() < CYCLES {
+                step(&mut state);
+            }
+            start.elapsed()
+        })
+        .collect();
+    samples.sort();
+    return samples[SAMPLES / 2];
+}
+
+fn main() {
+    let formatted = time(true, |state| state.emulate_op().unwrap());
+    let stepped = time(false, |state| state.emulate_op().unwrap());
+    let blocked = time(false, |state| state.emulate_block().unwrap());
+
+    let mhz = |time: Duration| CYCLES as f64 / time.as_secs_f64() / 1_000_000.0;
+    let speed_up = |time: Duration| time.as_secs_f64() / blocked.as_secs_f64();
+    println!("emulate_op, traced: {:?} ({:.0} MHz)", formatted, mhz(formatted));
+    println!("emulate_op:         {:?} ({:.0} MHz)", stepped, mhz(stepped));
+    println!("emulate_block:      {:?} ({:.0} MHz)", blocked, mhz(blocked));
+    println!(
+        "speed up:           {:.1}x over traced, {:.1}x over emulate_op",
+        speed_up(formatted),
+        speed_up(stepped)
+    );
+}
@@ -0,0 +1,760 @@
+use crate::emulator::config::CpuVariant;
+use crate::emulator::registers::Flag;
+use crate::emulator::registers::Reg;
+use crate::emulator::registers::RegPair;
+use std::fmt;
+
+mod z80;
+
+// Number of T-states (2 MHz clock ticks) each op code takes on the 8080. Conditional calls and
+// returns are listed with their not taken count, taking them costs CONDITIONAL_TAKEN_EXTRA more.
+pub const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    4,  10, 7,  5,  5,  5,  7,  4,  4,  10, 7,  5,  5,  5,  7,  4,  // 0
+    4,  10, 7,  5,  5,  5,  7,  4,  4,  10, 7,  5,  5,  5,  7,  4,  // 1
+    4,  10, 16, 5,  5,  5,  7,  4,  4,  10, 16, 5,  5,  5,  7,  4,  // 2
+    4,  10, 13, 5,  10, 10, 10, 4,  4,  10, 13, 5,  5,  5,  7,  4,  // 3
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,  // 4
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,  // 5
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,  // 6
+    7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5,  // 7
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 8
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 9
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // a
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // b
+    5,  10, 10, 10, 11, 11, 7,  11, 5,  10, 10, 10, 11, 17, 7,  11, // c
+    5,  10, 10, 10, 11, 11, 7,  11, 5,  10, 10, 10, 11, 17, 7,  11, // d
+    5,  10, 10, 18, 11, 11, 7,  11, 5,  5,  10, 4,  11, 17, 7,  11, // e
+    5,  10, 10, 4,  11, 11, 7,  11, 5,  5,  10, 4,  11, 17, 7,  11, // f
+];
+
+// A taken CALL cc is 17 instead of 11 and a taken RET cc is 11 instead of 5
+pub const CONDITIONAL_TAKEN_EXTRA: u32 = 6;
+
+// The same for the 8085, which is quicker at moves and increments but slower at stack work.
+// Conditional jumps, calls and returns are listed not taken, see CONDITIONAL_TAKEN_EXTRA_8085.
+// Its undocumented op codes aren't emulated, they go to the UnknownOpcodePolicy and are counted
+// like a NOP when it skips them.
+pub const CYCLES_8085: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    4,  10, 7,  6,  4,  4,  7,  4,  4,  10, 7,  6,  4,  4,  7,  4,  // 0
+    4,  10, 7,  6,  4,  4,  7,  4,  4,  10, 7,  6,  4,  4,  7,  4,  // 1
+    4,  10, 16, 6,  4,  4,  7,  4,  4,  10, 16, 6,  4,  4,  7,  4,  // 2
+    4,  10, 13, 6,  10, 10, 10, 4,  4,  10, 13, 6,  4,  4,  7,  4,  // 3
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 4
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 5
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 6
+    7,  7,  7,  7,  7,  7,  5,  7,  4,  4,  4,  4,  4,  4,  7,  4,  // 7
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 8
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 9
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // a
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // b
+    6,  10, 7,  10, 9,  12, 7,  12, 6,  10, 7,  4,  9,  18, 7,  12, // c
+    6,  10, 7,  10, 9,  12, 7,  12, 6,  4,  7,  10, 9,  4,  7,  12, // d
+    6,  10, 7,  16, 9,  12, 7,  12, 6,  6,  7,  4,  9,  4,  7,  12, // e
+    6,  10, 7,  4,  9,  12, 7,  12, 6,  6,  7,  4,  9,  4,  7,  12, // f
+];
+
+// A taken J cc is 10 instead of 7, C cc 18 instead of 9 and R cc 12 instead of 6
+const JUMP_TAKEN_EXTRA_8085: u32 = 3;
+const CALL_TAKEN_EXTRA_8085: u32 = 9;
+const RETURN_TAKEN_EXTRA_8085: u32 = 6;
+
+// The same for the Z80's unprefixed op codes, the prefixed ones are worked out in decoder::z80.
+// DJNZ, JR cc, CALL cc and RET cc are listed not taken. The prefixes themselves are 0.
+pub const CYCLES_Z80: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   a   b   c   d   e   f
+    4,  10, 7,  6,  4,  4,  7,  4,  4,  11, 7,  6,  4,  4,  7,  4,  // 0
+    8,  10, 7,  6,  4,  4,  7,  4,  12, 11, 7,  6,  4,  4,  7,  4,  // 1
+    7,  10, 16, 6,  4,  4,  7,  4,  7,  11, 16, 6,  4,  4,  7,  4,  // 2
+    7,  10, 13, 6,  11, 11, 10, 4,  7,  11, 13, 6,  4,  4,  7,  4,  // 3
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 4
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 5
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 6
+    7,  7,  7,  7,  7,  7,  4,  7,  4,  4,  4,  4,  4,  4,  7,  4,  // 7
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 8
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // 9
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // a
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,  // b
+    5,  10, 10, 10, 10, 11, 7,  11, 5,  10, 10, 0,  10, 17, 7,  11, // c
+    5,  10, 10, 11, 10, 11, 7,  11, 5,  4,  10, 11, 10, 0,  7,  11, // d
+    5,  10, 10, 19, 10, 11, 7,  11, 5,  4,  10, 4,  10, 0,  7,  11, // e
+    5,  10, 10, 4,  10, 11, 7,  11, 5,  6,  10, 4,  10, 0,  7,  11, // f
+];
+
+// Where an 8 bit operand comes from, in the order the op codes encode them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    M, // The byte in memory that HL points at
+    A,
+    IX(i8), // Z80 only, the byte at IX plus the offset
+    IY(i8), // Z80 only, the byte at IY plus the offset
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    NZ,
+    Z,
+    NC,
+    C,
+    PO,
+    PE,
+    P,
+    M,
+}
+
+// The eight operations of the 0x80-0xbf block and their immediate versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Adc,
+    Sub,
+    Sbb,
+    Ana,
+    Xra,
+    Ora,
+    Cmp,
+}
+
+// The shifts and rotates of the Z80's CB page, in op code order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotOp {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Sll, // Undocumented, shifts a 1 in
+    Srl,
+}
+
+// The Z80's block copy, compare, input and output instructions, in op code order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Ldi,
+    Cpi,
+    Ini,
+    Outi,
+    Ldd,
+    Cpd,
+    Ind,
+    Outd,
+    Ldir,
+    Cpir,
+    Inir,
+    Otir,
+    Lddr,
+    Cpdr,
+    Indr,
+    Otdr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Nop,
+    Lxi(RegPair, u16),
+    Stax(RegPair),
+    Ldax(RegPair),
+    Shld(u16),
+    Lhld(u16),
+    Sta(u16),
+    Lda(u16),
+    Inx(RegPair),
+    Dcx(RegPair),
+    Dad(RegPair),
+    Inr(Operand),
+    Dcr(Operand),
+    Mvi(Operand, u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Daa,
+    Cma,
+    Stc,
+    Cmc,
+    Mov(Operand, Operand), // Destination, source
+    Hlt,
+    Alu(AluOp, Operand),
+    AluImmediate(AluOp, u8),
+    Jmp(u16),
+    Jcc(Condition, u16),
+    Call(u16),
+    Ccc(Condition, u16),
+    Ret,
+    Rcc(Condition),
+    Rst(u8),
+    Push(RegPair),
+    Pop(RegPair),
+    Xthl,
+    Pchl,
+    Sphl,
+    Xchg,
+    Out(u8),
+    In(u8),
+    Ei,
+    Di,
+    Rim, // 8085 only
+    Sim, // 8085 only
+    // Z80 only from here on, relative jumps hold the address they go to
+    ExAf,
+    Exx,
+    Djnz(u16),
+    Jr(u16),
+    Jrcc(Condition, u16), // Only NZ, Z, NC and C
+    Rot(RotOp, Operand),
+    Bit(u8, Operand),
+    Res(u8, Operand),
+    Set(u8, Operand),
+    InC(Operand), // Port C
+    OutC(Operand),
+    AdcHl(RegPair),
+    SbcHl(RegPair),
+    Store16(RegPair, u16),
+    Load16(RegPair, u16),
+    Neg,
+    Retn,
+    Reti,
+    Im(u8),
+    LdIA,
+    LdRA,
+    LdAI,
+    LdAR,
+    Rrd,
+    Rld,
+    Block(BlockOp),
+    AddIndex(RegPair, RegPair), // IX or IY, the pair added to it
+    ExSp(RegPair),
+    JpIndirect(RegPair),
+    LdSp(RegPair),
+    Unknown(u8), // Not an instruction on this cpu
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub opcode: u8,
+    pub op: Op,
+    pub length: u16,
+    pub bytes: [u8; 4], // As fetched, the ones past length are 0
+    pub variant: CpuVariant, // The cpu it was decoded for, the timings depend on it
+}
+
+const OPERANDS: [Operand; 8] = [
+    Operand::B,
+    Operand::C,
+    Operand::D,
+    Operand::E,
+    Operand::H,
+    Operand::L,
+    Operand::M,
+    Operand::A,
+];
+const CONDITIONS: [Condition; 8] = [
+    Condition::NZ,
+    Condition::Z,
+    Condition::NC,
+    Condition::C,
+    Condition::PO,
+    Condition::PE,
+    Condition::P,
+    Condition::M,
+];
+const ALU_OPS: [AluOp; 8] = [
+    AluOp::Add,
+    AluOp::Adc,
+    AluOp::Sub,
+    AluOp::Sbb,
+    AluOp::Ana,
+    AluOp::Xra,
+    AluOp::Ora,
+    AluOp::Cmp,
+];
+// LXI, INX, DCX and DAD use SP for pair 3, PUSH and POP use PSW
+const PAIRS: [RegPair; 4] = [RegPair::BC, RegPair::DE, RegPair::HL, RegPair::SP];
+const STACK_PAIRS: [RegPair; 4] = [RegPair::BC, RegPair::DE, RegPair::HL, RegPair::PSW];
+
+const ALL_FLAGS: &[Flag] = &[Flag::S, Flag::Z, Flag::AC, Flag::P, Flag::CY];
+const ALL_BUT_CARRY: &[Flag] = &[Flag::S, Flag::Z, Flag::AC, Flag::P];
+const CARRY: &[Flag] = &[Flag::CY];
+const NO_FLAGS: &[Flag] = &[];
+
+// Op codes Intel never documented. On the 8080 0x08-0x38 are copies of NOP, 0xcb of JMP, 0xd9 of
+// RET and 0xdd, 0xed and 0xfd of CALL.
+const UNDOCUMENTED_8080: [u8; 12] = [0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+// The 8085 turned 0x20 and 0x30 into RIM and SIM and the rest into instructions of its own, which
+// aren't emulated
+const UNDOCUMENTED_8085: [u8; 10] = [0x08, 0x10, 0x18, 0x28, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+
+// How many bytes the 8080 instruction starting with opcode takes, including the op code
+pub fn length(opcode: u8) -> u16 {
+    return match opcode {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a | 0xc3 | 0xcd => 3,
+        0xcb | 0xdd | 0xed | 0xfd => 3, // JMP and CALL aliases
+        0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e | 0xd3 | 0xdb => 2,
+        _ if opcode & 0xc7 == 0xc2 || opcode & 0xc7 == 0xc4 => 3, // Jcc, Ccc
+        _ if opcode & 0xc7 == 0xc6 => 2,                         // ADI..CPI
+        _ => 1,
+    };
+}
+
+// Whether the byte starts a longer op code, which means the second byte is needed to know the
+// instruction's length. Only the Z80 has these.
+pub fn is_prefix(variant: CpuVariant, byte: u8) -> bool {
+    return variant == CpuVariant::Z80 && (byte == 0xcb || byte == 0xdd || byte == 0xed || byte == 0xfd);
+}
+
+// Like length, for the instruction set of variant. second is the byte after the op code, it
+// only matters when is_prefix says so.
+pub fn length_for(variant: CpuVariant, opcode: u8, second: u8) -> u16 {
+    return match variant {
+        CpuVariant::Z80 => z80::length(opcode, second),
+        CpuVariant::Intel8085 if UNDOCUMENTED_8085.contains(&opcode) => 1,
+        _ => length(opcode),
+    };
+}
+
+// Decodes the 8080 instruction whose op code is bytes[0], it was fetched from address. Operand
+// bytes missing from the end of bytes read as 0.
+pub fn decode(bytes: &[u8], address: u16) -> Instruction {
+    return decode_for(CpuVariant::Intel8080, bytes, address);
+}
+
+// Like decode, for the instruction set of variant
+pub fn decode_for(variant: CpuVariant, bytes: &[u8], address: u16) -> Instruction {
+    if variant == CpuVariant::Z80 {
+        return z80::decode(bytes, address);
+    }
+    let byte = |i: usize| *bytes.get(i).unwrap_or(&0);
+    let code = byte(0);
+    let word = u16::from_le_bytes([byte(1), byte(2)]);
+    let operand = OPERANDS[(code as usize >> 3) & 0x7];
+    let source = OPERANDS[code as usize & 0x7];
+    let pair = PAIRS[(code as usize >> 4) & 0x3];
+    let condition = CONDITIONS[(code as usize >> 3) & 0x7];
+
+    let op = match code {
+        0x20 if variant == CpuVariant::Intel8085 => Op::Rim,
+        0x30 if variant == CpuVariant::Intel8085 => Op::Sim,
+        0x08 | 0x10 | 0x18 | 0x28 | 0x38 if variant == CpuVariant::Intel8085 => Op::Unknown(code),
+        0x00 | 0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Op::Nop,
+        0x02 | 0x12 => Op::Stax(pair),
+        0x0a | 0x1a => Op::Ldax(pair),
+        0x22 => Op::Shld(word),
+        0x2a => Op::Lhld(word),
+        0x32 => Op::Sta(word),
+        0x3a => Op::Lda(word),
+        0x07 => Op::Rlc,
+        0x0f => Op::Rrc,
+        0x17 => Op::Ral,
+        0x1f => Op::Rar,
+        0x27 => Op::Daa,
+        0x2f => Op::Cma,
+        0x37 => Op::Stc,
+        0x3f => Op::Cmc,
+        0x00..=0x3f => match code & 0x0f {
+            0x01 => Op::Lxi(pair, word),
+            0x03 => Op::Inx(pair),
+            0x09 => Op::Dad(pair),
+            0x0b => Op::Dcx(pair),
+            _ => match code & 0x07 {
+                0x04 => Op::Inr(operand),
+                0x05 => Op::Dcr(operand),
+                _ => Op::Mvi(operand, byte(1)),
+            },
+        },
+
+        0x76 => Op::Hlt,
+        0x40..=0x7f => Op::Mov(operand, source),
+        0x80..=0xbf => Op::Alu(ALU_OPS[(code as usize >> 3) & 0x7], source),
+
+        0xcb | 0xd9 | 0xdd | 0xed | 0xfd if variant == CpuVariant::Intel8085 => Op::Unknown(code),
+        0xc3 | 0xcb => Op::Jmp(word),
+        0xc9 | 0xd9 => Op::Ret,
+        0xcd | 0xdd | 0xed | 0xfd => Op::Call(word),
+        0xd3 => Op::Out(byte(1)),
+        0xdb => Op::In(byte(1)),
+        0xe3 => Op::Xthl,
+        0xe9 => Op::Pchl,
+        0xeb => Op::Xchg,
+        0xf3 => Op::Di,
+        0xf9 => Op::Sphl,
+        0xfb => Op::Ei,
+        _ => match code & 0x07 {
+            0x00 => Op::Rcc(condition),
+            0x01 => Op::Pop(STACK_PAIRS[(code as usize >> 4) & 0x3]),
+            0x02 => Op::Jcc(condition, word),
+            0x04 => Op::Ccc(condition, word),
+            0x05 => Op::Push(STACK_PAIRS[(code as usize >> 4) & 0x3]),
+            0x06 => Op::AluImmediate(ALU_OPS[(code as usize >> 3) & 0x7], byte(1)),
+            _ => Op::Rst((code >> 3) & 0x7),
+        },
+    };
+
+    let length = length_for(variant, code, 0);
+    let mut raw = [0; 4];
+    for (i, raw_byte) in raw.iter_mut().take(length as usize).enumerate() {
+        *raw_byte = byte(i);
+    }
+    return Instruction { address, opcode: code, op, length, bytes: raw, variant };
+}
+
+impl Instruction {
+    // Just the bytes that make up the instruction
+    pub fn encoding(&self) -> &[u8] {
+        return &self.bytes[..self.length as usize];
+    }
+
+    // Whether the op code is one the manufacturer never documented, the Z80 has none that are
+    // emulated
+    pub fn is_undocumented(&self) -> bool {
+        return match self.variant {
+            CpuVariant::Intel8080 => UNDOCUMENTED_8080.contains(&self.opcode),
+            CpuVariant::Intel8085 => UNDOCUMENTED_8085.contains(&self.opcode),
+            CpuVariant::Z80 => false,
+        };
+    }
+
+    // T-states when a conditional branch isn't taken, and for everything else
+    pub fn cycles(&self) -> u32 {
+        return match self.variant {
+            CpuVariant::Intel8080 => CYCLES[self.opcode as usize] as u32,
+            CpuVariant::Intel8085 => CYCLES_8085[self.opcode as usize] as u32,
+            CpuVariant::Z80 => z80::cycles(self),
+        };
+    }
+
+    // T-states when a conditional branch is taken
+    pub fn cycles_taken(&self) -> u32 {
+        let extra = match (self.variant, self.op) {
+            (CpuVariant::Intel8080, Op::Ccc(..)) | (CpuVariant::Intel8080, Op::Rcc(_)) => {
+                CONDITIONAL_TAKEN_EXTRA
+            }
+            (CpuVariant::Intel8085, Op::Jcc(..)) => JUMP_TAKEN_EXTRA_8085,
+            (CpuVariant::Intel8085, Op::Ccc(..)) => CALL_TAKEN_EXTRA_8085,
+            (CpuVariant::Intel8085, Op::Rcc(_)) => RETURN_TAKEN_EXTRA_8085,
+            (CpuVariant::Z80, op) => z80::taken_extra(op),
+            _ => 0,
+        };
+        return self.cycles() + extra;
+    }
+
+    // The flags the instruction can change. INR and DCR leave the carry alone.
+    pub fn affected_flags(&self) -> &'static [Flag] {
+        if self.variant == CpuVariant::Z80 {
+            return z80::affected_flags(self.op);
+        }
+        return match self.op {
+            Op::Alu(..) | Op::AluImmediate(..) | Op::Daa | Op::Pop(RegPair::PSW) => ALL_FLAGS,
+            Op::Inr(_) | Op::Dcr(_) => ALL_BUT_CARRY,
+            Op::Dad(_) | Op::Rlc | Op::Rrc | Op::Ral | Op::Rar | Op::Stc | Op::Cmc => CARRY,
+            _ => NO_FLAGS,
+        };
+    }
+}
+
+impl Operand {
+    // The register the operand names, None for the ones in memory
+    pub fn reg(&self) -> Option<Reg> {
+        return match self {
+            Operand::B => Some(Reg::B),
+            Operand::C => Some(Reg::C),
+            Operand::D => Some(Reg::D),
+            Operand::E => Some(Reg::E),
+            Operand::H => Some(Reg::H),
+            Operand::L => Some(Reg::L),
+            Operand::M | Operand::IX(_) | Operand::IY(_) => None,
+            Operand::A => Some(Reg::A),
+        };
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            Operand::IX(offset) => write!(f, "(IX{})", z80::offset(offset)),
+            Operand::IY(offset) => write!(f, "(IY{})", z80::offset(offset)),
+            _ => write!(f, "{:?}", self),
+        };
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{:?}", self);
+    }
+}
+
+// Pairs are written by their first register, like the Intel assembler does
+fn pair_name(pair: RegPair) -> &'static str {
+    return match pair {
+        RegPair::BC => "B",
+        RegPair::DE => "D",
+        RegPair::HL => "H",
+        RegPair::SP => "SP",
+        RegPair::PSW => "PSW",
+        RegPair::IX => "IX",
+        RegPair::IY => "IY",
+    };
+}
+
+fn alu_name(op: AluOp, immediate: bool) -> &'static str {
+    let (register, immediate_name) = match op {
+        AluOp::Add => ("ADD", "ADI"),
+        AluOp::Adc => ("ADC", "ACI"),
+        AluOp::Sub => ("SUB", "SUI"),
+        AluOp::Sbb => ("SBB", "SBI"),
+        AluOp::Ana => ("ANA", "ANI"),
+        AluOp::Xra => ("XRA", "XRI"),
+        AluOp::Ora => ("ORA", "ORI"),
+        AluOp::Cmp => ("CMP", "CPI"),
+    };
+    return if immediate { immediate_name } else { register };
+}
+
+// Mnemonics are padded so operands line up in a column after them
+fn write_instruction(f: &mut fmt::Formatter, mnemonic: &str, operands: &str) -> fmt::Result {
+    if operands.is_empty() {
+        return write!(f, "{}", mnemonic);
+    }
+    return write!(f, "{:7}{}", mnemonic, operands);
+}
+
+// Intel mnemonics, the Z80's extra instructions have none so they get their Zilog ones
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (mnemonic, operands) = match *self {
+            Op::Nop => ("NOP".to_string(), String::new()),
+            Op::Lxi(pair, word) => ("LXI".to_string(), format!("{},#${:04x}", pair_name(pair), word)),
+            Op::Stax(pair) => ("STAX".to_string(), pair_name(pair).to_string()),
+            Op::Ldax(pair) => ("LDAX".to_string(), pair_name(pair).to_string()),
+            Op::Shld(word) => ("SHLD".to_string(), format!("${:04x}", word)),
+            Op::Lhld(word) => ("LHLD".to_string(), format!("${:04x}", word)),
+            Op::Sta(word) => ("STA".to_string(), format!("${:04x}", word)),
+            Op::Lda(word) => ("LDA".to_string(), format!("${:04x}", word)),
+            Op::Inx(pair) => ("INX".to_string(), pair_name(pair).to_string()),
+            Op::Dcx(pair) => ("DCX".to_string(), pair_name(pair).to_string()),
+            Op::Dad(pair) => ("DAD".to_string(), pair_name(pair).to_string()),
+            Op::Inr(operand) => ("INR".to_string(), operand.to_string()),
+            Op::Dcr(operand) => ("DCR".to_string(), operand.to_string()),
+            Op::Mvi(operand, byte) => ("MVI".to_string(), format!("{},#${:02x}", operand, byte)),
+            Op::Rlc => ("RLC".to_string(), String::new()),
+            Op::Rrc => ("RRC".to_string(), String::new()),
+            Op::Ral => ("RAL".to_string(), String::new()),
+            Op::Rar => ("RAR".to_string(), String::new()),
+            Op::Daa => ("DAA".to_string(), String::new()),
+            Op::Cma => ("CMA".to_string(), String::new()),
+            Op::Stc => ("STC".to_string(), String::new()),
+            Op::Cmc => ("CMC".to_string(), String::new()),
+            Op::Mov(destination, source) => ("MOV".to_string(), format!("{},{}", destination, source)),
+            Op::Hlt => ("HLT".to_string(), String::new()),
+            Op::Alu(op, operand) => (alu_name(op, false).to_string(), operand.to_string()),
+            Op::AluImmediate(op, byte) => (alu_name(op, true).to_string(), format!("#${:02x}", byte)),
+            Op::Jmp(word) => ("JMP".to_string(), format!("${:04x}", word)),
+            Op::Jcc(condition, word) => (format!("J{}", condition), format!("${:04x}", word)),
+            Op::Call(word) => ("CALL".to_string(), format!("${:04x}", word)),
+            Op::Ccc(condition, word) => (format!("C{}", condition), format!("${:04x}", word)),
+            Op::Ret => ("RET".to_string(), String::new()),
+            Op::Rcc(condition) => (format!("R{}", condition), String::new()),
+            Op::Rst(n) => ("RST".to_string(), n.to_string()),
+            Op::Push(pair) => ("PUSH".to_string(), pair_name(pair).to_string()),
+            Op::Pop(pair) => ("POP".to_string(), pair_name(pair).to_string()),
+            Op::Xthl => ("XTHL".to_string(), String::new()),
+            Op::Pchl => ("PCHL".to_string(), String::new()),
+            Op::Sphl => ("SPHL".to_string(), String::new()),
+            Op::Xchg => ("XCHG".to_string(), String::new()),
+            Op::Out(port) => ("OUT".to_string(), format!("#${:02x}", port)),
+            Op::In(port) => ("IN".to_string(), format!("#${:02x}", port)),
+            Op::Ei => ("EI".to_string(), String::new()),
+            Op::Di => ("DI".to_string(), String::new()),
+            Op::Rim => ("RIM".to_string(), String::new()),
+            Op::Sim => ("SIM".to_string(), String::new()),
+            Op::Unknown(code) => ("DB".to_string(), format!("${:02x}", code)),
+            op => z80::zilog(op),
+        };
+        return write_instruction(f, &mnemonic, &operands);
+    }
+}
+
+// Intel mnemonics for the 8080 and 8085, Zilog ones for the Z80
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.variant == CpuVariant::Z80 {
+            let (mnemonic, operands) = z80::zilog(self.op);
+            return write_instruction(f, &mnemonic, &operands);
+        }
+        return write!(f, "{}", self.op);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::*;
+
+    #[test]
+    fn test_decode() {
+        // (bytes, expected op)
+        let cases: [(&[u8], Op); 16] = [
+            (&[0x00], Op::Nop),
+            (&[0x31, 0x00, 0x24], Op::Lxi(RegPair::SP, 0x2400)),
+            (&[0x12], Op::Stax(RegPair::DE)),
+            (&[0x2a, 0x34, 0x12], Op::Lhld(0x1234)),
+            (&[0x33], Op::Inx(RegPair::SP)),
+            (&[0x35], Op::Dcr(Operand::M)),
+            (&[0x3e, 0x42], Op::Mvi(Operand::A, 0x42)),
+            (&[0x7e], Op::Mov(Operand::A, Operand::M)),
+            (&[0x76], Op::Hlt),
+            (&[0x9a], Op::Alu(AluOp::Sbb, Operand::D)),
+            (&[0xfe, 0x10], Op::AluImmediate(AluOp::Cmp, 0x10)),
+            (&[0xe2, 0xcd, 0xab], Op::Jcc(Condition::PO, 0xabcd)),
+            (&[0xfc, 0x00, 0x01], Op::Ccc(Condition::M, 0x0100)),
+            (&[0xf8], Op::Rcc(Condition::M)),
+            (&[0xf1], Op::Pop(RegPair::PSW)),
+            (&[0xdf], Op::Rst(3)),
+        ];
+        for (bytes, expected) in cases.iter() {
+            let instruction = decode(bytes, 0x1000);
+            assert_eq!(instruction.op, *expected, "{:02x}", bytes[0]);
+            assert_eq!(instruction.length as usize, bytes.len(), "{:02x}", bytes[0]);
+            assert_eq!(instruction.address, 0x1000);
+            assert_eq!(instruction.opcode, bytes[0]);
+        }
+    }
+
+    #[test]
+    fn test_every_op_code_decodes_to_its_length() {
+        for code in 0..=0xffu8 {
+            let instruction = decode(&[code], 0);
+            let expected = match instruction.op {
+                Op::Lxi(..) | Op::Shld(_) | Op::Lhld(_) | Op::Sta(_) | Op::Lda(_) => 3,
+                Op::Jmp(_) | Op::Jcc(..) | Op::Call(_) | Op::Ccc(..) => 3,
+                Op::Mvi(..) | Op::AluImmediate(..) | Op::Out(_) | Op::In(_) => 2,
+                _ => 1,
+            };
+            assert_eq!(instruction.length, expected, "{:02x}", code);
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(decode(&[0x3e, 0x42, 0x99], 0).encoding(), &[0x3e, 0x42]);
+        assert_eq!(decode(&[0xcd, 0x34, 0x12], 0).encoding(), &[0xcd, 0x34, 0x12]);
+        assert_eq!(decode(&[0x00, 0x11, 0x22], 0).bytes, [0x00, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_missing_operand_bytes_are_zero() {
+        assert_eq!(decode(&[0xc3], 0).op, Op::Jmp(0x0000));
+        assert_eq!(decode(&[0xc3, 0x12], 0).op, Op::Jmp(0x0012));
+        assert_eq!(decode(&[], 0).op, Op::Nop);
+    }
+
+    #[test]
+    fn test_cycles() {
+        assert_eq!(decode(&[0x00], 0).cycles(), 4);
+        assert_eq!(decode(&[0xe3], 0).cycles(), 18);
+        assert_eq!(decode(&[0xcd], 0).cycles_taken(), 17);
+        assert_eq!(decode(&[0xc4], 0).cycles(), 11);
+        assert_eq!(decode(&[0xc4], 0).cycles_taken(), 17);
+        assert_eq!(decode(&[0xc0], 0).cycles(), 5);
+        assert_eq!(decode(&[0xc0], 0).cycles_taken(), 11);
+    }
+
+    #[test]
+    fn test_8085() {
+        let rim = decode_for(CpuVariant::Intel8085, &[0x20], 0);
+        assert_eq!(rim.op, Op::Rim);
+        assert_eq!(rim.to_string(), "RIM");
+        assert_eq!(decode_for(CpuVariant::Intel8085, &[0x30], 0).op, Op::Sim);
+        assert_eq!(decode(&[0x20], 0).op, Op::Nop);
+        assert_eq!(decode(&[0x30], 0).op, Op::Nop);
+        // the 8080's JMP, RET and CALL aliases are other instructions on the 8085
+        let alias = decode_for(CpuVariant::Intel8085, &[0xdd, 0x34, 0x12], 0);
+        assert_eq!(alias.op, Op::Unknown(0xdd));
+        assert_eq!(alias.length, 1);
+        // everything else decodes the same
+        for code in 0..=0xffu8 {
+            if code != 0x20 && code != 0x30 && !UNDOCUMENTED_8085.contains(&code) {
+                let bytes = [code, 0x34, 0x12];
+                assert_eq!(decode_for(CpuVariant::Intel8085, &bytes, 0).op, decode(&bytes, 0).op);
+            }
+        }
+    }
+
+    #[test]
+    fn test_undocumented_aliases() {
+        // (bytes, expected op, T-states)
+        let cases: [(&[u8], Op, u32); 6] = [
+            (&[0x08], Op::Nop, 4),
+            (&[0x38], Op::Nop, 4),
+            (&[0xcb, 0x34, 0x12], Op::Jmp(0x1234), 10),
+            (&[0xd9], Op::Ret, 10),
+            (&[0xdd, 0x34, 0x12], Op::Call(0x1234), 17),
+            (&[0xfd, 0x34, 0x12], Op::Call(0x1234), 17),
+        ];
+        for (bytes, expected, cycles) in cases.iter() {
+            let instruction = decode(bytes, 0);
+            assert_eq!(instruction.op, *expected, "{:02x}", bytes[0]);
+            assert_eq!(instruction.length as usize, bytes.len(), "{:02x}", bytes[0]);
+            assert_eq!(instruction.cycles(), *cycles, "{:02x}", bytes[0]);
+            assert_eq!(instruction.is_undocumented(), true, "{:02x}", bytes[0]);
+        }
+        assert_eq!(decode(&[0x00], 0).is_undocumented(), false);
+        assert_eq!(decode(&[0xc3], 0).is_undocumented(), false);
+        assert_eq!(decode_for(CpuVariant::Intel8085, &[0x20], 0).is_undocumented(), false);
+        assert_eq!(decode_for(CpuVariant::Intel8085, &[0xed], 0).is_undocumented(), true);
+        assert_eq!(decode_for(CpuVariant::Z80, &[0x08], 0).is_undocumented(), false);
+    }
+
+    #[test]
+    fn test_8085_cycles() {
+        // (op code, not taken, taken)
+        let cases: [(u8, u32, u32); 9] = [
+            (0x41, 4, 4),   // MOV B, C
+            (0x03, 6, 6),   // INX B
+            (0x76, 5, 5),   // HLT
+            (0xc2, 7, 10),  // JNZ
+            (0xc4, 9, 18),  // CNZ
+            (0xc0, 6, 12),  // RNZ
+            (0xcd, 18, 18), // CALL
+            (0xc5, 12, 12), // PUSH B
+            (0xe3, 16, 16), // XTHL
+        ];
+        for &(code, not_taken, taken) in cases.iter() {
+            let instruction = decode_for(CpuVariant::Intel8085, &[code], 0);
+            assert_eq!(instruction.cycles(), not_taken, "{:02x}", code);
+            assert_eq!(instruction.cycles_taken(), taken, "{:02x}", code);
+        }
+        assert_eq!(decode(&[0xc2], 0).cycles_taken(), 10);
+    }
+
+    #[test]
+    fn test_affected_flags() {
+        assert_eq!(decode(&[0x80], 0).affected_flags(), ALL_FLAGS);
+        assert_eq!(decode(&[0x04], 0).affected_flags(), ALL_BUT_CARRY);
+        assert_eq!(decode(&[0x09], 0).affected_flags(), CARRY);
+        assert_eq!(decode(&[0xf1], 0).affected_flags(), ALL_FLAGS);
+        assert_eq!(decode(&[0xc1], 0).affected_flags(), NO_FLAGS);
+        assert_eq!(decode(&[0x41], 0).affected_flags(), NO_FLAGS);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(decode(&[0x01, 0x34, 0x12], 0).to_string(), "LXI    B,#$1234");
+        assert_eq!(decode(&[0x36, 0x0f], 0).to_string(), "MVI    M,#$0f");
+        assert_eq!(decode(&[0xca, 0x00, 0x20], 0).to_string(), "JZ     $2000");
+        assert_eq!(decode(&[0xf5], 0).to_string(), "PUSH   PSW");
+        assert_eq!(decode(&[0xe6, 0x80], 0).to_string(), "ANI    #$80");
+        assert_eq!(decode(&[0xf8], 0).to_string(), "RM");
+        assert_eq!(decode(&[0xcb, 0x00, 0x20], 0).to_string(), "JMP    $2000");
+        assert_eq!(decode_for(CpuVariant::Intel8085, &[0xcb], 0).to_string(), "DB     $cb");
+        assert_eq!(decode_for(CpuVariant::Intel8085, &[0x28], 0).to_string(), "DB     $28");
+    }
+}
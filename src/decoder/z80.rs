@@ -0,0 +1,496 @@
+use crate::decoder::*;
+
+// Unprefixed op codes are the 8080's apart from these, the 8080's NOP aliases at 0x08-0x38 and
+// 0xd9 are the Z80's exchanges and relative jumps. Relative jumps are measured from the end of
+// the instruction.
+fn main_op(bytes: &[u8], address: u16) -> Op {
+    let code = bytes[0];
+    let offset = *bytes.get(1).unwrap_or(&0) as i8;
+    let target = address.wrapping_add(2).wrapping_add(offset as u16);
+    return match code {
+        0x08 => Op::ExAf,
+        0x10 => Op::Djnz(target),
+        0x18 => Op::Jr(target),
+        0x20 | 0x28 | 0x30 | 0x38 => Op::Jrcc(CONDITIONS[(code as usize >> 3) & 0x3], target),
+        0xd9 => Op::Exx,
+        _ => decode_for(CpuVariant::Intel8080, bytes, address).op,
+    };
+}
+
+const ROT_OPS: [RotOp; 8] = [
+    RotOp::Rlc,
+    RotOp::Rrc,
+    RotOp::Rl,
+    RotOp::Rr,
+    RotOp::Sla,
+    RotOp::Sra,
+    RotOp::Sll,
+    RotOp::Srl,
+];
+const BLOCK_OPS: [BlockOp; 16] = [
+    BlockOp::Ldi,
+    BlockOp::Cpi,
+    BlockOp::Ini,
+    BlockOp::Outi,
+    BlockOp::Ldd,
+    BlockOp::Cpd,
+    BlockOp::Ind,
+    BlockOp::Outd,
+    BlockOp::Ldir,
+    BlockOp::Cpir,
+    BlockOp::Inir,
+    BlockOp::Otir,
+    BlockOp::Lddr,
+    BlockOp::Cpdr,
+    BlockOp::Indr,
+    BlockOp::Otdr,
+];
+
+// The CB page, after DD CB or FD CB every op works on the indexed byte whatever register it names
+fn cb_op(code: u8, indexed: Option<Operand>) -> Op {
+    let operand = indexed.unwrap_or(OPERANDS[code as usize & 0x7]);
+    let bit = (code >> 3) & 0x7;
+    return match code >> 6 {
+        0 => Op::Rot(ROT_OPS[bit as usize], operand),
+        1 => Op::Bit(bit, operand),
+        2 => Op::Res(bit, operand),
+        _ => Op::Set(bit, operand),
+    };
+}
+
+// The ED page, the gaps and the undocumented copies of NEG, RETN and IM are left unknown
+fn ed_op(code: u8, word: u16) -> Op {
+    let operand = OPERANDS[(code as usize >> 3) & 0x7];
+    let pair = PAIRS[(code as usize >> 4) & 0x3];
+    return match code {
+        0x44 => Op::Neg,
+        0x45 => Op::Retn,
+        0x4d => Op::Reti,
+        0x46 => Op::Im(0),
+        0x56 => Op::Im(1),
+        0x5e => Op::Im(2),
+        0x47 => Op::LdIA,
+        0x4f => Op::LdRA,
+        0x57 => Op::LdAI,
+        0x5f => Op::LdAR,
+        0x67 => Op::Rrd,
+        0x6f => Op::Rld,
+        0x70 | 0x71 => Op::Unknown(0xed), // IN F,(C) and OUT (C),0
+        0x40..=0x7f => match code & 0x0f {
+            0x00 | 0x08 => Op::InC(operand),
+            0x01 | 0x09 => Op::OutC(operand),
+            0x02 => Op::SbcHl(pair),
+            0x0a => Op::AdcHl(pair),
+            0x03 => Op::Store16(pair, word),
+            0x0b => Op::Load16(pair, word),
+            _ => Op::Unknown(0xed),
+        },
+        0xa0..=0xbf if code & 0x04 == 0 => {
+            Op::Block(BLOCK_OPS[((code as usize >> 1) & 0xc) | (code as usize & 0x3)])
+        }
+        _ => Op::Unknown(0xed),
+    };
+}
+
+// What DD (IX) and FD (IY) do to the op code after them: HL becomes the index register and (HL)
+// becomes the byte at the index plus a signed offset. Anything else, including the undocumented
+// ops on the index register's halves, is unknown and the prefix is a one byte instruction.
+fn indexed_op(prefix: u8, code: u8, third: u8, fourth: u8) -> Op {
+    let index = if prefix == 0xdd { RegPair::IX } else { RegPair::IY };
+    let byte = if index == RegPair::IX { Operand::IX(third as i8) } else { Operand::IY(third as i8) };
+    let word = u16::from_le_bytes([third, fourth]);
+    return match code {
+        0x09 | 0x19 | 0x29 | 0x39 => {
+            let pair = PAIRS[(code as usize >> 4) & 0x3];
+            Op::AddIndex(index, if pair == RegPair::HL { index } else { pair })
+        }
+        0x21 => Op::Lxi(index, word),
+        0x22 => Op::Store16(index, word),
+        0x2a => Op::Load16(index, word),
+        0x23 => Op::Inx(index),
+        0x2b => Op::Dcx(index),
+        0x34 => Op::Inr(byte),
+        0x35 => Op::Dcr(byte),
+        0x36 => Op::Mvi(byte, fourth),
+        0x76 => Op::Unknown(prefix),
+        0x40..=0x7f if code & 0x07 == 0x06 => Op::Mov(OPERANDS[(code as usize >> 3) & 0x7], byte),
+        0x70..=0x77 => Op::Mov(byte, OPERANDS[code as usize & 0x7]),
+        0x80..=0xbf if code & 0x07 == 0x06 => Op::Alu(ALU_OPS[(code as usize >> 3) & 0x7], byte),
+        0xcb => cb_op(fourth, Some(byte)),
+        0xe1 => Op::Pop(index),
+        0xe3 => Op::ExSp(index),
+        0xe5 => Op::Push(index),
+        0xe9 => Op::JpIndirect(index),
+        0xf9 => Op::LdSp(index),
+        _ => Op::Unknown(prefix),
+    };
+}
+
+pub(super) fn length(opcode: u8, second: u8) -> u16 {
+    return match opcode {
+        0xcb => 2,
+        0xed if second & 0xc7 == 0x43 => 4, // LD (nn),rr and LD rr,(nn)
+        0xed => 2,
+        0xdd | 0xfd => match indexed_op(opcode, second, 0, 0) {
+            Op::Unknown(_) => 1,
+            Op::Lxi(..) | Op::Store16(..) | Op::Load16(..) => 4,
+            Op::Mvi(..) | Op::Rot(..) | Op::Bit(..) | Op::Res(..) | Op::Set(..) => 4,
+            Op::Inr(_) | Op::Dcr(_) | Op::Mov(..) | Op::Alu(..) => 3,
+            _ => 2,
+        },
+        0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 2,
+        _ => super::length(opcode),
+    };
+}
+
+pub(super) fn decode(bytes: &[u8], address: u16) -> Instruction {
+    let byte = |i: usize| *bytes.get(i).unwrap_or(&0);
+    let code = byte(0);
+    let op = match code {
+        0xcb => cb_op(byte(1), None),
+        0xed => ed_op(byte(1), u16::from_le_bytes([byte(2), byte(3)])),
+        0xdd | 0xfd => indexed_op(code, byte(1), byte(2), byte(3)),
+        _ => main_op(&[code, byte(1), byte(2)], address),
+    };
+
+    let length = length(code, byte(1));
+    let mut raw = [0; 4];
+    for (i, raw_byte) in raw.iter_mut().take(length as usize).enumerate() {
+        *raw_byte = byte(i);
+    }
+    return Instruction { address, opcode: code, op, length, bytes: raw, variant: CpuVariant::Z80 };
+}
+
+// A taken DJNZ or JR cc is 5 more, CALL cc 7, RET cc 6, and a block instruction that goes round
+// again 5
+pub(super) fn taken_extra(op: Op) -> u32 {
+    return match op {
+        Op::Djnz(_) | Op::Jrcc(..) | Op::Block(_) => 5,
+        Op::Ccc(..) => 7,
+        Op::Rcc(_) => 6,
+        _ => 0,
+    };
+}
+
+pub(super) fn cycles(instruction: &Instruction) -> u32 {
+    let op = instruction.op;
+    return match instruction.opcode {
+        0xcb => match op {
+            Op::Bit(_, Operand::M) => 12,
+            Op::Rot(_, Operand::M) | Op::Res(_, Operand::M) | Op::Set(_, Operand::M) => 15,
+            _ => 8,
+        },
+        0xed => match op {
+            Op::InC(_) | Op::OutC(_) => 12,
+            Op::AdcHl(_) | Op::SbcHl(_) => 15,
+            Op::Store16(..) | Op::Load16(..) => 20,
+            Op::Retn | Op::Reti => 14,
+            Op::LdIA | Op::LdRA | Op::LdAI | Op::LdAR => 9,
+            Op::Rrd | Op::Rld => 18,
+            Op::Block(_) => 16,
+            _ => 8, // NEG, IM and the unknown ones
+        },
+        // The prefix costs 4 and working out the indexed address 8 more, or 5 when it
+        // overlaps with fetching LD (IX+d),n's byte
+        0xdd | 0xfd => match op {
+            Op::Unknown(_) => 4,
+            Op::Bit(..) => 20,
+            Op::Rot(..) | Op::Res(..) | Op::Set(..) => 23,
+            Op::Mvi(..) => 19,
+            _ if instruction.length == 3 => CYCLES_Z80[instruction.bytes[1] as usize] as u32 + 12,
+            _ => CYCLES_Z80[instruction.bytes[1] as usize] as u32 + 4,
+        },
+        code => CYCLES_Z80[code as usize] as u32,
+    };
+}
+
+const Z80_ALL_FLAGS: &[Flag] = &[Flag::S, Flag::Z, Flag::AC, Flag::P, Flag::N, Flag::CY];
+const Z80_ALL_BUT_CARRY: &[Flag] = &[Flag::S, Flag::Z, Flag::AC, Flag::P, Flag::N];
+const Z80_CARRY: &[Flag] = &[Flag::AC, Flag::N, Flag::CY];
+const Z80_BLOCK_COPY: &[Flag] = &[Flag::AC, Flag::P, Flag::N];
+const Z80_CPL: &[Flag] = &[Flag::AC, Flag::N];
+
+// Like the 8080's, plus the subtract flag
+pub(super) fn affected_flags(op: Op) -> &'static [Flag] {
+    return match op {
+        Op::Alu(..) | Op::AluImmediate(..) | Op::Daa | Op::Pop(RegPair::PSW) => Z80_ALL_FLAGS,
+        Op::Rot(..) | Op::AdcHl(_) | Op::SbcHl(_) | Op::Neg => Z80_ALL_FLAGS,
+        Op::Inr(_) | Op::Dcr(_) | Op::Bit(..) | Op::InC(_) => Z80_ALL_BUT_CARRY,
+        Op::LdAI | Op::LdAR | Op::Rrd | Op::Rld => Z80_ALL_BUT_CARRY,
+        Op::Block(BlockOp::Ldi) | Op::Block(BlockOp::Ldd) => Z80_BLOCK_COPY,
+        Op::Block(BlockOp::Ldir) | Op::Block(BlockOp::Lddr) => Z80_BLOCK_COPY,
+        Op::Block(_) => Z80_ALL_BUT_CARRY,
+        Op::Dad(_) | Op::AddIndex(..) | Op::Rlc | Op::Rrc | Op::Ral | Op::Rar => Z80_CARRY,
+        Op::Stc | Op::Cmc => Z80_CARRY,
+        Op::Cma => Z80_CPL,
+        _ => NO_FLAGS,
+    };
+}
+
+// An index offset as it is written after IX or IY, +$05 or -$05
+pub(super) fn offset(offset: i8) -> String {
+    if offset < 0 {
+        return format!("-${:02x}", -(offset as i16));
+    }
+    return format!("+${:02x}", offset);
+}
+
+fn operand(operand: Operand) -> String {
+    return match operand {
+        Operand::M => "(HL)".to_string(),
+        _ => operand.to_string(),
+    };
+}
+
+fn pair(pair: RegPair) -> &'static str {
+    return match pair {
+        RegPair::BC => "BC",
+        RegPair::DE => "DE",
+        RegPair::HL => "HL",
+        RegPair::SP => "SP",
+        RegPair::PSW => "AF",
+        RegPair::IX => "IX",
+        RegPair::IY => "IY",
+    };
+}
+
+fn alu(op: AluOp, value: String) -> (String, String) {
+    let (mnemonic, operands) = match op {
+        AluOp::Add => ("ADD", format!("A,{}", value)),
+        AluOp::Adc => ("ADC", format!("A,{}", value)),
+        AluOp::Sub => ("SUB", value),
+        AluOp::Sbb => ("SBC", format!("A,{}", value)),
+        AluOp::Ana => ("AND", value),
+        AluOp::Xra => ("XOR", value),
+        AluOp::Ora => ("OR", value),
+        AluOp::Cmp => ("CP", value),
+    };
+    return (mnemonic.to_string(), operands);
+}
+
+// Zilog mnemonic and operands for op
+pub(super) fn zilog(op: Op) -> (String, String) {
+    let plain = |mnemonic: &str| (mnemonic.to_string(), String::new());
+    let with = |mnemonic: &str, operands: String| (mnemonic.to_string(), operands);
+    return match op {
+        Op::Nop => plain("NOP"),
+        Op::Lxi(p, word) => with("LD", format!("{},${:04x}", pair(p), word)),
+        Op::Stax(p) => with("LD", format!("({}),A", pair(p))),
+        Op::Ldax(p) => with("LD", format!("A,({})", pair(p))),
+        Op::Shld(word) => with("LD", format!("(${:04x}),HL", word)),
+        Op::Lhld(word) => with("LD", format!("HL,(${:04x})", word)),
+        Op::Sta(word) => with("LD", format!("(${:04x}),A", word)),
+        Op::Lda(word) => with("LD", format!("A,(${:04x})", word)),
+        Op::Inx(p) => with("INC", pair(p).to_string()),
+        Op::Dcx(p) => with("DEC", pair(p).to_string()),
+        Op::Dad(p) => with("ADD", format!("HL,{}", pair(p))),
+        Op::Inr(o) => with("INC", operand(o)),
+        Op::Dcr(o) => with("DEC", operand(o)),
+        Op::Mvi(o, byte) => with("LD", format!("{},${:02x}", operand(o), byte)),
+        Op::Rlc => plain("RLCA"),
+        Op::Rrc => plain("RRCA"),
+        Op::Ral => plain("RLA"),
+        Op::Rar => plain("RRA"),
+        Op::Daa => plain("DAA"),
+        Op::Cma => plain("CPL"),
+        Op::Stc => plain("SCF"),
+        Op::Cmc => plain("CCF"),
+        Op::Mov(destination, source) => with("LD", format!("{},{}", operand(destination), operand(source))),
+        Op::Hlt => plain("HALT"),
+        Op::Alu(op, o) => alu(op, operand(o)),
+        Op::AluImmediate(op, byte) => alu(op, format!("${:02x}", byte)),
+        Op::Jmp(word) => with("JP", format!("${:04x}", word)),
+        Op::Jcc(condition, word) => with("JP", format!("{},${:04x}", condition, word)),
+        Op::Call(word) => with("CALL", format!("${:04x}", word)),
+        Op::Ccc(condition, word) => with("CALL", format!("{},${:04x}", condition, word)),
+        Op::Ret => plain("RET"),
+        Op::Rcc(condition) => with("RET", condition.to_string()),
+        Op::Rst(n) => with("RST", format!("${:02x}", n << 3)),
+        Op::Push(p) => with("PUSH", pair(p).to_string()),
+        Op::Pop(p) => with("POP", pair(p).to_string()),
+        Op::Xthl => with("EX", "(SP),HL".to_string()),
+        Op::Pchl => with("JP", "(HL)".to_string()),
+        Op::Sphl => with("LD", "SP,HL".to_string()),
+        Op::Xchg => with("EX", "DE,HL".to_string()),
+        Op::Out(port) => with("OUT", format!("(${:02x}),A", port)),
+        Op::In(port) => with("IN", format!("A,(${:02x})", port)),
+        Op::Ei => plain("EI"),
+        Op::Di => plain("DI"),
+        Op::Rim => plain("RIM"),
+        Op::Sim => plain("SIM"),
+        Op::ExAf => with("EX", "AF,AF'".to_string()),
+        Op::Exx => plain("EXX"),
+        Op::Djnz(word) => with("DJNZ", format!("${:04x}", word)),
+        Op::Jr(word) => with("JR", format!("${:04x}", word)),
+        Op::Jrcc(condition, word) => with("JR", format!("{},${:04x}", condition, word)),
+        Op::Rot(op, o) => (format!("{:?}", op).to_uppercase(), operand(o)),
+        Op::Bit(bit, o) => with("BIT", format!("{},{}", bit, operand(o))),
+        Op::Res(bit, o) => with("RES", format!("{},{}", bit, operand(o))),
+        Op::Set(bit, o) => with("SET", format!("{},{}", bit, operand(o))),
+        Op::InC(o) => with("IN", format!("{},(C)", operand(o))),
+        Op::OutC(o) => with("OUT", format!("(C),{}", operand(o))),
+        Op::AdcHl(p) => with("ADC", format!("HL,{}", pair(p))),
+        Op::SbcHl(p) => with("SBC", format!("HL,{}", pair(p))),
+        Op::Store16(p, word) => with("LD", format!("(${:04x}),{}", word, pair(p))),
+        Op::Load16(p, word) => with("LD", format!("{},(${:04x})", pair(p), word)),
+        Op::Neg => plain("NEG"),
+        Op::Retn => plain("RETN"),
+        Op::Reti => plain("RETI"),
+        Op::Im(mode) => with("IM", mode.to_string()),
+        Op::LdIA => with("LD", "I,A".to_string()),
+        Op::LdRA => with("LD", "R,A".to_string()),
+        Op::LdAI => with("LD", "A,I".to_string()),
+        Op::LdAR => with("LD", "A,R".to_string()),
+        Op::Rrd => plain("RRD"),
+        Op::Rld => plain("RLD"),
+        Op::Block(op) => plain(&format!("{:?}", op).to_uppercase()),
+        Op::AddIndex(index, p) => with("ADD", format!("{},{}", pair(index), pair(p))),
+        Op::ExSp(p) => with("EX", format!("(SP),{}", pair(p))),
+        Op::JpIndirect(p) => with("JP", format!("({})", pair(p))),
+        Op::LdSp(p) => with("LD", format!("SP,{}", pair(p))),
+        Op::Unknown(code) => with("DB", format!("${:02x}", code)),
+    };
+}
+
+impl BlockOp {
+    // LDIR and the others ending in R go round until their count runs out
+    pub fn repeats(&self) -> bool {
+        return BLOCK_OPS.iter().position(|op| op == self).unwrap() >= 8;
+    }
+
+    // LDD and the others with a D step backwards through memory
+    pub fn decrements(&self) -> bool {
+        return BLOCK_OPS.iter().position(|op| op == self).unwrap() & 0x4 != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::z80::*;
+
+    fn z80(bytes: &[u8]) -> Instruction {
+        return decode_for(CpuVariant::Z80, bytes, 0x1000);
+    }
+
+    #[test]
+    fn test_decode_z80() {
+        // (bytes, expected op)
+        let cases: [(&[u8], Op); 22] = [
+            (&[0x08], Op::ExAf),
+            (&[0xd9], Op::Exx),
+            (&[0x10, 0xfe], Op::Djnz(0x1000)),
+            (&[0x18, 0x10], Op::Jr(0x1012)),
+            (&[0x38, 0x80], Op::Jrcc(Condition::C, 0x0f82)),
+            (&[0x3e, 0x42], Op::Mvi(Operand::A, 0x42)),
+            (&[0xcb, 0x07], Op::Rot(RotOp::Rlc, Operand::A)),
+            (&[0xcb, 0x3e], Op::Rot(RotOp::Srl, Operand::M)),
+            (&[0xcb, 0x7c], Op::Bit(7, Operand::H)),
+            (&[0xcb, 0xc1], Op::Set(0, Operand::C)),
+            (&[0xed, 0x78], Op::InC(Operand::A)),
+            (&[0xed, 0x52], Op::SbcHl(RegPair::DE)),
+            (&[0xed, 0x73, 0x00, 0x20], Op::Store16(RegPair::SP, 0x2000)),
+            (&[0xed, 0x5e], Op::Im(2)),
+            (&[0xed, 0xb0], Op::Block(BlockOp::Ldir)),
+            (&[0xed, 0xab], Op::Block(BlockOp::Outd)),
+            (&[0xdd, 0x21, 0x34, 0x12], Op::Lxi(RegPair::IX, 0x1234)),
+            (&[0xfd, 0x29], Op::AddIndex(RegPair::IY, RegPair::IY)),
+            (&[0xdd, 0x7e, 0xfb], Op::Mov(Operand::A, Operand::IX(-5))),
+            (&[0xfd, 0x36, 0x02, 0x99], Op::Mvi(Operand::IY(2), 0x99)),
+            (&[0xdd, 0xcb, 0x03, 0x46], Op::Bit(0, Operand::IX(3))),
+            (&[0xfd, 0xe9], Op::JpIndirect(RegPair::IY)),
+        ];
+        for (bytes, expected) in cases.iter() {
+            let instruction = z80(bytes);
+            assert_eq!(instruction.op, *expected, "{:02x?}", bytes);
+            assert_eq!(instruction.length as usize, bytes.len(), "{:02x?}", bytes);
+            assert_eq!(instruction.encoding(), *bytes);
+        }
+    }
+
+    #[test]
+    fn test_z80_unknown() {
+        assert_eq!(z80(&[0xed, 0x00]).op, Op::Unknown(0xed));
+        assert_eq!(z80(&[0xed, 0x00]).length, 2);
+        // the halves of IX aren't emulated, the prefix runs on its own
+        assert_eq!(z80(&[0xdd, 0x7c]).op, Op::Unknown(0xdd));
+        assert_eq!(z80(&[0xdd, 0x7c]).length, 1);
+    }
+
+    #[test]
+    fn test_z80_shares_the_8080_op_codes() {
+        for code in 0..=0xffu8 {
+            let bytes = [code, 0x34, 0x12];
+            let intel = decode(&bytes, 0);
+            if is_prefix(CpuVariant::Z80, code) || intel.op == Op::Nop || code == 0xd9 {
+                continue;
+            }
+            let zilog = decode_for(CpuVariant::Z80, &bytes, 0);
+            assert_eq!(zilog.op, intel.op, "{:02x}", code);
+            assert_eq!(zilog.length, intel.length, "{:02x}", code);
+        }
+    }
+
+    #[test]
+    fn test_z80_cycles() {
+        // (bytes, not taken, taken)
+        let cases: [(&[u8], u32, u32); 14] = [
+            (&[0x00], 4, 4),                   // NOP
+            (&[0x41], 4, 4),                   // LD B,C
+            (&[0x10], 8, 13),                  // DJNZ
+            (&[0x20], 7, 12),                  // JR NZ
+            (&[0xc4], 10, 17),                 // CALL NZ
+            (&[0xc0], 5, 11),                  // RET NZ
+            (&[0xcb, 0x00], 8, 8),             // RLC B
+            (&[0xcb, 0x46], 12, 12),           // BIT 0,(HL)
+            (&[0xed, 0xb0], 16, 21),           // LDIR
+            (&[0xdd, 0x21], 14, 14),           // LD IX,nn
+            (&[0xdd, 0xe5], 15, 15),           // PUSH IX
+            (&[0xdd, 0x7e], 19, 19),           // LD A,(IX+d)
+            (&[0xdd, 0x34], 23, 23),           // INC (IX+d)
+            (&[0xfd, 0xcb, 0x00, 0x06], 23, 23), // RLC (IY+d)
+        ];
+        for &(bytes, not_taken, taken) in cases.iter() {
+            let instruction = z80(bytes);
+            assert_eq!(instruction.cycles(), not_taken, "{:02x?}", bytes);
+            assert_eq!(instruction.cycles_taken(), taken, "{:02x?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_z80_lengths() {
+        assert_eq!(length_for(CpuVariant::Z80, 0xdd, 0x36), 4);
+        assert_eq!(length_for(CpuVariant::Z80, 0xdd, 0xcb), 4);
+        assert_eq!(length_for(CpuVariant::Z80, 0xed, 0x4b), 4);
+        assert_eq!(length_for(CpuVariant::Z80, 0x18, 0x00), 2);
+        assert_eq!(length_for(CpuVariant::Intel8080, 0x18, 0x00), 1);
+        assert_eq!(is_prefix(CpuVariant::Z80, 0xcb), true);
+        assert_eq!(is_prefix(CpuVariant::Intel8080, 0xcb), false);
+    }
+
+    #[test]
+    fn test_z80_affected_flags() {
+        assert_eq!(z80(&[0x80]).affected_flags(), Z80_ALL_FLAGS);
+        assert_eq!(z80(&[0x2f]).affected_flags(), &[Flag::AC, Flag::N]);
+        assert_eq!(z80(&[0xed, 0xb0]).affected_flags(), &[Flag::AC, Flag::P, Flag::N]);
+        assert_eq!(z80(&[0x41]).affected_flags(), NO_FLAGS);
+    }
+
+    #[test]
+    fn test_zilog_display() {
+        assert_eq!(z80(&[0x01, 0x34, 0x12]).to_string(), "LD     BC,$1234");
+        assert_eq!(z80(&[0x36, 0x0f]).to_string(), "LD     (HL),$0f");
+        assert_eq!(z80(&[0xca, 0x00, 0x20]).to_string(), "JP     Z,$2000");
+        assert_eq!(z80(&[0xf5]).to_string(), "PUSH   AF");
+        assert_eq!(z80(&[0xe6, 0x80]).to_string(), "AND    $80");
+        assert_eq!(z80(&[0x88]).to_string(), "ADC    A,B");
+        assert_eq!(z80(&[0xff]).to_string(), "RST    $38");
+        assert_eq!(z80(&[0x08]).to_string(), "EX     AF,AF'");
+        assert_eq!(z80(&[0xdd, 0x77, 0x05]).to_string(), "LD     (IX+$05),A");
+        assert_eq!(z80(&[0xfd, 0xcb, 0xfe, 0x2e]).to_string(), "SRA    (IY-$02)");
+        assert_eq!(z80(&[0xed, 0xb1]).to_string(), "CPIR");
+        assert_eq!(z80(&[0x76]).to_string(), "HALT");
+        // the Intel names for the Z80's extra instructions are the Zilog ones
+        assert_eq!(z80(&[0xed, 0x44]).op.to_string(), "NEG");
+        assert_eq!(z80(&[0xdd, 0x77, 0x05]).op.to_string(), "MOV    (IX+$05),A");
+    }
+}
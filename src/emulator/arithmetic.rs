@@ -1,5 +1,6 @@
-use crate::emulator::utils::combine;
-use crate::emulator::utils::split;
+use crate::emulator::config::CpuVariant;
+use crate::emulator::utils::aux_carry;
+use crate::emulator::registers::RegPair;
 use crate::emulator::utils::update_flags;
 use crate::emulator::ConditionCodes;
 use crate::emulator::State8080;
@@ -16,18 +17,145 @@ pub fn adc(value: u8, state: &mut State8080) {
 }
 
 fn add_core(value: u8, state: &mut State8080, use_carry: bool) {
-    let answer: u16 = state.a as u16 + value as u16 + if use_carry && state.cc.cy { 1 } else { 0 };
+    let carry = use_carry && state.cc.cy;
+    let answer: u16 = state.a as u16 + value as u16 + carry as u16;
+    state.cc.ac = aux_carry(state.a, value, carry);
     update_flags(answer, &mut state.cc);
+    if state.variant == CpuVariant::Z80 {
+        // Overflow, both operands had the same sign and the answer doesn't
+        state.cc.p = (state.a ^ answer as u8) & (value ^ answer as u8) & 0x80 != 0;
+    }
+    state.cc.n = false;
     state.a = answer as u8;
 }
 
+pub fn sub(value: u8, state: &mut State8080) {
+    state.a = sub_core(value, state, false);
+}
+
+pub fn sbb(value: u8, state: &mut State8080) {
+    state.a = sub_core(value, state, true);
+}
+
+// cmp is a sub that throws the answer away and keeps the flags
+pub fn cmp(value: u8, state: &mut State8080) {
+    sub_core(value, state, false);
+}
+
+// The 8080 subtracts by adding the two's complement, so the carry out of the add is inverted to
+// give a borrow: cy is set when value (plus the borrow for sbb) is bigger than a. ac is not
+// inverted, it is the carry out of bit 3 of that same add. The Z80 does invert it, its h is a
+// borrow into bit 4, and p is overflow.
+fn sub_core(value: u8, state: &mut State8080, use_borrow: bool) -> u8 {
+    let carry = !(use_borrow && state.cc.cy);
+    let answer: u16 = state.a as u16 + !value as u16 + carry as u16;
+    state.cc.ac = aux_carry(state.a, !value, carry);
+    update_flags(answer, &mut state.cc);
+    state.cc.cy = !state.cc.cy;
+    if state.variant == CpuVariant::Z80 {
+        state.cc.ac = !state.cc.ac;
+        state.cc.p = (state.a ^ value) & (state.a ^ answer as u8) & 0x80 != 0;
+    }
+    state.cc.n = true;
+    return answer as u8;
+}
+
+// NEG, a = 0 - a
+pub fn neg(state: &mut State8080) {
+    let value = state.a;
+    state.a = 0;
+    sub(value, state);
+}
+
+// Decimal adjust a after adding two BCD numbers, following the two steps in the Intel manual:
+// first fix up the low digit, then the high digit. cy is only ever set here, never cleared.
+pub fn daa(state: &mut State8080) {
+    if state.variant == CpuVariant::Z80 {
+        daa_z80(state);
+        return;
+    }
+    let lsb = state.a & 0x0f;
+    let msb = state.a >> 4;
+    let mut correction = 0;
+    let mut carry = state.cc.cy;
+    if state.cc.ac || lsb > 9 {
+        correction += 0x06;
+    }
+    if state.cc.cy || msb > 9 || (msb >= 9 && lsb > 9) {
+        correction += 0x60;
+        carry = true;
+    }
+    add_core(correction, state, false);
+    state.cc.cy = carry;
+}
+
+// The Z80 also knows how to adjust after a subtraction, n says which it was. h ends up as the
+// carry or borrow out of the low digit the correction made.
+fn daa_z80(state: &mut State8080) {
+    let a = state.a;
+    let mut correction = 0;
+    let mut carry = state.cc.cy;
+    if state.cc.ac || a & 0x0f > 9 {
+        correction |= 0x06;
+    }
+    if state.cc.cy || a > 0x99 {
+        correction |= 0x60;
+        carry = true;
+    }
+    if state.cc.n {
+        state.cc.ac = state.cc.ac && a & 0x0f < 6;
+        state.a = a.wrapping_sub(correction);
+    } else {
+        state.cc.ac = a & 0x0f > 9;
+        state.a = a.wrapping_add(correction);
+    }
+    update_flags(state.a as u16, &mut state.cc);
+    state.cc.cy = carry;
+}
+
 pub fn dad(num: u16, state: &mut State8080) {
-    let other = combine(state.h, state.l) as u32;
+    add_pair(RegPair::HL, num, state);
+}
+
+// DAD and the Z80's ADD IX/IY, only the carry changes on the 8080. The Z80 also sets h from bit
+// 11 and clears n.
+pub fn add_pair(pair: RegPair, num: u16, state: &mut State8080) {
+    let other = state.pair(pair) as u32;
     let answer = num as u32 + other;
     state.cc.cy = answer > MAX_U16 as u32;
-    let (upper, lower) = split(answer as u16);
-    state.h = upper;
-    state.l = lower;
+    if state.variant == CpuVariant::Z80 {
+        state.cc.ac = (num & 0x0fff) as u32 + (other & 0x0fff) > 0x0fff;
+        state.cc.n = false;
+    }
+    state.set_pair(pair, answer as u16);
+}
+
+// The Z80's ADC HL and SBC HL, they set every flag from the 16 bit answer
+pub fn adc16(num: u16, state: &mut State8080) {
+    let hl = state.pair(RegPair::HL);
+    let carry = state.cc.cy as u32;
+    let answer = hl as u32 + num as u32 + carry;
+    state.cc.ac = (hl & 0x0fff) as u32 + (num & 0x0fff) as u32 + carry > 0x0fff;
+    state.cc.n = false;
+    set_16_bit_flags(answer, state);
+    state.cc.p = (hl ^ answer as u16) & (num ^ answer as u16) & 0x8000 != 0;
+}
+
+pub fn sbc16(num: u16, state: &mut State8080) {
+    let hl = state.pair(RegPair::HL);
+    let borrow = state.cc.cy as u32;
+    let answer = (hl as u32).wrapping_sub(num as u32).wrapping_sub(borrow);
+    state.cc.ac = ((hl & 0x0fff) as u32) < (num & 0x0fff) as u32 + borrow;
+    state.cc.n = true;
+    set_16_bit_flags(answer, state);
+    state.cc.p = (hl ^ num) & (hl ^ answer as u16) & 0x8000 != 0;
+}
+
+fn set_16_bit_flags(answer: u32, state: &mut State8080) {
+    state.cc.cy = answer > MAX_U16 as u32;
+    state.cc.z = answer as u16 == 0;
+    state.cc.s = answer & 0x8000 != 0;
+    state.set_pair(RegPair::HL, answer as u16);
 }
 
 // inx B -> BC + 1 add one to lower then carry to upper
@@ -67,13 +195,35 @@ pub fn dcx(upper: &mut u8, lower: &mut u8) {
     }
 }
 
+// INR and DCR leave the carry alone
 pub fn inr(value: &mut u8, codes: &mut ConditionCodes) {
+    let carry = codes.cy;
     let answer: u16 = *value as u16 + 1;
+    codes.ac = aux_carry(*value, 1, false);
     update_flags(answer, codes);
+    codes.cy = carry;
     *value = answer as u8;
 }
 
+// The Z80's INC and DEC use p for overflow. DEC's h is a borrow into bit
+// 4, the opposite of the 8080's ac.
+pub fn inr_z80(value: &mut u8, codes: &mut ConditionCodes) {
+    inr(value, codes);
+    codes.p = *value == 0x80;
+    codes.n = false;
+}
+
+pub fn dcr_z80(value: &mut u8, codes: &mut ConditionCodes) {
+    dcr(value, codes);
+    codes.ac = !codes.ac;
+    codes.p = *value == 0x7f;
+    codes.n = true;
+}
+
+// The chip decrements by adding 0xff, so ac is set unless the low nibble was 0
 pub fn dcr(value: &mut u8, codes: &mut ConditionCodes) {
+    let carry = codes.cy;
+    codes.ac = aux_carry(*value, 0xff, false);
     if *value > 0 {
         let answer: u16 = *value as u16 - 1;
         update_flags(answer, codes);
@@ -82,6 +232,7 @@ pub fn dcr(value: &mut u8, codes: &mut ConditionCodes) {
         *value = 0xff;
         update_flags(*value as u16, codes);
     }
+    codes.cy = carry;
 }
 
 #[cfg(test)]
@@ -170,14 +321,7 @@ mod tests {
 
     #[test]
     fn test_inr() {
-        let mut codes = ConditionCodes {
-            z: false,
-            s: false,
-            p: false,
-            cy: false,
-            ac: false,
-            pad: false,
-        };
+        let mut codes = ConditionCodes::new();
 
         let mut test: u8 = 0;
         inr(&mut test, &mut codes);
@@ -207,23 +351,289 @@ mod tests {
         assert_eq!(true, codes.z);
         assert_eq!(false, codes.s);
         assert_eq!(true, codes.p);
-        assert_eq!(true, codes.cy);
+        assert_eq!(false, codes.cy);
     }
 
     #[test]
     fn test_inr_m() {
         let mut state = setup_state();
-        state.memory[0] = 0x34; // CMA op code
-        state.memory[1] = 0x34; // CMA op code
+        state.memory.write(0, 0x34); // CMA op code
+        state.memory.write(1, 0x34); // CMA op code
         state.h = 0x12;
         state.l = 0xab;
-        assert_eq!(state.memory[0x12ab], 0);
-        state.emulate_op();
-        assert_eq!(state.memory[0x12ab], 1);
+        assert_eq!(state.memory.read(0x12ab), 0);
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x12ab), 1);
+
+        state.memory.write(0x12ab, 0xff);
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x12ab), 0);
+        assert_eq!(state.cc.z, true);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_inr_dcr_keep_carry() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x3c); // INR A
+        state.memory.write(1, 0x05); // DCR B
+        state.memory.write(2, 0x3c); // INR A
+        state.memory.write(3, 0x05); // DCR B
+        state.a = 0x10;
+        state.b = 0x10;
+        state.cc.cy = true;
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.cc.cy, true);
+
+        // Wrapping around doesn't touch it either
+        state.a = 0xff;
+        state.b = 0x00;
+        state.cc.cy = false;
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x00);
+        assert_eq!(state.b, 0xff);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_adi() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xc6); // ADI op code
+        state.memory.write(1, 0x42); // immediate
+        state.a = 0x14;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x56);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_aci() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xce); // ACI op code
+        state.memory.write(1, 0xbe); // immediate
+        state.a = 0x56;
+        state.cc.cy = true;
+        state.emulate_op().unwrap();
+        // 0x56 + 0xbe + 1 = 0x115
+        assert_eq!(state.a, 0x15);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.cy, true);
+    }
+
+    #[test]
+    fn test_sub() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x90); // SUB B op code
+        state.memory.write(1, 0x97); // SUB A op code
+        state.a = 0x3e;
+        state.b = 0x3f;
+        state.emulate_op().unwrap();
+        // 0x3e - 0x3f borrows and wraps around to 0xff
+        assert_eq!(state.a, 0xff);
+        assert_eq!(state.cc.cy, true);
+        assert_eq!(state.cc.s, true);
+        assert_eq!(state.cc.z, false);
+        assert_eq!(state.cc.p, true);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0);
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.z, true);
+        assert_eq!(state.cc.s, false);
+    }
+
+    #[test]
+    fn test_sub_m() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x96); // SUB M op code
+        state.memory.write(0x1234, 0x05);
+        state.h = 0x12;
+        state.l = 0x34;
+        state.a = 0x0a;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x05);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_sbb() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x9d); // SBB L op code
+        state.memory.write(1, 0x9d); // SBB L op code
+        state.a = 0x04;
+        state.l = 0x02;
+        state.cc.cy = true;
+        state.emulate_op().unwrap();
+        // 4 - 2 - 1 for the borrow
+        assert_eq!(state.a, 0x01);
+        assert_eq!(state.cc.cy, false);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0xff);
+        assert_eq!(state.cc.cy, true);
+    }
+
+    #[test]
+    fn test_sbb_borrow_equal_operands() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x98); // SBB B op code
+        state.a = 0x00;
+        state.b = 0x00;
+        state.cc.cy = true;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0xff);
+        assert_eq!(state.cc.cy, true);
+    }
 
-        state.memory[0x12ab] = 0xff;
-        state.emulate_op();
-        assert_eq!(state.memory[0x12ab], 0);
+    #[test]
+    fn test_sui() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xd6); // SUI op code
+        state.memory.write(1, 0x01); // immediate
+        state.a = 0x00;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0xff);
+        assert_eq!(state.pc, 2);
         assert_eq!(state.cc.cy, true);
     }
+
+    #[test]
+    fn test_sbi() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xde); // SBI op code
+        state.memory.write(1, 0x10); // immediate
+        state.a = 0x20;
+        state.cc.cy = true;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x0f);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_cmp() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xbb); // CMP E op code
+        state.memory.write(1, 0xbb); // CMP E op code
+        state.memory.write(2, 0xbb); // CMP E op code
+        state.a = 0x0a;
+        state.e = 0x05;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x0a); // a is never modified
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.z, false);
+
+        state.e = 0x0a;
+        state.emulate_op().unwrap();
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.z, true);
+
+        state.e = 0x0b;
+        state.emulate_op().unwrap();
+        assert_eq!(state.cc.cy, true);
+        assert_eq!(state.cc.z, false);
+    }
+
+    #[test]
+    fn test_cpi() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xfe); // CPI op code
+        state.memory.write(1, 0x40); // immediate
+        state.a = 0x4a;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x4a);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.z, false);
+    }
+
+    #[test]
+    fn test_aux_carry_flag() {
+        // (op code with b as the operand, a, b, cy before, expected a, expected ac)
+        let cases: [(u8, u8, u8, bool, u8, bool); 20] = [
+            (0x80, 0x0e, 0x01, false, 0x0f, false), // ADD B
+            (0x80, 0x0f, 0x01, false, 0x10, true),  // ADD B
+            (0x80, 0x2e, 0x74, false, 0xa2, true),  // ADD B
+            (0x88, 0x07, 0x08, false, 0x0f, false), // ADC B
+            (0x88, 0x07, 0x08, true, 0x10, true),   // ADC B
+            (0x90, 0x3e, 0x3e, false, 0x00, true),  // SUB B
+            (0x90, 0x10, 0x01, false, 0x0f, false), // SUB B
+            (0x90, 0x0f, 0x01, false, 0x0e, true),  // SUB B
+            (0x98, 0x04, 0x02, true, 0x01, true),   // SBB B
+            (0x98, 0x10, 0x00, true, 0x0f, false),  // SBB B
+            (0xb8, 0x0a, 0x05, false, 0x0a, true),  // CMP B
+            (0xb8, 0x02, 0x05, false, 0x02, false), // CMP B
+            (0x04, 0x00, 0x0f, false, 0x00, true),  // INR B, a is untouched
+            (0x04, 0x00, 0x0e, false, 0x00, false), // INR B
+            (0x05, 0x00, 0x10, false, 0x00, false), // DCR B
+            (0x05, 0x00, 0x01, false, 0x00, true),  // DCR B
+            (0xa0, 0x08, 0x00, false, 0x00, true),  // ANA B
+            (0xa0, 0xf7, 0xf7, false, 0xf7, false), // ANA B
+            (0xa8, 0x08, 0x08, false, 0x00, false), // XRA B
+            (0xb0, 0x08, 0x08, false, 0x08, false), // ORA B
+        ];
+        for &(code, a, b, cy, expected_a, expected_ac) in cases.iter() {
+            let mut state = setup_state();
+            state.memory.write(0, code);
+            state.a = a;
+            state.b = b;
+            state.cc.cy = cy;
+            state.cc.ac = !expected_ac;
+
+            state.emulate_op().unwrap();
+
+            assert_eq!(state.a, expected_a, "op {:02x} a {:02x} b {:02x}", code, a, b);
+            assert_eq!(state.cc.ac, expected_ac, "op {:02x} a {:02x} b {:02x}", code, a, b);
+        }
+    }
+
+    #[test]
+    fn test_daa() {
+        // (a, ac before, cy before, expected a, expected ac, expected cy)
+        let cases: [(u8, bool, bool, u8, bool, bool); 10] = [
+            (0x9b, false, false, 0x01, true, true), // the example in the Intel manual
+            (0x00, false, false, 0x00, false, false),
+            (0x09, false, false, 0x09, false, false),
+            (0x0a, false, false, 0x10, true, false),
+            (0x99, false, false, 0x99, false, false),
+            (0x9a, false, false, 0x00, true, true),
+            (0xa0, false, false, 0x00, false, true),
+            (0x12, true, false, 0x18, false, false), // 0x09 + 0x09 = 0x12 with ac set
+            (0x30, false, true, 0x90, false, true), // 0x90 + 0xa0 = 0x130 with cy set
+            (0x00, true, true, 0x66, false, true),
+        ];
+        for &(a, ac, cy, expected_a, expected_ac, expected_cy) in cases.iter() {
+            let mut state = setup_state();
+            state.memory.write(0, 0x27); // DAA op code
+            state.a = a;
+            state.cc.ac = ac;
+            state.cc.cy = cy;
+
+            state.emulate_op().unwrap();
+
+            assert_eq!(state.a, expected_a, "a {:02x}", a);
+            assert_eq!(state.cc.ac, expected_ac, "a {:02x}", a);
+            assert_eq!(state.cc.cy, expected_cy, "a {:02x}", a);
+            assert_eq!(state.cc.z, expected_a == 0, "a {:02x}", a);
+        }
+    }
+
+    #[test]
+    fn test_bcd_add() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xc6); // ADI op code
+        state.memory.write(1, 0x38); // immediate
+        state.memory.write(2, 0x27); // DAA op code
+        state.a = 0x29;
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        // 29 + 38 = 67 in decimal
+        assert_eq!(state.a, 0x67);
+        assert_eq!(state.cc.cy, false);
+    }
 }
@@ -0,0 +1,342 @@
+use crate::decoder::decode_for;
+use crate::emulator::config::CpuVariant;
+use crate::decoder::Instruction;
+use crate::decoder::Op;
+use crate::emulator::error::EmulatorError;
+use crate::emulator::State8080;
+
+// Longest run of instructions decoded into one block, keeps a block from running for too long
+// between chances to raise an interrupt
+const MAX_BLOCK_LENGTH: usize = 32;
+
+// A straight line run of instructions, only the last one can jump, call, return, halt or change
+// the interrupt state
+pub struct Block {
+    instructions: Vec<Instruction>,
+    cycles: Vec<u32>, // What each instruction takes when no branch is taken
+}
+
+impl Block {
+    // The pages the block has code in, start's page first
+    fn pages(&self, start: u16) -> impl Iterator<Item = u16> {
+        let last = self.instructions.last().expect("Blocks are never empty");
+        let end = last.address.wrapping_add(last.length - 1) >> 8;
+        let count = (end.wrapping_sub(start >> 8) & 0xff) + 1;
+        return (0..count).map(move |page| ((start >> 8) + page) & 0xff);
+    }
+}
+
+// Blocks that have already been decoded, indexed by start address and only allocated once
+// something is cached. Memory is split into 256 byte pages and each page remembers which blocks
+// have code in it, a write to the page drops them. A block is taken out of the cache while it
+// runs and put back afterwards, unless a write dropped it in the meantime.
+pub struct BlockCache {
+    blocks: Vec<Option<Box<Block>>>,
+    pages: Vec<Vec<u16>>,
+    // Bumped whenever a block is dropped, so a block that overwrites itself can tell
+    generation: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        return BlockCache {
+            blocks: vec![],
+            pages: vec![vec![]; 256],
+            generation: 0,
+        };
+    }
+
+    fn take(&mut self, address: u16) -> Option<Box<Block>> {
+        return match self.blocks.get_mut(address as usize) {
+            Some(block) => block.take(),
+            None => None,
+        };
+    }
+
+    // Puts back a block that was taken to run, or caches a new one when it was just decoded
+    fn put(&mut self, start: u16, block: Box<Block>, generation: u64) {
+        if self.generation != generation {
+            // Something was dropped while it ran, find out if it was this block
+            let cached = block.pages(start).all(|page| self.pages[page as usize].contains(&start));
+            if !cached {
+                return;
+            }
+        }
+        self.blocks[start as usize] = Some(block);
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        return self.blocks.iter().filter(|block| block.is_some()).count();
+    }
+
+    // Starts watching the pages a newly decoded block has code in, put caches it once it has run
+    fn watch(&mut self, start: u16, block: &Block) {
+        if self.blocks.is_empty() {
+            self.blocks = (0..0x10000).map(|_| None).collect();
+        }
+        for page in block.pages(start) {
+            self.pages[page as usize].push(start);
+        }
+    }
+
+    // Called for every write the cpu makes, drops the blocks with code in the same page
+    pub fn invalidate(&mut self, address: u16) {
+        let page = &mut self.pages[(address >> 8) as usize];
+        if page.is_empty() {
+            return;
+        }
+        for start in page.drain(..) {
+            self.blocks[start as usize] = None;
+        }
+        self.generation += 1;
+    }
+
+    pub fn clear(&mut self) {
+        for page in self.pages.iter_mut() {
+            for start in page.drain(..) {
+                self.blocks[start as usize] = None;
+            }
+        }
+        self.generation += 1;
+    }
+}
+
+fn ends_block(op: &Op) -> bool {
+    return match op {
+        Op::Jmp(_) | Op::Jcc(..) | Op::Call(_) | Op::Ccc(..) | Op::Ret | Op::Rcc(_) => true,
+        Op::Rst(_) | Op::Pchl | Op::Hlt | Op::Ei | Op::Di | Op::Sim | Op::Unknown(_) => true,
+        Op::Djnz(_) | Op::Jr(_) | Op::Jrcc(..) | Op::JpIndirect(_) | Op::Retn | Op::Reti => true,
+        Op::Block(op) => op.repeats(),
+        _ => false,
+    };
+}
+
+impl State8080 {
+    // Runs the block of instructions starting at pc and returns how many T-states it took. Blocks
+    // are decoded the first time they run and cached, which saves the fetch and decode emulate_op
+    // does for each instruction, see benches/blocks.rs. Writes the cpu makes drop the blocks they
+    // land in, writes from anywhere else (a device, a mirror region, swapping memory) need
+    // invalidate_blocks.
+    pub fn emulate_block(&mut self) -> Result<u32, EmulatorError> {
+        // The 8085's interrupt pins are only looked at between blocks
+        if self.variant == CpuVariant::Intel8085 {
+            if let Some(cycles) = self.service_pins()? {
+                return Ok(cycles);
+            }
+        }
+        if self.halted {
+            return self.emulate_op();
+        }
+        let start = self.pc;
+        let block = match self.blocks.take(start) {
+            Some(block) => block,
+            None => {
+                let block = Box::new(self.decode_block(start)?);
+                self.blocks.watch(start, &block);
+                block
+            }
+        };
+
+        let generation = self.blocks.generation;
+        let result = self.run_block(&block, generation);
+        self.blocks.put(start, block, generation);
+        return result;
+    }
+
+    fn run_block(&mut self, block: &Block, generation: u64) -> Result<u32, EmulatorError> {
+        let traced = self.trace.is_some();
+        let mut total = 0;
+        for (instruction, &cycles) in block.instructions.iter().zip(block.cycles.iter()) {
+            // The instruction after EI has now run, so interrupts are really on
+            self.ei_delay = false;
+            self.pc = instruction.address.wrapping_add(instruction.length);
+            if traced {
+                total += self.run(instruction)?;
+            } else {
+                // run without the trace, cycles was looked up when the block was decoded
+                let cycles = self.execute(instruction, cycles)?;
+                self.check_bus()?;
+                self.cycles += cycles as u64;
+                total += cycles;
+            }
+            if self.blocks.generation != generation {
+                // Something was written over cached code, the rest of this block might be stale
+                break;
+            }
+        }
+        return Ok(total);
+    }
+
+    // Drops every cached block, for when memory changed behind the cpu's back
+    pub fn invalidate_blocks(&mut self) {
+        self.blocks.clear();
+    }
+
+    fn decode_block(&mut self, start: u16) -> Result<Block, EmulatorError> {
+        let mut instructions = vec![];
+        let mut address = start;
+        loop {
+            let bytes = self.fetch(address);
+            if let Some(fault) = self.memory.take_fault() {
+                if instructions.is_empty() {
+                    return Err(EmulatorError::BusFault { address: fault });
+                }
+                // Leave it for the next block, which faults when it gets there
+                break;
+            }
+            let instruction = decode_for(self.variant, &bytes, address);
+            instructions.push(instruction);
+            address = address.wrapping_add(instruction.length);
+            if ends_block(&instruction.op) || instructions.len() == MAX_BLOCK_LENGTH || address < start {
+                break;
+            }
+        }
+        let cycles = instructions.iter().map(|instruction| instruction.cycles()).collect();
+        return Ok(Block { instructions, cycles });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::error::EmulatorError;
+    use crate::emulator::test_utils::*;
+    use crate::emulator::State8080;
+
+    // Adds up 1 to 0x10 in a and stores the total at 0x2000
+    const SUM: [u8; 10] = [
+        0x0e, 0x10,       // MVI C, 0x10
+        0x81,             // ADD C
+        0x0d,             // DCR C
+        0xc2, 0x02, 0x00, // JNZ 0x0002
+        0x32, 0x00, 0x20, // STA 0x2000
+    ];
+
+    fn load(state: &mut State8080, program: &[u8]) {
+        for (i, byte) in program.iter().enumerate() {
+            state.memory.write(i as u16, *byte);
+        }
+    }
+
+    #[test]
+    fn test_block_matches_single_steps() {
+        let mut stepped = setup_state();
+        let mut blocked = setup_state();
+        load(&mut stepped, &SUM);
+        load(&mut blocked, &SUM);
+        stepped.memory.write(10, 0x76); // HLT op code
+        blocked.memory.write(10, 0x76); // HLT op code
+
+        while !stepped.is_halted() {
+            stepped.emulate_op().unwrap();
+        }
+        while !blocked.is_halted() {
+            blocked.emulate_block().unwrap();
+        }
+
+        assert_eq!(blocked.a, 0x88);
+        assert_eq!(blocked.registers(), stepped.registers());
+        assert_eq!(blocked.cycles(), stepped.cycles());
+        assert_eq!(blocked.memory.read(0x2000), 0x88);
+    }
+
+    #[test]
+    fn test_block_stops_at_branch() {
+        let mut state = setup_state();
+        load(&mut state, &SUM);
+
+        assert_eq!(state.emulate_block(), Ok(7 + 4 + 5 + 10));
+        assert_eq!(state.pc, 0x0002);
+        assert_eq!(state.blocks.len(), 1);
+        state.emulate_block().unwrap();
+        assert_eq!(state.blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_write_to_code_invalidates_block() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x3c); // INR A op code
+        state.memory.write(1, 0x77); // MOV M, A op code
+        state.memory.write(2, 0xc3); // JMP 0x0000
+        state.h = 0x00;
+        state.l = 0x10;
+
+        // the write lands in the block's own page, so it stops and gets decoded again
+        state.emulate_block().unwrap();
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.blocks.len(), 0);
+
+        state.emulate_block().unwrap();
+        state.emulate_block().unwrap();
+        assert_eq!(state.a, 2);
+        assert_eq!(state.memory.read(0x0010), 2);
+    }
+
+    #[test]
+    fn test_write_elsewhere_keeps_block() {
+        let mut state = setup_state();
+        load(&mut state, &SUM);
+
+        state.emulate_block().unwrap();
+        state.memory.write(0x2000, 0x00);
+        state.emulate_block().unwrap();
+        state.emulate_block().unwrap();
+
+        assert_eq!(state.blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_dropping_another_block_keeps_this_one() {
+        let mut state = setup_state();
+        state.memory.write(0x1000, 0xc3); // JMP 0x0000
+        state.memory.write(0x1001, 0x00);
+        state.memory.write(0x1002, 0x00);
+        state.memory.write(0, 0x32); // STA 0x1002
+        state.memory.write(1, 0x02);
+        state.memory.write(2, 0x10);
+        state.memory.write(3, 0xc3); // JMP 0x1000
+        state.memory.write(4, 0x00);
+        state.memory.write(5, 0x10);
+        state.pc = 0x1000;
+
+        state.emulate_block().unwrap();
+        assert_eq!(state.blocks.len(), 1);
+        // The STA drops the block at 0x1000, the one it is in is still good
+        state.emulate_block().unwrap();
+        assert_eq!(state.pc, 3);
+        assert_eq!(state.blocks.len(), 1);
+        state.emulate_block().unwrap();
+        assert_eq!(state.pc, 0x1000);
+        assert_eq!(state.blocks.len(), 2);
+    }
+
+    #[test]
+    fn test_self_modifying_block() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x3e); // MVI A, 0x04 (INR B op code)
+        state.memory.write(1, 0x04);
+        state.memory.write(2, 0x32); // STA 0x0005
+        state.memory.write(3, 0x05);
+        state.memory.write(4, 0x00);
+        state.memory.write(5, 0x0c); // INR C op code, overwritten with INR B
+        state.memory.write(6, 0x76); // HLT op code
+
+        state.emulate_block().unwrap();
+        assert_eq!(state.pc, 5);
+        state.emulate_block().unwrap();
+
+        assert_eq!(state.b, 1);
+        assert_eq!(state.c, 0);
+        assert_eq!(state.is_halted(), true);
+    }
+
+    #[test]
+    fn test_block_bus_fault() {
+        let mut state = State8080::new(vec![0x00, 0x00]); // NOP, NOP
+
+        state.emulate_block().unwrap();
+
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.emulate_block(), Err(EmulatorError::BusFault { address: 2 }));
+    }
+}
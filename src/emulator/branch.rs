@@ -1,45 +1,94 @@
-use crate::emulator::utils::combine;
+use crate::emulator::registers::RegPair;
 use crate::emulator::State8080;
 
-pub fn conditional_jmp(condition: bool, state: &mut State8080) {
+// The conditional ones return whether they were taken, which can cost extra cycles
+pub fn conditional_jmp(condition: bool, address: u16, state: &mut State8080) -> bool {
     if condition {
-        jmp(state);
-    } else {
-        state.pc += 2;
+        jmp(address, state);
     }
+    return condition;
 }
 
-pub fn call(state: &mut State8080) {
-    let return_address = state.pc + 2;
-    state.memory[(state.sp - 1) as usize] = ((return_address >> 8) & 0xff) as u8;
-    state.memory[(state.sp - 2) as usize] = (return_address & 0xff) as u8;
-    state.sp -= 2;
-    jmp(state);
+// pc already points at the next instruction, that is where the call returns to
+pub fn call(address: u16, state: &mut State8080) {
+    let return_address = state.pc;
+    push(return_address, state);
+    jmp(address, state);
+}
+
+pub fn conditional_call(condition: bool, address: u16, state: &mut State8080) -> bool {
+    if condition {
+        call(address, state);
+    }
+    return condition;
 }
 
 pub fn ret(state: &mut State8080) {
-    let upper = state.memory[state.sp as usize];
-    let lower = state.memory[(state.sp + 1) as usize];
-    state.pc = combine(upper, lower);
-    state.sp += 2;
+    state.pc = pop(state);
+}
+
+pub fn conditional_ret(condition: bool, state: &mut State8080) -> bool {
+    if condition {
+        ret(state);
+    }
+    return condition;
+}
+
+pub fn jmp(address: u16, state: &mut State8080) {
+    state.pc = address;
+}
+
+// RST n is a one byte call to n * 8
+pub fn rst(n: u8, state: &mut State8080) {
+    let return_address = state.pc;
+    push(return_address, state);
+    state.pc = (n as u16 & 0x7) << 3;
 }
 
-pub fn jmp(state: &mut State8080) {
-    state.pc = state.get_double_at_pc();
+// The stack grows down, the upper byte goes to sp - 1 and the lower to sp - 2. sp wraps around
+// at 16 bits like it does on the chip.
+pub fn push(value: u16, state: &mut State8080) {
+    let [upper, lower] = value.to_be_bytes();
+    state.sp = state.sp.wrapping_sub(1);
+    state.write_byte(state.sp, upper);
+    state.sp = state.sp.wrapping_sub(1);
+    state.write_byte(state.sp, lower);
+}
+
+pub fn pop(state: &mut State8080) -> u16 {
+    let lower = state.memory.read(state.sp);
+    state.sp = state.sp.wrapping_add(1);
+    let upper = state.memory.read(state.sp);
+    state.sp = state.sp.wrapping_add(1);
+    return u16::from_be_bytes([upper, lower]);
+}
+
+// XTHL swaps l with the top of the stack and h with the byte under it
+pub fn xthl(state: &mut State8080) {
+    exchange_top(RegPair::HL, state);
+}
+
+// The same for any pair, the Z80 can also do it with IX and IY
+pub fn exchange_top(pair: RegPair, state: &mut State8080) {
+    let top = pop(state);
+    let value = state.pair(pair);
+    push(value, state);
+    state.set_pair(pair, top);
 }
 
 #[cfg(test)]
 mod tests {
     use crate::emulator::test_utils::*;
+    use crate::emulator::State8080;
 
     #[test]
     fn test_jmp() {
         let mut state = setup_state();
-        state.memory[0] = 0xc3; // JMP op code
-        state.memory[1] = 0x11; // lower half of address
-        state.memory[2] = 0x22; // upper half of address
+        state.memory.write(0, 0xc3); // JMP op code
+        state.memory.write(1, 0x11); // lower half of address
+        state.memory.write(2, 0x22); // upper half of address
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.pc, 0x2211);
     }
@@ -49,16 +98,16 @@ mod tests {
         let mut state = setup_state();
         state.sp = 100;
         state.pc = 0x1234; // start somewhere interesting
-        state.memory[0x1234] = 0xcd; // CALL op code
-        state.memory[0x1235] = 0x11; // lower half of address
-        state.memory[0x1236] = 0x22; // upper half of address
+        state.memory.write(0x1234, 0xcd); // CALL op code
+        state.memory.write(0x1235, 0x11); // lower half of address
+        state.memory.write(0x1236, 0x22); // upper half of address
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.pc, 0x2211);
         assert_eq!(state.sp, 98);
-        assert_eq!(state.memory[99], 0x12);
-        assert_eq!(state.memory[98], 0x37); // 1234 + 1 for jump + 2 for jmp address
+        assert_eq!(state.memory.read(99), 0x12);
+        assert_eq!(state.memory.read(98), 0x37); // 1234 + 1 for jump + 2 for jmp address
     }
 
     #[test]
@@ -67,13 +116,278 @@ mod tests {
 
         state.sp = 100;
         state.pc = 0x3456; // start somewhere interesting
-        state.memory[0x3456] = 0xc9; // RET op code
-        state.memory[100] = 0x11; // lower half of address
-        state.memory[101] = 0x22; // upper half of address
+        state.memory.write(0x3456, 0xc9); // RET op code
+        state.memory.write(100, 0x11); // lower half of address
+        state.memory.write(101, 0x22); // upper half of address
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
-        assert_eq!(state.pc, 0x1122);
+        assert_eq!(state.pc, 0x2211);
         assert_eq!(state.sp, 102);
     }
+
+    #[test]
+    fn test_call_then_ret() {
+        let mut state = setup_state();
+        state.pc = 0x1234;
+        state.memory.write(0x1234, 0xcd); // CALL op code
+        state.memory.write(0x1235, 0x00); // lower half of address
+        state.memory.write(0x1236, 0x20); // upper half of address
+        state.memory.write(0x2000, 0xc9); // RET op code
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 0x1237);
+        assert_eq!(state.sp, 100);
+    }
+
+    // (Jcc op code, flag, value of the flag that satisfies the condition). Ccc is Jcc + 2 and
+    // Rcc is Jcc - 2.
+    const CONDITIONS: [(u8, char, bool); 8] = [
+        (0xc2, 'z', false), // NZ
+        (0xca, 'z', true),  // Z
+        (0xd2, 'c', false), // NC
+        (0xda, 'c', true),  // C
+        (0xe2, 'p', false), // PO
+        (0xea, 'p', true),  // PE
+        (0xf2, 's', false), // P
+        (0xfa, 's', true),  // M
+    ];
+
+    fn set_flag(state: &mut State8080, flag: char, value: bool) {
+        match flag {
+            'z' => state.cc.z = value,
+            'c' => state.cc.cy = value,
+            'p' => state.cc.p = value,
+            's' => state.cc.s = value,
+            _ => panic!("Unknown flag {}", flag),
+        }
+    }
+
+    #[test]
+    fn test_conditional_jmp() {
+        for &(code, flag, value) in CONDITIONS.iter() {
+            for &taken in [true, false].iter() {
+                let mut state = setup_state();
+                state.memory.write(0, code);
+                state.memory.write(1, 0x00); // lower half of address
+                state.memory.write(2, 0x30); // upper half of address
+                set_flag(&mut state, flag, value == taken);
+
+                state.emulate_op().unwrap();
+
+                let expected = if taken { 0x3000 } else { 3 };
+                assert_eq!(state.pc, expected, "op {:02x} taken: {}", code, taken);
+                assert_eq!(state.sp, 100);
+            }
+        }
+    }
+
+    #[test]
+    fn test_conditional_call() {
+        for &(code, flag, value) in CONDITIONS.iter() {
+            for &taken in [true, false].iter() {
+                let mut state = setup_state();
+                state.pc = 0x1000;
+                state.memory.write(0x1000, code + 2);
+                state.memory.write(0x1001, 0x00); // lower half of address
+                state.memory.write(0x1002, 0x30); // upper half of address
+                set_flag(&mut state, flag, value == taken);
+
+                state.emulate_op().unwrap();
+
+                if taken {
+                    assert_eq!(state.pc, 0x3000, "op {:02x}", code + 2);
+                    assert_eq!(state.sp, 98);
+                    assert_eq!(state.memory.read(99), 0x10);
+                    assert_eq!(state.memory.read(98), 0x03);
+                } else {
+                    assert_eq!(state.pc, 0x1003, "op {:02x}", code + 2);
+                    assert_eq!(state.sp, 100);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_conditional_ret() {
+        for &(code, flag, value) in CONDITIONS.iter() {
+            for &taken in [true, false].iter() {
+                let mut state = setup_state();
+                state.pc = 0x1000;
+                state.memory.write(0x1000, code - 2);
+                state.memory.write(100, 0x34); // lower half of address
+                state.memory.write(101, 0x12); // upper half of address
+                set_flag(&mut state, flag, value == taken);
+
+                state.emulate_op().unwrap();
+
+                if taken {
+                    assert_eq!(state.pc, 0x1234, "op {:02x}", code - 2);
+                    assert_eq!(state.sp, 102);
+                } else {
+                    assert_eq!(state.pc, 0x1001, "op {:02x}", code - 2);
+                    assert_eq!(state.sp, 100);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_rst() {
+        for n in 0..8 {
+            let mut state = setup_state();
+            state.pc = 0x1234;
+            state.memory.write(0x1234, 0xc7 | (n << 3)); // RST n op code
+
+            state.emulate_op().unwrap();
+
+            assert_eq!(state.pc, n as u16 * 8);
+            assert_eq!(state.sp, 98);
+            assert_eq!(state.memory.read(99), 0x12);
+            assert_eq!(state.memory.read(98), 0x35);
+        }
+    }
+
+    #[test]
+    fn test_push_pop() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xc5); // PUSH B op code
+        state.memory.write(1, 0xd5); // PUSH D op code
+        state.memory.write(2, 0xe5); // PUSH H op code
+        state.memory.write(3, 0xc1); // POP B op code
+        state.memory.write(4, 0xd1); // POP D op code
+        state.memory.write(5, 0xe1); // POP H op code
+        state.b = 0x0b;
+        state.c = 0x0c;
+        state.d = 0x0d;
+        state.e = 0x0e;
+        state.h = 0x01;
+        state.l = 0x02;
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.sp, 98);
+        assert_eq!(state.memory.read(99), 0x0b);
+        assert_eq!(state.memory.read(98), 0x0c);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.sp, 94);
+
+        // popping in the same order reverses the registers
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.sp, 100);
+        assert_eq!((state.b, state.c), (0x01, 0x02));
+        assert_eq!((state.d, state.e), (0x0d, 0x0e));
+        assert_eq!((state.h, state.l), (0x0b, 0x0c));
+    }
+
+    #[test]
+    fn test_push_pop_psw() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xf5); // PUSH PSW op code
+        state.memory.write(1, 0xf1); // POP PSW op code
+        state.a = 0x42;
+        state.cc.s = true;
+        state.cc.z = false;
+        state.cc.ac = true;
+        state.cc.p = false;
+        state.cc.cy = true;
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(99), 0x42);
+        assert_eq!(state.memory.read(98), 0b10010011);
+
+        state.a = 0;
+        state.cc.s = false;
+        state.cc.ac = false;
+        state.cc.cy = false;
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x42);
+        assert_eq!(state.cc.s, true);
+        assert_eq!(state.cc.z, false);
+        assert_eq!(state.cc.ac, true);
+        assert_eq!(state.cc.p, false);
+        assert_eq!(state.cc.cy, true);
+    }
+
+    #[test]
+    fn test_push_wraps_sp() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xc5); // PUSH B op code
+        state.memory.write(1, 0xc1); // POP B op code
+        state.sp = 0x0001;
+        state.b = 0xaa;
+        state.c = 0xbb;
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.sp, 0xffff);
+        assert_eq!(state.memory.read(0x0000), 0xaa);
+        assert_eq!(state.memory.read(0xffff), 0xbb);
+
+        state.b = 0;
+        state.c = 0;
+        state.emulate_op().unwrap();
+        assert_eq!(state.sp, 0x0001);
+        assert_eq!((state.b, state.c), (0xaa, 0xbb));
+    }
+
+    #[test]
+    fn test_xthl() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xe3); // XTHL op code
+        state.memory.write(100, 0xf0);
+        state.memory.write(101, 0x0d);
+        state.h = 0x0b;
+        state.l = 0x3c;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.h, 0x0d);
+        assert_eq!(state.l, 0xf0);
+        assert_eq!(state.memory.read(100), 0x3c);
+        assert_eq!(state.memory.read(101), 0x0b);
+        assert_eq!(state.sp, 100);
+    }
+
+    #[test]
+    fn test_pchl() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xe9); // PCHL op code
+        state.h = 0x41;
+        state.l = 0x3e;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 0x413e);
+    }
+
+    #[test]
+    fn test_sphl() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xf9); // SPHL op code
+        state.h = 0x50;
+        state.l = 0x6c;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.sp, 0x506c);
+    }
+
+    #[test]
+    fn test_xchg() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xeb); // XCHG op code
+        state.d = 0x33;
+        state.e = 0x55;
+        state.h = 0x00;
+        state.l = 0xff;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!((state.d, state.e), (0x00, 0xff));
+        assert_eq!((state.h, state.l), (0x33, 0x55));
+    }
 }
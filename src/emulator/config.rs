@@ -0,0 +1,408 @@
+use crate::emulator::memory::Memory;
+use crate::emulator::ConditionCodes;
+use crate::emulator::State8080;
+use log::warn;
+
+pub const DEFAULT_STACK_POINTER: u16 = 0xf000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuVariant {
+    Intel8080,
+    // Adds RIM and SIM, the RST 5.5, 6.5 and 7.5 and TRAP interrupt pins, the SID and SOD serial
+    // lines and its own timings
+    Intel8085,
+    // The Zilog superset: the CB, DD, ED and FD prefixes, IX and IY, the alternate registers,
+    // relative jumps, block instructions, interrupt modes 0 to 2 and NMI, see emulator::z80
+    Z80,
+}
+
+// The whole 8080 address space
+pub const MAX_MEMORY_SIZE: usize = 0x10000;
+
+// Where a State8080's memory came from, which decides what power_cycle does with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MemorySource {
+    Ram(usize), // Plain RAM of this many bytes the builder allocated, refilled from scratch
+    Given,      // Handed to the builder, only the program is written into it again
+    Replaced,   // Swapped with set_memory after the build, left alone
+}
+
+// Everything needed to bring a State8080 up from power on, kept so power_cycle can do it again
+#[derive(Debug, Clone)]
+pub struct Config {
+    program: Vec<u8>,
+    load_address: u16,
+    entry_point: u16,
+    stack_pointer: u16,
+    ram_fill: Vec<u8>,
+    variant: CpuVariant,
+    pub(crate) memory: MemorySource,
+}
+
+impl Config {
+    // size bytes of RAM holding the fill pattern with the program copied in at the load address
+    fn ram(&self, size: usize) -> Vec<u8> {
+        let mut memory: Vec<u8> = if self.ram_fill.is_empty() {
+            vec![0; size]
+        } else {
+            self.ram_fill.iter().cycle().take(size).cloned().collect()
+        };
+        self.load(&mut memory, size);
+        return memory;
+    }
+
+    // Writes the program through the Memory trait, so a MemoryMap decides where it ends up. Bytes
+    // that would go past size are dropped.
+    fn load(&self, memory: &mut dyn Memory, size: usize) {
+        let start = self.load_address as usize;
+        let fits = self.program.len().min(size.saturating_sub(start));
+        if fits < self.program.len() {
+            warn!(
+                "Program runs past {:04x}, dropped the last {} bytes",
+                size.max(1) - 1,
+                self.program.len() - fits
+            );
+        }
+        for (i, byte) in self.program[..fits].iter().enumerate() {
+            memory.write((start + i) as u16, *byte);
+        }
+    }
+}
+
+// Sets up a State8080. By default it gets 64K of RAM, the program is loaded at and started from 0,
+// sp is DEFAULT_STACK_POINTER and RAM starts out zeroed.
+pub struct State8080Builder {
+    config: Config,
+    memory: Option<Box<dyn Memory>>, // Kept out of config, a Memory can't be cloned
+}
+
+impl Default for State8080Builder {
+    fn default() -> State8080Builder {
+        return State8080Builder::new();
+    }
+}
+
+impl State8080Builder {
+    pub fn new() -> State8080Builder {
+        return State8080Builder {
+            config: Config {
+                program: vec![],
+                load_address: 0,
+                entry_point: 0,
+                stack_pointer: DEFAULT_STACK_POINTER,
+                ram_fill: vec![0],
+                variant: CpuVariant::Intel8080,
+                memory: MemorySource::Ram(MAX_MEMORY_SIZE),
+            },
+            memory: None,
+        };
+    }
+
+    pub fn program(mut self, program: Vec<u8>) -> State8080Builder {
+        self.config.program = program;
+        return self;
+    }
+
+    // Where the first byte of the program goes, CP/M programs for example load at 0x0100
+    pub fn load_address(mut self, address: u16) -> State8080Builder {
+        self.config.load_address = address;
+        return self;
+    }
+
+    // Where pc starts, and goes back to on reset
+    pub fn entry_point(mut self, address: u16) -> State8080Builder {
+        self.config.entry_point = address;
+        return self;
+    }
+
+    pub fn stack_pointer(mut self, sp: u16) -> State8080Builder {
+        self.config.stack_pointer = sp;
+        return self;
+    }
+
+    // RAM is filled by repeating pattern before the program is loaded, real chips come up with
+    // all sorts of garbage in them and some software cares
+    pub fn ram_fill(mut self, pattern: Vec<u8>) -> State8080Builder {
+        self.config.ram_fill = pattern;
+        return self;
+    }
+
+    pub fn variant(mut self, variant: CpuVariant) -> State8080Builder {
+        self.config.variant = variant;
+        return self;
+    }
+
+    // How many bytes of RAM to give it, from address 0 up to 64K. Reads past the end of it float
+    // to UNMAPPED and writes are dropped.
+    pub fn memory_size(mut self, size: usize) -> State8080Builder {
+        if size > MAX_MEMORY_SIZE {
+            warn!("Memory size {:x} is more than the 8080 can address, using 64K", size);
+        }
+        self.config.memory = MemorySource::Ram(size.min(MAX_MEMORY_SIZE));
+        self.memory = None;
+        return self;
+    }
+
+    // Runs against memory instead of plain RAM, a MemoryMap with ROM and devices for example. The
+    // program is written into it through the Memory trait and ram_fill doesn't apply.
+    pub fn memory(mut self, memory: Box<dyn Memory>) -> State8080Builder {
+        self.config.memory = MemorySource::Given;
+        self.memory = Some(memory);
+        return self;
+    }
+
+    pub fn build(self) -> State8080 {
+        let memory: Box<dyn Memory> = match (self.config.memory, self.memory) {
+            (MemorySource::Ram(size), _) => Box::new(self.config.ram(size)),
+            (_, Some(mut memory)) => {
+                self.config.load(memory.as_mut(), MAX_MEMORY_SIZE);
+                memory
+            }
+            (_, None) => Box::new(self.config.ram(MAX_MEMORY_SIZE)),
+        };
+        let mut state = State8080::with_memory(memory);
+        state.pc = self.config.entry_point;
+        state.sp = self.config.stack_pointer;
+        state.variant = self.config.variant;
+        state.config = Some(self.config);
+        return state;
+    }
+}
+
+impl State8080 {
+    pub fn builder() -> State8080Builder {
+        return State8080Builder::new();
+    }
+
+    pub fn variant(&self) -> CpuVariant {
+        return self.variant;
+    }
+
+    // What the RESET pin does:
+    //  pc          back to the entry point (0 unless the builder set one)
+    //  interrupts  disabled, including one pending from EI
+    //  halted      cleared, the cpu starts fetching again
+    //  8085 pins   RST masks set, RST 7.5 and TRAP forgotten, SOD low
+    //  Z80         interrupt mode 0, I and R 0
+    //  a-l, sp     unchanged
+    //  flags       unchanged
+    //  cycles      unchanged, the count is since power on
+    //  memory, io  unchanged
+    pub fn reset(&mut self) {
+        self.pc = match self.config {
+            Some(ref config) => config.entry_point,
+            None => 0,
+        };
+        self.int_enable = false;
+        self.ei_delay = false;
+        self.halted = false;
+        self.pins.reset();
+        self.z80.reset();
+    }
+
+    // Like turning it off and on again:
+    //  pc          the entry point
+    //  sp          the initial stack pointer
+    //  a-l         0
+    //  flags       all clear
+    //  interrupts  disabled
+    //  halted      cleared
+    //  cycles      0
+    //  memory      RAM from the builder is refilled with the fill pattern and the program loaded
+    //              again, memory given to the builder just gets the program written again and
+    //              memory swapped in with set_memory is left alone
+    //  io          unchanged
+    pub fn power_cycle(&mut self) {
+        self.reset();
+        self.a = 0;
+        self.b = 0;
+        self.c = 0;
+        self.d = 0;
+        self.e = 0;
+        self.h = 0;
+        self.l = 0;
+        self.cc = ConditionCodes::new();
+        self.cycles = 0;
+        self.sp = DEFAULT_STACK_POINTER;
+        if let Some(ref config) = self.config {
+            self.sp = config.stack_pointer;
+            match config.memory {
+                MemorySource::Ram(size) => {
+                    let memory: Box<dyn Memory> = Box::new(config.ram(size));
+                    self.memory = memory;
+                }
+                MemorySource::Given => config.load(self.memory.as_mut(), MAX_MEMORY_SIZE),
+                MemorySource::Replaced => {}
+            }
+            self.invalidate_blocks();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::config::*;
+    use crate::emulator::memory::MemoryMap;
+    use crate::emulator::memory::Region;
+    use crate::emulator::memory::UNMAPPED;
+
+    #[test]
+    fn test_defaults() {
+        let mut state = State8080::builder().program(vec![0x3c]).build();
+        assert_eq!(state.pc, 0);
+        assert_eq!(state.sp, DEFAULT_STACK_POINTER);
+        assert_eq!(state.variant(), CpuVariant::Intel8080);
+        assert_eq!(state.memory.read(0x0000), 0x3c);
+        assert_eq!(state.memory.read(0x0001), 0x00);
+        assert_eq!(state.memory.read(0xffff), 0x00);
+    }
+
+    #[test]
+    fn test_load_address_and_entry_point() {
+        let mut state = State8080::builder()
+            .program(vec![0x3e, 0x42]) // MVI A, 0x42
+            .load_address(0x0100)
+            .entry_point(0x0100)
+            .stack_pointer(0x2400)
+            .build();
+        assert_eq!(state.pc, 0x0100);
+        assert_eq!(state.sp, 0x2400);
+        assert_eq!(state.memory.read(0x0100), 0x3e);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x42);
+    }
+
+    #[test]
+    fn test_ram_fill() {
+        let mut state = State8080::builder()
+            .program(vec![0x11, 0x22])
+            .load_address(0x0001)
+            .ram_fill(vec![0xaa, 0x55])
+            .build();
+        assert_eq!(state.memory.read(0x0000), 0xaa);
+        assert_eq!(state.memory.read(0x0001), 0x11);
+        assert_eq!(state.memory.read(0x0002), 0x22);
+        assert_eq!(state.memory.read(0x0003), 0x55);
+        assert_eq!(state.memory.read(0x0004), 0xaa);
+        assert_eq!(state.memory.read(0xffff), 0x55);
+    }
+
+    #[test]
+    fn test_program_past_end_of_memory_is_dropped() {
+        let mut state = State8080::builder()
+            .program(vec![0x01, 0x02, 0x03])
+            .load_address(0xfffe)
+            .build();
+        assert_eq!(state.memory.read(0xfffe), 0x01);
+        assert_eq!(state.memory.read(0xffff), 0x02);
+        assert_eq!(state.memory.read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut state = State8080::builder()
+            .program(vec![0xfb, 0x3c, 0x76]) // EI, INR A, HLT
+            .entry_point(0x0000)
+            .build();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.memory.write(0x1000, 0x99);
+        assert_eq!(state.is_halted(), true);
+
+        state.reset();
+
+        assert_eq!(state.pc, 0);
+        assert_eq!(state.is_halted(), false);
+        assert_eq!(state.interrupts_enabled(), false);
+        assert_eq!(state.a, 1);
+        assert_eq!(state.cycles(), 4 + 5 + 7);
+        assert_eq!(state.memory.read(0x1000), 0x99);
+    }
+
+    #[test]
+    fn test_power_cycle() {
+        let mut state = State8080::builder()
+            .program(vec![0x3c, 0x32, 0x00, 0x10]) // INR A, STA 0x1000
+            .load_address(0x0200)
+            .entry_point(0x0200)
+            .stack_pointer(0x3000)
+            .ram_fill(vec![0xee])
+            .build();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.sp = 0x1234;
+        state.cc.cy = true;
+        assert_eq!(state.memory.read(0x1000), 0x01);
+
+        state.power_cycle();
+
+        assert_eq!(state.pc, 0x0200);
+        assert_eq!(state.sp, 0x3000);
+        assert_eq!(state.a, 0);
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cycles(), 0);
+        assert_eq!(state.memory.read(0x1000), 0xee);
+        assert_eq!(state.memory.read(0x0200), 0x3c);
+    }
+
+    #[test]
+    fn test_memory_size() {
+        let mut state = State8080::builder()
+            .program(vec![0x01, 0x02, 0x03])
+            .load_address(0x0ffe)
+            .memory_size(0x1000)
+            .build();
+        assert_eq!(state.memory.read(0x0ffe), 0x01);
+        assert_eq!(state.memory.read(0x0fff), 0x02);
+        assert_eq!(state.memory.read(0x1000), UNMAPPED);
+
+        state.memory.write(0x1000, 0x42);
+        state.memory.write(0x0000, 0x42);
+        state.power_cycle();
+        assert_eq!(state.memory.read(0x0000), 0x00);
+        assert_eq!(state.memory.read(0x1000), UNMAPPED);
+    }
+
+    #[test]
+    fn test_given_memory() {
+        let memory = MemoryMap::new()
+            .region(0x0000, 0x0100, Region::Rom(vec![0x76; 0x100]))
+            .region(0x0100, 0x0100, Region::Ram(vec![0; 0x100]));
+        let mut state = State8080::builder()
+            .program(vec![0x3e, 0x42]) // MVI A, 0x42
+            .load_address(0x0100)
+            .entry_point(0x0100)
+            .ram_fill(vec![0xee])
+            .memory(Box::new(memory))
+            .build();
+        assert_eq!(state.memory.read(0x0000), 0x76);
+        assert_eq!(state.memory.read(0x0100), 0x3e);
+        assert_eq!(state.memory.read(0x0102), 0x00);
+        assert_eq!(state.memory.read(0x0200), UNMAPPED);
+
+        state.emulate_op().unwrap();
+        state.memory.write(0x0100, 0x00);
+        state.memory.write(0x0150, 0x99);
+        state.power_cycle();
+
+        // Still the map, with the program written back and the rest of its RAM as it was
+        assert_eq!(state.pc, 0x0100);
+        assert_eq!(state.memory.read(0x0000), 0x76);
+        assert_eq!(state.memory.read(0x0100), 0x3e);
+        assert_eq!(state.memory.read(0x0150), 0x99);
+        assert_eq!(state.memory.read(0x0200), UNMAPPED);
+    }
+
+    #[test]
+    fn test_power_cycle_keeps_set_memory() {
+        let mut state = State8080::builder().program(vec![0x3c]).build();
+        let memory = MemoryMap::new().region(0x0000, 0x0100, Region::Rom(vec![0x76; 0x100]));
+        state.set_memory(Box::new(memory));
+        state.power_cycle();
+
+        assert_eq!(state.memory.read(0x0000), 0x76);
+        assert_eq!(state.memory.read(0x0100), UNMAPPED);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::emulator::config::CpuVariant;
+use crate::emulator::error::EmulatorError;
+use crate::emulator::io::IoBus;
+use crate::emulator::memory::Memory;
+use crate::emulator::registers::Registers;
+use crate::emulator::State8080;
+
+// What frontends, debuggers and machine drivers need from a cpu, so they can be written once and
+// work with any core. State8080 covers the 8080, 8085 and Z80 through its variant.
+pub trait Cpu {
+    fn variant(&self) -> CpuVariant;
+
+    // Runs one instruction, returns the T-states it took
+    fn step(&mut self) -> Result<u32, EmulatorError>;
+
+    // Total T-states since power on
+    fn cycles(&self) -> u64;
+
+    // On a Z80 the snapshot includes IX, IY, I, R, the alternate set and the interrupt state
+    fn registers(&self) -> Registers;
+
+    fn restore_registers(&mut self, registers: &Registers);
+
+    // Raise the interrupt line with opcode on the data bus, returns whether it was accepted
+    fn interrupt(&mut self, opcode: u8) -> Result<bool, EmulatorError>;
+
+    fn is_halted(&self) -> bool;
+
+    // The RESET pin
+    fn reset(&mut self);
+
+    fn set_memory(&mut self, memory: Box<dyn Memory>);
+
+    fn set_io(&mut self, io: Box<dyn IoBus>);
+
+    // Memory as the cpu sees it, without side effects on devices
+    fn peek(&self, address: u16) -> u8;
+
+    fn poke(&mut self, address: u16, value: u8);
+}
+
+impl Cpu for State8080 {
+    fn variant(&self) -> CpuVariant {
+        return State8080::variant(self);
+    }
+
+    fn step(&mut self) -> Result<u32, EmulatorError> {
+        return self.emulate_op();
+    }
+
+    fn cycles(&self) -> u64 {
+        return State8080::cycles(self);
+    }
+
+    fn registers(&self) -> Registers {
+        return State8080::registers(self);
+    }
+
+    fn restore_registers(&mut self, registers: &Registers) {
+        State8080::restore_registers(self, registers);
+    }
+
+    fn interrupt(&mut self, opcode: u8) -> Result<bool, EmulatorError> {
+        return State8080::interrupt(self, opcode);
+    }
+
+    fn is_halted(&self) -> bool {
+        return State8080::is_halted(self);
+    }
+
+    fn reset(&mut self) {
+        State8080::reset(self);
+    }
+
+    fn set_memory(&mut self, memory: Box<dyn Memory>) {
+        State8080::set_memory(self, memory);
+    }
+
+    fn set_io(&mut self, io: Box<dyn IoBus>) {
+        State8080::set_io(self, io);
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        return State8080::peek(self, address);
+    }
+
+    fn poke(&mut self, address: u16, value: u8) {
+        State8080::poke(self, address, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::cpu::Cpu;
+    use crate::emulator::config::CpuVariant;
+    use crate::emulator::registers::RegPair;
+    use crate::emulator::test_utils::*;
+    use crate::emulator::z80::InterruptMode;
+
+    // Runs until HLT, the way a frontend that only knows about Cpu would
+    fn run_to_halt(cpu: &mut dyn Cpu) -> u32 {
+        let mut steps = 0;
+        while !cpu.is_halted() {
+            cpu.step().unwrap();
+            steps += 1;
+        }
+        return steps;
+    }
+
+    #[test]
+    fn test_drive_through_trait() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x3e); // MVI A,0x42
+        state.memory.write(1, 0x42);
+        state.memory.write(2, 0x32); // STA 0x1000
+        state.memory.write(3, 0x00);
+        state.memory.write(4, 0x10);
+        state.memory.write(5, 0x76); // HLT
+
+        let cpu: &mut dyn Cpu = &mut state;
+        assert_eq!(cpu.variant(), CpuVariant::Intel8080);
+        assert_eq!(run_to_halt(cpu), 3);
+        assert_eq!(cpu.cycles(), 7 + 13 + 7);
+        assert_eq!(cpu.registers().a, 0x42);
+        assert_eq!(cpu.peek(0x1000), 0x42);
+
+        let mut registers = cpu.registers();
+        registers.a = 0;
+        cpu.restore_registers(&registers);
+        cpu.reset();
+        assert_eq!(cpu.is_halted(), false);
+        assert_eq!(cpu.registers().pc, 0);
+        assert_eq!(cpu.registers().a, 0);
+    }
+
+    #[test]
+    fn test_z80_registers_round_trip() {
+        let mut state = setup_state();
+        state.variant = CpuVariant::Z80;
+        let program = [
+            0x01, 0x78, 0x56,       // LD BC,0x5678
+            0xdd, 0x21, 0x34, 0x12, // LD IX,0x1234
+            0xed, 0x5e,             // IM 2
+            0xfb,                   // EI
+            0xd9,                   // EXX
+            0x08,                   // EX AF,AF'
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            state.memory.write(i as u16, *byte);
+        }
+        let cpu: &mut dyn Cpu = &mut state;
+        cpu.step().unwrap();
+        let registers = cpu.registers();
+        assert_eq!(registers.z80.is_some(), true);
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert_ne!(cpu.registers(), registers);
+
+        cpu.restore_registers(&registers);
+        assert_eq!(cpu.registers(), registers);
+        assert_eq!(state.pair(RegPair::IX), 0);
+        assert_eq!(state.pair(RegPair::BC), 0x5678);
+        assert_eq!(state.interrupt_mode(), InterruptMode::Mode0);
+        assert_eq!(state.interrupts_enabled(), false);
+    }
+
+    #[test]
+    fn test_poke_drops_cached_block() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x3c); // INR A
+        state.memory.write(1, 0x76); // HLT
+        state.emulate_block().unwrap();
+        assert_eq!(state.a, 1);
+
+        let cpu: &mut dyn Cpu = &mut state;
+        cpu.poke(0, 0x3d); // DCR A
+        cpu.reset();
+        state.emulate_block().unwrap();
+        assert_eq!(state.a, 0);
+    }
+
+    #[test]
+    fn test_set_memory() {
+        let mut state = setup_state();
+        let cpu: &mut dyn Cpu = &mut state;
+        cpu.set_memory(Box::new(vec![0x3c, 0x76]));
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers().a, 1);
+        assert_eq!(cpu.peek(1), 0x76);
+    }
+}
@@ -0,0 +1,42 @@
+use std::error::Error;
+use std::fmt;
+
+// Why a step couldn't be run
+#[derive(Debug, PartialEq)]
+pub enum EmulatorError {
+    // The op code at address isn't implemented and the policy said to trap
+    UnimplementedOpcode { opcode: u8, address: u16 },
+    // Nothing answered an access to address and the memory treats that as a fault
+    BusFault { address: u16 },
+    // HLT ran with interrupts disabled at address, nothing can wake the cpu up again
+    HaltedWithInterruptsDisabled { address: u16 },
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulatorError::UnimplementedOpcode { opcode, address } => {
+                return write!(f, "Unimplemented op code {:02x} at {:04x}", opcode, address);
+            }
+            EmulatorError::BusFault { address } => {
+                return write!(f, "Bus fault accessing {:04x}", address);
+            }
+            EmulatorError::HaltedWithInterruptsDisabled { address } => {
+                return write!(f, "Halted with interrupts disabled at {:04x}", address);
+            }
+        }
+    }
+}
+
+impl Error for EmulatorError {}
+
+// What to do when the cpu fetches an op code it doesn't implement
+pub enum UnknownOpcodePolicy {
+    // Stop and return UnimplementedOpcode
+    Trap,
+    // Log it and carry on as if it were a NOP
+    Nop,
+    // Hand the op code and its address to the callback, Ok carries on as a NOP and Err is returned
+    // from the step
+    Callback(Box<dyn FnMut(u8, u16) -> Result<(), EmulatorError>>),
+}
@@ -0,0 +1,397 @@
+use crate::decoder::decode_for;
+use crate::emulator::error::EmulatorError;
+use crate::emulator::State8080;
+
+// The interrupt inputs the 8085 has on top of INTR, highest priority first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptPin {
+    Trap,  // Not maskable, vectors to 0x24
+    Rst75, // Rising edge, latched until taken or reset by SIM, vectors to 0x3c
+    Rst65, // Level, vectors to 0x34
+    Rst55, // Level, vectors to 0x2c
+}
+
+// Taking one of the pin interrupts costs the same as an RST
+const PIN_INTERRUPT_CYCLES: u32 = 12;
+
+// SIM bits, from a
+const SIM_SOD: u8 = 0b1000_0000;
+const SIM_SOD_ENABLE: u8 = 0b0100_0000;
+const SIM_RESET_75: u8 = 0b0001_0000;
+const SIM_MASK_ENABLE: u8 = 0b0000_1000;
+// The three masks, RST 7.5, 6.5 and 5.5 from high to low, a set bit masks the interrupt
+const MASKS: u8 = 0b0000_0111;
+const MASK_75: u8 = 0b100;
+const MASK_65: u8 = 0b010;
+const MASK_55: u8 = 0b001;
+
+// RIM bits, into a, the masks come back in the low three bits
+const RIM_SID: u8 = 0b1000_0000;
+const RIM_PENDING_75: u8 = 0b0100_0000;
+const RIM_PENDING_65: u8 = 0b0010_0000;
+const RIM_PENDING_55: u8 = 0b0001_0000;
+const RIM_INTERRUPTS_ENABLED: u8 = 0b0000_1000;
+
+// What the 8085's extra pins are doing, the 8080 just ignores all of this
+#[derive(Debug)]
+pub(crate) struct Pins {
+    masks: u8,
+    trap: bool,           // Level on TRAP
+    trap_pending: bool,   // Set on a rising edge, TRAP also has to stay high until it is taken
+    rst75: bool,          // Level on RST 7.5
+    rst75_latch: bool,    // Set on a rising edge, stays set while masked
+    rst65: bool,
+    rst55: bool,
+    ie_before_trap: Option<bool>, // The first RIM after a TRAP shows interrupts as they were
+    sid: bool,
+    sod: bool,
+}
+
+impl Pins {
+    // RESET IN masks all three RST inputs, drops the 7.5 latch and pulls SOD low
+    pub fn new() -> Pins {
+        return Pins {
+            masks: MASKS,
+            trap: false,
+            trap_pending: false,
+            rst75: false,
+            rst75_latch: false,
+            rst65: false,
+            rst55: false,
+            ie_before_trap: None,
+            sid: false,
+            sod: false,
+        };
+    }
+
+    // Resets the cpu side, the levels on the input pins are whatever the hardware drives
+    pub fn reset(&mut self) {
+        self.masks = MASKS;
+        self.trap_pending = false;
+        self.rst75_latch = false;
+        self.ie_before_trap = None;
+        self.sod = false;
+    }
+}
+
+impl State8080 {
+    // Drive one of the 8085's interrupt pins high or low. They are checked before every
+    // instruction, so a level triggered pin has to be held until it is taken. Ignored on the 8080,
+    // which doesn't have them.
+    pub fn set_interrupt_pin(&mut self, pin: InterruptPin, high: bool) {
+        let pins = &mut self.pins;
+        match pin {
+            InterruptPin::Trap => {
+                if high && !pins.trap {
+                    pins.trap_pending = true;
+                } else if !high {
+                    pins.trap_pending = false;
+                }
+                pins.trap = high;
+            }
+            InterruptPin::Rst75 => {
+                if high && !pins.rst75 {
+                    pins.rst75_latch = true;
+                }
+                pins.rst75 = high;
+            }
+            InterruptPin::Rst65 => pins.rst65 = high,
+            InterruptPin::Rst55 => pins.rst55 = high,
+        }
+    }
+
+    // The serial input line, read into bit 7 of a by RIM
+    pub fn set_sid(&mut self, high: bool) {
+        self.pins.sid = high;
+    }
+
+    // The serial output line, set from bit 7 of a by SIM
+    pub fn sod(&self) -> bool {
+        return self.pins.sod;
+    }
+
+    pub(crate) fn rim(&mut self) -> u8 {
+        let pins = &mut self.pins;
+        let mut value = pins.masks;
+        if pins.sid { value |= RIM_SID; }
+        if pins.rst75_latch { value |= RIM_PENDING_75; }
+        if pins.rst65 { value |= RIM_PENDING_65; }
+        if pins.rst55 { value |= RIM_PENDING_55; }
+        let enabled = match pins.ie_before_trap.take() {
+            Some(enabled) => enabled,
+            None => self.int_enable,
+        };
+        if enabled { value |= RIM_INTERRUPTS_ENABLED; }
+        return value;
+    }
+
+    pub(crate) fn sim(&mut self, value: u8) {
+        let pins = &mut self.pins;
+        if value & SIM_MASK_ENABLE != 0 {
+            pins.masks = value & MASKS;
+        }
+        if value & SIM_RESET_75 != 0 {
+            pins.rst75_latch = false;
+        }
+        if value & SIM_SOD_ENABLE != 0 {
+            pins.sod = value & SIM_SOD != 0;
+        }
+    }
+
+    // Takes the highest priority pin interrupt that is pending and allowed, if there is one, and
+    // returns the T-states it took. It runs as a CALL to the vector so traces show it, costing
+    // what an RST does.
+    pub(crate) fn service_pins(&mut self) -> Result<Option<u32>, EmulatorError> {
+        let enabled = self.interrupts_enabled();
+        let pins = &mut self.pins;
+        let vector = if pins.trap_pending {
+            pins.trap_pending = false;
+            pins.ie_before_trap = Some(self.int_enable);
+            0x24
+        } else if enabled && pins.rst75_latch && pins.masks & MASK_75 == 0 {
+            pins.rst75_latch = false;
+            0x3c
+        } else if enabled && pins.rst65 && pins.masks & MASK_65 == 0 {
+            0x34
+        } else if enabled && pins.rst55 && pins.masks & MASK_55 == 0 {
+            0x2c
+        } else {
+            return Ok(None);
+        };
+
+        self.int_enable = false;
+        self.ei_delay = false;
+        self.halted = false;
+        let instruction = decode_for(self.variant, &[0xcd, vector, 0x00], self.pc);
+        let cycles = self.run_costing(&instruction, Some(PIN_INTERRUPT_CYCLES))?;
+        return Ok(Some(cycles));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::config::CpuVariant;
+    use crate::emulator::i8085::*;
+
+    fn setup_8085() -> State8080 {
+        let mut state = State8080::builder()
+            .variant(CpuVariant::Intel8085)
+            .stack_pointer(100)
+            .build();
+        state.memory.write(0, 0xfb); // EI op code
+        state.memory.write(1, 0x00); // NOP op code
+        return state;
+    }
+
+    #[test]
+    fn test_rim_after_reset() {
+        let mut state = setup_8085();
+        state.memory.write(0, 0x20); // RIM op code
+        state.set_sid(true);
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, RIM_SID | MASKS);
+    }
+
+    #[test]
+    fn test_sim_masks_and_sod() {
+        let mut state = setup_8085();
+        state.memory.write(0, 0x30); // SIM op code
+        state.memory.write(1, 0x30); // SIM op code
+        state.memory.write(2, 0x20); // RIM op code
+
+        state.a = SIM_SOD | SIM_SOD_ENABLE | SIM_MASK_ENABLE | MASK_65;
+        state.emulate_op().unwrap();
+        assert_eq!(state.sod(), true);
+        assert_eq!(state.pins.masks, MASK_65);
+
+        // without the enable bits nothing changes
+        state.a = 0b0000_0111;
+        state.emulate_op().unwrap();
+        assert_eq!(state.sod(), true);
+        assert_eq!(state.pins.masks, MASK_65);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, MASK_65);
+    }
+
+    #[test]
+    fn test_rst55_level() {
+        let mut state = setup_8085();
+        state.sim(SIM_MASK_ENABLE); // unmask everything
+        state.set_interrupt_pin(InterruptPin::Rst55, true);
+
+        // EI has to be followed by one more instruction before anything is taken
+        assert_eq!(state.emulate_op(), Ok(4));
+        assert_eq!(state.emulate_op(), Ok(4));
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.emulate_op(), Ok(12));
+        assert_eq!(state.pc, 0x2c);
+        assert_eq!(state.memory.read(98), 0x02);
+        assert_eq!(state.interrupts_enabled(), false);
+    }
+
+    #[test]
+    fn test_masked_interrupts_are_ignored() {
+        let mut state = setup_8085();
+        state.set_interrupt_pin(InterruptPin::Rst65, true);
+        state.set_interrupt_pin(InterruptPin::Rst55, true);
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 3);
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut state = setup_8085();
+        state.sim(SIM_MASK_ENABLE);
+        state.set_interrupt_pin(InterruptPin::Rst55, true);
+        state.set_interrupt_pin(InterruptPin::Rst65, true);
+        state.set_interrupt_pin(InterruptPin::Rst75, true);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.pc, 0x3c);
+
+        state.memory.write(0x3c, 0xfb); // EI op code
+        state.memory.write(0x3d, 0x00); // NOP op code
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.pc, 0x34);
+    }
+
+    #[test]
+    fn test_rst75_is_latched() {
+        let mut state = setup_8085();
+        state.set_interrupt_pin(InterruptPin::Rst75, true);
+        state.set_interrupt_pin(InterruptPin::Rst75, false);
+        state.memory.write(1, 0x20); // RIM op code
+        state.memory.write(2, 0x30); // SIM op code
+        state.memory.write(3, 0x20); // RIM op code
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.a & RIM_PENDING_75, RIM_PENDING_75);
+        assert_eq!(state.pc, 2);
+
+        // unmasking takes it, the edge is remembered
+        state.a = SIM_MASK_ENABLE;
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.pc, 0x3c);
+    }
+
+    #[test]
+    fn test_sim_resets_rst75() {
+        let mut state = setup_8085();
+        state.set_interrupt_pin(InterruptPin::Rst75, true);
+        state.sim(SIM_RESET_75 | SIM_MASK_ENABLE);
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 3);
+    }
+
+    #[test]
+    fn test_trap() {
+        let mut state = setup_8085();
+        state.memory.write(0, 0x76); // HLT op code
+        state.memory.write(0x24, 0x20); // RIM op code
+        state.memory.write(0x25, 0x20); // RIM op code
+
+        state.emulate_op().unwrap();
+        // halted with interrupts off, but TRAP can still wake it up
+        assert_eq!(state.is_stopped(), false);
+        assert_eq!(state.emulate_op(), Ok(4));
+
+        state.set_interrupt_pin(InterruptPin::Trap, true);
+        assert_eq!(state.emulate_op(), Ok(12));
+        assert_eq!(state.pc, 0x24);
+        assert_eq!(state.is_halted(), false);
+
+        // it was taken, holding the pin high doesn't take it again
+        state.emulate_op().unwrap();
+        assert_eq!(state.pc, 0x25);
+        assert_eq!(state.a & RIM_INTERRUPTS_ENABLED, 0);
+    }
+
+    #[test]
+    fn test_rim_after_trap_shows_old_interrupt_enable() {
+        let mut state = setup_8085();
+        state.memory.write(0x24, 0x20); // RIM op code
+        state.memory.write(0x25, 0x20); // RIM op code
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        state.set_interrupt_pin(InterruptPin::Trap, true);
+        state.emulate_op().unwrap();
+        assert_eq!(state.interrupts_enabled(), false);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a & RIM_INTERRUPTS_ENABLED, RIM_INTERRUPTS_ENABLED);
+        state.emulate_op().unwrap();
+        assert_eq!(state.a & RIM_INTERRUPTS_ENABLED, 0);
+    }
+
+    #[test]
+    fn test_trap_dropped_before_it_is_taken() {
+        let mut state = setup_8085();
+        state.set_interrupt_pin(InterruptPin::Trap, true);
+        state.set_interrupt_pin(InterruptPin::Trap, false);
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 1);
+    }
+
+    #[test]
+    fn test_8085_timings() {
+        let mut state = setup_8085();
+        state.memory.write(0, 0x41); // MOV B, C op code
+        state.memory.write(1, 0xc2); // JNZ 0x0000
+        state.memory.write(2, 0x00);
+        state.memory.write(3, 0x00);
+        state.memory.write(4, 0xc2); // JNZ 0x0000
+        state.memory.write(5, 0x00);
+        state.memory.write(6, 0x00);
+
+        assert_eq!(state.emulate_op(), Ok(4));
+        state.cc.z = true;
+        assert_eq!(state.emulate_op(), Ok(7));
+        state.cc.z = false;
+        assert_eq!(state.emulate_op(), Ok(10));
+        assert_eq!(state.pc, 0);
+    }
+
+    #[test]
+    fn test_undocumented_ops_trap() {
+        let mut state = setup_8085();
+        state.memory.write(0, 0x08); // DSUB on the 8085, not emulated
+
+        assert_eq!(
+            state.emulate_op(),
+            Err(EmulatorError::UnimplementedOpcode { opcode: 0x08, address: 0 })
+        );
+    }
+
+    #[test]
+    fn test_8080_ignores_pins() {
+        let mut state = State8080::builder().stack_pointer(100).build();
+        state.memory.write(0, 0xfb); // EI op code
+        state.set_interrupt_pin(InterruptPin::Trap, true);
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 3);
+    }
+}
@@ -0,0 +1,88 @@
+use log::info;
+
+// Everything the cpu sends or receives with IN and OUT goes through an IoBus. Machines implement
+// it to hook up their own devices (shift registers, serial cards, sound latches...).
+pub trait IoBus {
+    // IN port, the returned value ends up in a
+    fn input(&mut self, port: u8) -> u8;
+
+    // OUT port, value is whatever was in a
+    fn output(&mut self, port: u8, value: u8);
+}
+
+// The default bus, nothing is attached so reads float high and every access is logged
+pub struct LoggingIoBus;
+
+impl IoBus for LoggingIoBus {
+    fn input(&mut self, port: u8) -> u8 {
+        info!("IN  port {:02x}", port);
+        return 0xff;
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        info!("OUT port {:02x} value {:02x}", port, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::io::IoBus;
+    use crate::emulator::test_utils::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Records every OUT and answers every IN with port + 1
+    struct RecordingIoBus {
+        outputs: Rc<RefCell<Vec<(u8, u8)>>>,
+    }
+
+    impl IoBus for RecordingIoBus {
+        fn input(&mut self, port: u8) -> u8 {
+            return port + 1;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.outputs.borrow_mut().push((port, value));
+        }
+    }
+
+    #[test]
+    fn test_in() {
+        let mut state = setup_state();
+        state.set_io(Box::new(RecordingIoBus { outputs: Rc::new(RefCell::new(vec![])) }));
+        state.memory.write(0, 0xdb); // IN op code
+        state.memory.write(1, 0x41); // port
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0x42);
+        assert_eq!(state.pc, 2);
+    }
+
+    #[test]
+    fn test_out() {
+        let outputs = Rc::new(RefCell::new(vec![]));
+        let mut state = setup_state();
+        state.set_io(Box::new(RecordingIoBus { outputs: outputs.clone() }));
+        state.memory.write(0, 0xd3); // OUT op code
+        state.memory.write(1, 0x03); // port
+        state.a = 0x99;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(*outputs.borrow(), vec![(0x03, 0x99)]);
+        assert_eq!(state.a, 0x99);
+        assert_eq!(state.pc, 2);
+    }
+
+    #[test]
+    fn test_default_bus_reads_high() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xdb); // IN op code
+        state.memory.write(1, 0x00); // port
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0xff);
+    }
+}
@@ -1,11 +1,78 @@
+use crate::decoder::RotOp;
+use crate::emulator::config::CpuVariant;
+use crate::emulator::utils::update_flags;
 use crate::emulator::State8080;
 
+// The logical ops always clear cy. ana sets ac to the OR of bit 3 of both operands, which is an
+// 8080 quirk (the 8085 and Z80 always set it), while xra and ora clear it.
+pub fn ana(value: u8, state: &mut State8080) {
+    state.cc.ac = (state.a | value) & 0x08 != 0 || state.variant != CpuVariant::Intel8080;
+    state.a &= value;
+    update_logical_flags(state.a, state);
+}
+
+pub fn xra(value: u8, state: &mut State8080) {
+    state.cc.ac = false;
+    state.a ^= value;
+    update_logical_flags(state.a, state);
+}
+
+pub fn ora(value: u8, state: &mut State8080) {
+    state.cc.ac = false;
+    state.a |= value;
+    update_logical_flags(state.a, state);
+}
+
+// s, z and p from value, cy and n cleared
+pub fn update_logical_flags(value: u8, state: &mut State8080) {
+    update_flags(value as u16, &mut state.cc);
+    state.cc.n = false;
+}
+
+// The Z80's CB page shifts and rotates, unlike RLC and friends they set every flag
+pub fn rotate(op: RotOp, value: u8, state: &mut State8080) -> u8 {
+    let carry_in = state.cc.cy as u8;
+    let (answer, carry) = match op {
+        RotOp::Rlc => (value.rotate_left(1), value & 0x80 != 0),
+        RotOp::Rrc => (value.rotate_right(1), value & 0x01 != 0),
+        RotOp::Rl => (value << 1 | carry_in, value & 0x80 != 0),
+        RotOp::Rr => (value >> 1 | carry_in << 7, value & 0x01 != 0),
+        RotOp::Sla => (value << 1, value & 0x80 != 0),
+        RotOp::Sra => (value >> 1 | (value & 0x80), value & 0x01 != 0),
+        RotOp::Sll => (value << 1 | 0x01, value & 0x80 != 0),
+        RotOp::Srl => (value >> 1, value & 0x01 != 0),
+    };
+    update_logical_flags(answer, state);
+    state.cc.ac = false;
+    state.cc.cy = carry;
+    return answer;
+}
+
+// BIT n, z is set when the bit is clear. Only BIT 7 can set s.
+pub fn bit(n: u8, value: u8, state: &mut State8080) {
+    let clear = value & (1 << n) == 0;
+    state.cc.z = clear;
+    state.cc.p = clear;
+    state.cc.s = n == 7 && !clear;
+    state.cc.ac = true;
+    state.cc.n = false;
+}
+
+// The Z80 clears h and n when rotating a
+fn rotated_a(state: &mut State8080) {
+    if state.variant == CpuVariant::Z80 {
+        state.cc.ac = false;
+    }
+    state.cc.n = false;
+}
+
 pub fn rlc(state: &mut State8080) {
     state.cc.cy = state.a >> 7 == 0b1;
     state.a = state.a << 1;
     if state.cc.cy {
         state.a |= 0b1;
     }
+    rotated_a(state);
 }
 
 pub fn rrc(state: &mut State8080) {
@@ -14,6 +81,7 @@ pub fn rrc(state: &mut State8080) {
     if state.cc.cy {
         state.a |= 0b1 << 7;
     }
+    rotated_a(state);
 }
 
 pub fn ral(state: &mut State8080) {
@@ -23,6 +91,7 @@ pub fn ral(state: &mut State8080) {
     if old_carry {
         state.a |= 0b1;
     }
+    rotated_a(state);
 }
 
 pub fn rar(state: &mut State8080) {
@@ -32,20 +101,22 @@ pub fn rar(state: &mut State8080) {
     if old_carry {
         state.a |= 0b1 << 7;
     }
+    rotated_a(state);
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::emulator::config::CpuVariant;
     use crate::emulator::test_utils::*;
 
     #[test]
     fn test_rlc_with_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x07; // RLC op code
+        state.memory.write(0, 0x07); // RLC op code
         state.a = 0b11110010;
         state.cc.cy = false;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b11100101);
         assert_eq!(state.cc.cy, true);
@@ -54,11 +125,11 @@ mod tests {
     #[test]
     fn test_rlc_without_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x07; // RLC op code
+        state.memory.write(0, 0x07); // RLC op code
         state.a = 0b01110010;
         state.cc.cy = true;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b11100100);
         assert_eq!(state.cc.cy, false);
@@ -67,11 +138,11 @@ mod tests {
     #[test]
     fn test_rrc_without_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x0f; // RRC op code
+        state.memory.write(0, 0x0f); // RRC op code
         state.a = 0b11110010;
         state.cc.cy = true;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b01111001);
         assert_eq!(state.cc.cy, false);
@@ -80,11 +151,11 @@ mod tests {
     #[test]
     fn test_rrc_with_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x0f; // RRC op code
+        state.memory.write(0, 0x0f); // RRC op code
         state.a = 0b11110011;
         state.cc.cy = false;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b11111001);
         assert_eq!(state.cc.cy, true);
@@ -93,11 +164,11 @@ mod tests {
     #[test]
     fn test_ral_with_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x17; // RRC op code
+        state.memory.write(0, 0x17); // RRC op code
         state.a = 0b10110101;
         state.cc.cy = false;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b01101010);
         assert_eq!(state.cc.cy, true);
@@ -106,11 +177,11 @@ mod tests {
     #[test]
     fn test_ral_without_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x17; // RRC op code
+        state.memory.write(0, 0x17); // RRC op code
         state.a = 0b00110101;
         state.cc.cy = true;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b01101011);
         assert_eq!(state.cc.cy, false);
@@ -119,11 +190,11 @@ mod tests {
     #[test]
     fn test_rar_with_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x1f; // rar op code
+        state.memory.write(0, 0x1f); // rar op code
         state.a = 0b01101010;
         state.cc.cy = true;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b10110101);
         assert_eq!(state.cc.cy, false);
@@ -132,13 +203,144 @@ mod tests {
     #[test]
     fn test_rar_without_carry() {
         let mut state = setup_state();
-        state.memory[0] = 0x1f; // rar op code
+        state.memory.write(0, 0x1f); // rar op code
         state.a = 0b01101011;
         state.cc.cy = false;
 
-        state.emulate_op();
+        state.emulate_op().unwrap();
 
         assert_eq!(state.a, 0b00110101);
         assert_eq!(state.cc.cy, true);
     }
+
+    #[test]
+    fn test_ana() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xa1); // ANA C op code
+        state.a = 0b11111100;
+        state.c = 0b00001111;
+        state.cc.cy = true;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0b00001100);
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.ac, true);
+        assert_eq!(state.cc.p, true);
+        assert_eq!(state.cc.z, false);
+    }
+
+    #[test]
+    fn test_ana_m() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xa6); // ANA M op code
+        state.memory.write(0x2000, 0b00110000);
+        state.h = 0x20;
+        state.l = 0x00;
+        state.a = 0b11000011;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0);
+        assert_eq!(state.cc.z, true);
+        assert_eq!(state.cc.ac, false);
+    }
+
+    #[test]
+    fn test_ana_8085_sets_ac() {
+        let mut state = setup_state();
+        state.variant = CpuVariant::Intel8085;
+        state.memory.write(0, 0xa1); // ANA C op code
+        state.a = 0b11110000;
+        state.c = 0b00110000;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0b00110000);
+        assert_eq!(state.cc.ac, true);
+    }
+
+    #[test]
+    fn test_xra() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xaf); // XRA A op code
+        state.memory.write(1, 0xa8); // XRA B op code
+        state.a = 0x5c;
+        state.b = 0x78;
+        state.cc.cy = true;
+        state.cc.ac = true;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0);
+        assert_eq!(state.cc.z, true);
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.ac, false);
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0x78);
+        assert_eq!(state.cc.z, false);
+    }
+
+    #[test]
+    fn test_ora() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xb1); // ORA C op code
+        state.a = 0b00110011;
+        state.c = 0b00001111;
+        state.cc.cy = true;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0b00111111);
+        assert_eq!(state.cc.cy, false);
+        assert_eq!(state.cc.p, true);
+        assert_eq!(state.cc.s, false);
+    }
+
+    #[test]
+    fn test_ani() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xe6); // ANI op code
+        state.memory.write(1, 0x0f); // immediate
+        state.a = 0x3a;
+        state.cc.cy = true;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0x0a);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_xri() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xee); // XRI op code
+        state.memory.write(1, 0x81); // immediate
+        state.a = 0x3b;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0xba);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.s, true);
+        assert_eq!(state.cc.cy, false);
+    }
+
+    #[test]
+    fn test_ori() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xf6); // ORI op code
+        state.memory.write(1, 0x0f); // immediate
+        state.a = 0xb5;
+        state.cc.cy = true;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.a, 0xbf);
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.cc.cy, false);
+    }
 }
@@ -0,0 +1,331 @@
+// Every read and write the cpu makes goes through a Memory, so machines can model their own
+// address decoding: ROM that ignores writes, RAM mirrors, holes and memory mapped devices.
+pub trait Memory {
+    fn read(&mut self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, value: u8);
+
+    // Read without any side effects on devices, for the disassembler and debuggers
+    fn peek(&self, address: u16) -> u8;
+
+    // The address of the first access since the last call that the memory treats as a fault, the
+    // cpu checks this after every instruction. By default nothing ever faults.
+    fn take_fault(&mut self) -> Option<u16> {
+        return None;
+    }
+}
+
+// A plain buffer is RAM from address 0, anything past the end of it is unmapped
+impl Memory for Vec<u8> {
+    fn read(&mut self, address: u16) -> u8 {
+        return self.peek(address);
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        return *self.get(address as usize).unwrap_or(&UNMAPPED);
+    }
+}
+
+// What the data bus floats to when nothing answers a read
+pub const UNMAPPED: u8 = 0xff;
+
+// A memory mapped peripheral. Addresses are relative to the start of its region.
+pub trait MemoryDevice {
+    fn read(&mut self, offset: u16) -> u8;
+
+    fn write(&mut self, offset: u16, value: u8);
+
+    // Reading a device register can have side effects, so by default debuggers don't get to
+    fn peek(&self, _offset: u16) -> u8 {
+        return UNMAPPED;
+    }
+}
+
+pub enum Region {
+    Rom(Vec<u8>),
+    Ram(Vec<u8>),
+    // Another range of the map shows up here again, the address wraps around its size
+    Mirror { target: u16, size: u32 },
+    Device(Box<dyn MemoryDevice>),
+}
+
+struct MappedRegion {
+    start: u32,
+    end: u32, // Exclusive
+    region: Region,
+}
+
+// An address space built out of regions. The first region that covers an address wins, reads from
+// addresses no region covers return UNMAPPED and writes to them are dropped, unless the map was
+// told to fault on them.
+pub struct MemoryMap {
+    regions: Vec<MappedRegion>,
+    fault_on_unmapped: bool,
+    fault: Option<u16>,
+}
+
+impl Default for MemoryMap {
+    fn default() -> MemoryMap {
+        return MemoryMap::new();
+    }
+}
+
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        return MemoryMap { regions: vec![], fault_on_unmapped: false, fault: None };
+    }
+
+    // Report reads and writes that no region answers as bus faults
+    pub fn fault_on_unmapped(mut self) -> MemoryMap {
+        self.fault_on_unmapped = true;
+        return self;
+    }
+
+    fn unmapped(&mut self, address: u16) {
+        if self.fault_on_unmapped && self.fault.is_none() {
+            self.fault = Some(address);
+        }
+    }
+
+    // Read only memory holding data, writes to it are ignored like on the real chips
+    pub fn rom(self, start: u16, data: Vec<u8>) -> MemoryMap {
+        let size = data.len() as u32;
+        return self.region(start, size, Region::Rom(data));
+    }
+
+    pub fn ram(self, start: u16, size: u32) -> MemoryMap {
+        return self.region(start, size, Region::Ram(vec![0; size as usize]));
+    }
+
+    // Makes start..start + size another view of target..target + mirrored_size, repeating it
+    // if the mirror is bigger than the target
+    pub fn mirror(self, start: u16, size: u32, target: u16, mirrored_size: u32) -> MemoryMap {
+        return self.region(start, size, Region::Mirror { target, size: mirrored_size });
+    }
+
+    pub fn device(self, start: u16, size: u32, device: Box<dyn MemoryDevice>) -> MemoryMap {
+        return self.region(start, size, Region::Device(device));
+    }
+
+    pub fn region(mut self, start: u16, size: u32, region: Region) -> MemoryMap {
+        let start = start as u32;
+        let end = (start + size).min(0x10000);
+        self.regions.push(MappedRegion { start, end, region });
+        return self;
+    }
+
+    // Which region an address lands in and the offset into it, following mirrors
+    fn find(&self, address: u16) -> Option<(usize, u16)> {
+        let mut address = address;
+        // Bounded so a mirror pointing at itself can't hang the emulator
+        for _ in 0..self.regions.len() {
+            let index = self.regions.iter().position(|mapped| {
+                mapped.start <= address as u32 && (address as u32) < mapped.end
+            })?;
+            let mapped = &self.regions[index];
+            let offset = address as u32 - mapped.start;
+            match mapped.region {
+                Region::Mirror { target, size } if size > 0 => {
+                    address = target.wrapping_add((offset % size) as u16);
+                }
+                Region::Mirror { .. } => return None,
+                _ => return Some((index, offset as u16)),
+            }
+        }
+        return None;
+    }
+}
+
+impl Memory for MemoryMap {
+    fn read(&mut self, address: u16) -> u8 {
+        if let Some((index, offset)) = self.find(address) {
+            match self.regions[index].region {
+                Region::Rom(ref data) | Region::Ram(ref data) => return data[offset as usize],
+                Region::Device(ref mut device) => return device.read(offset),
+                Region::Mirror { .. } => {}
+            }
+        }
+        self.unmapped(address);
+        return UNMAPPED;
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if let Some((index, offset)) = self.find(address) {
+            match self.regions[index].region {
+                Region::Ram(ref mut data) => data[offset as usize] = value,
+                Region::Device(ref mut device) => device.write(offset, value),
+                Region::Rom(_) | Region::Mirror { .. } => {}
+            }
+        } else {
+            self.unmapped(address);
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        if let Some((index, offset)) = self.find(address) {
+            match self.regions[index].region {
+                Region::Rom(ref data) | Region::Ram(ref data) => return data[offset as usize],
+                Region::Device(ref device) => return device.peek(offset),
+                Region::Mirror { .. } => {}
+            }
+        }
+        return UNMAPPED;
+    }
+
+    fn take_fault(&mut self) -> Option<u16> {
+        return self.fault.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::memory::*;
+    use crate::emulator::State8080;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_vec_memory() {
+        let mut memory = vec![1, 2, 3];
+        assert_eq!(memory.read(1), 2);
+        memory.write(1, 0x22);
+        assert_eq!(memory.read(1), 0x22);
+        // past the end of the buffer is unmapped
+        memory.write(3, 0x33);
+        assert_eq!(memory.read(3), 0xff);
+        assert_eq!(memory.len(), 3);
+    }
+
+    #[test]
+    fn test_rom_ignores_writes() {
+        let mut memory = MemoryMap::new().rom(0x0000, vec![0xaa, 0xbb]);
+        memory.write(0x0001, 0x00);
+        assert_eq!(memory.read(0x0000), 0xaa);
+        assert_eq!(memory.read(0x0001), 0xbb);
+    }
+
+    #[test]
+    fn test_ram() {
+        let mut memory = MemoryMap::new().ram(0x2000, 0x400);
+        assert_eq!(memory.read(0x2000), 0);
+        memory.write(0x23ff, 0x42);
+        assert_eq!(memory.read(0x23ff), 0x42);
+        assert_eq!(memory.peek(0x23ff), 0x42);
+    }
+
+    #[test]
+    fn test_unmapped_reads_ff() {
+        let mut memory = MemoryMap::new().ram(0x2000, 0x400);
+        memory.write(0x2400, 0x42);
+        assert_eq!(memory.read(0x2400), 0xff);
+        assert_eq!(memory.read(0x0000), 0xff);
+        assert_eq!(memory.read(0xffff), 0xff);
+    }
+
+    #[test]
+    fn test_fault_on_unmapped() {
+        let mut memory = MemoryMap::new().ram(0x2000, 0x400).fault_on_unmapped();
+        memory.write(0x2000, 0x42);
+        assert_eq!(memory.take_fault(), None);
+        assert_eq!(memory.read(0x1fff), 0xff);
+        memory.write(0x2400, 0x42);
+        // only the first fault is kept
+        assert_eq!(memory.take_fault(), Some(0x1fff));
+        assert_eq!(memory.take_fault(), None);
+        // peeking never faults
+        assert_eq!(memory.peek(0x0000), 0xff);
+        assert_eq!(memory.take_fault(), None);
+    }
+
+    #[test]
+    fn test_mirror() {
+        // Space Invaders' RAM at 0x2000-0x3fff shows up again from 0x4000
+        let mut memory = MemoryMap::new()
+            .ram(0x2000, 0x2000)
+            .mirror(0x4000, 0xc000, 0x2000, 0x2000);
+        memory.write(0x4001, 0x11);
+        assert_eq!(memory.read(0x2001), 0x11);
+        memory.write(0x2002, 0x22);
+        assert_eq!(memory.read(0x4002), 0x22);
+        assert_eq!(memory.read(0x6002), 0x22);
+        assert_eq!(memory.read(0xe002), 0x22);
+    }
+
+    #[test]
+    fn test_mirror_of_itself_is_unmapped() {
+        let mut memory = MemoryMap::new().mirror(0x0000, 0x100, 0x0000, 0x100);
+        assert_eq!(memory.read(0x0010), 0xff);
+    }
+
+    #[test]
+    fn test_first_region_wins() {
+        let mut memory = MemoryMap::new()
+            .rom(0x0000, vec![0x01; 0x10])
+            .ram(0x0000, 0x10000);
+        memory.write(0x0000, 0x02);
+        memory.write(0x0010, 0x03);
+        assert_eq!(memory.read(0x0000), 0x01);
+        assert_eq!(memory.read(0x0010), 0x03);
+    }
+
+    // Remembers the last write and counts reads so side effects can be checked
+    struct Latch {
+        value: u8,
+        reads: Rc<RefCell<u32>>,
+    }
+
+    impl MemoryDevice for Latch {
+        fn read(&mut self, offset: u16) -> u8 {
+            *self.reads.borrow_mut() += 1;
+            return self.value.wrapping_add(offset as u8);
+        }
+
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.value = value;
+        }
+    }
+
+    #[test]
+    fn test_device() {
+        let reads = Rc::new(RefCell::new(0));
+        let mut memory = MemoryMap::new().device(
+            0x5000,
+            0x10,
+            Box::new(Latch { value: 0, reads: reads.clone() }),
+        );
+        memory.write(0x5003, 0x40);
+        assert_eq!(memory.read(0x5000), 0x40);
+        assert_eq!(memory.read(0x5002), 0x42);
+        assert_eq!(*reads.borrow(), 2);
+        // peeking doesn't go through the device
+        assert_eq!(memory.peek(0x5000), 0xff);
+        assert_eq!(*reads.borrow(), 2);
+    }
+
+    #[test]
+    fn test_cpu_with_memory_map() {
+        let rom = vec![
+            0x3e, 0x42, // MVI A, 0x42
+            0x32, 0x00, 0x40, // STA 0x4000
+            0x32, 0x00, 0x00, // STA 0x0000
+        ];
+        let memory = MemoryMap::new()
+            .rom(0x0000, rom)
+            .ram(0x2000, 0x2000)
+            .mirror(0x4000, 0xc000, 0x2000, 0x2000);
+        let mut state = State8080::with_memory(Box::new(memory));
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.memory.read(0x2000), 0x42);
+        assert_eq!(state.memory.read(0x0000), 0x3e);
+    }
+}
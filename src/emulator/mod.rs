@@ -1,28 +1,139 @@
 mod utils;
-mod test_utils;
+#[cfg(test)]
+pub(crate) mod test_utils;
+pub mod io;
+pub mod memory;
+pub mod error;
 mod arithmetic;
 mod branch;
+mod blocks;
+pub mod i8085;
 mod logical;
+pub mod z80;
+pub mod registers;
+pub mod config;
+pub mod trace;
+pub mod cpu;
 
-use log::error;
-use log::debug;
-use crate::disassembler::disassemble_op;
-use crate::emulator::utils::*;
+use log::warn;
+use std::fmt;
+use crate::decoder::decode_for;
+use crate::decoder::is_prefix;
+use crate::decoder::length_for;
+use crate::decoder::AluOp;
+use crate::decoder::Condition;
+use crate::decoder::Instruction;
+use crate::decoder::Op;
+use crate::decoder::Operand;
 use crate::emulator::branch::*;
+use crate::emulator::i8085::Pins;
+use crate::emulator::z80::Z80;
+use crate::emulator::blocks::BlockCache;
 use crate::emulator::arithmetic::*;
 use crate::emulator::logical::*;
+use crate::emulator::io::IoBus;
+use crate::emulator::io::LoggingIoBus;
+use crate::emulator::memory::Memory;
+use crate::emulator::memory::MemoryMap;
+use crate::emulator::memory::Region;
+use crate::emulator::error::EmulatorError;
+use crate::emulator::error::UnknownOpcodePolicy;
+use crate::emulator::registers::RegPair;
+use crate::emulator::config::Config;
+use crate::emulator::config::CpuVariant;
+use crate::emulator::config::MemorySource;
+use crate::emulator::config::DEFAULT_STACK_POINTER;
+use crate::emulator::trace::TraceRecord;
+use crate::emulator::trace::TraceSink;
 
 #[derive(Debug)]
 pub struct ConditionCodes {
-    z: bool,   // Zero, 1 when a is 0, else 0
-    s: bool,   // Sign, 1 when bit 7 (MSB) is set in register a, else 0
-    p: bool,   // Parity, 1 when the answer has an even number of 1 bits
-    cy: bool,  // Carry, 1 when the previous instruction resulted in a carry, else 0
-    ac: bool,  // Auxiliary carry
-    pad: bool, // ?
+    z: bool,  // Zero, 1 when a is 0, else 0
+    s: bool,  // Sign, 1 when bit 7 (MSB) is set in register a, else 0
+    p: bool,  // Parity, 1 when the answer has an even number of 1 bits
+    cy: bool, // Carry, 1 when the previous instruction resulted in a carry, else 0
+    ac: bool, // Auxiliary carry, 1 when there was a carry out of bit 3, else 0
+    n: bool,  // Z80 only, 1 when the last arithmetic was a subtraction
+}
+
+// What a halted cpu burns each time it is stepped
+const HALTED_CYCLES: u32 = 4;
+
+// Bit positions of the flags in the PSW byte: S Z 0 AC 0 P 1 CY
+const PSW_S: u8 = 0b1000_0000;
+const PSW_Z: u8 = 0b0100_0000;
+const PSW_AC: u8 = 0b0001_0000;
+const PSW_P: u8 = 0b0000_0100;
+const PSW_CY: u8 = 0b0000_0001;
+// Bit 1 always reads back as 1 and bits 3 and 5 as 0, whatever was popped into them
+const PSW_ALWAYS_SET: u8 = 0b0000_0010;
+// The Z80 keeps n in bit 1 instead
+const F_N: u8 = 0b0000_0010;
+
+impl Default for ConditionCodes {
+    fn default() -> ConditionCodes {
+        return ConditionCodes::new();
+    }
+}
+
+impl ConditionCodes {
+    pub fn new() -> ConditionCodes {
+        return ConditionCodes::from_psw(0);
+    }
+
+    // Packs the flags into the byte that PUSH PSW stores under a
+    pub fn to_psw(&self) -> u8 {
+        let mut psw = PSW_ALWAYS_SET;
+        if self.s { psw |= PSW_S; }
+        if self.z { psw |= PSW_Z; }
+        if self.ac { psw |= PSW_AC; }
+        if self.p { psw |= PSW_P; }
+        if self.cy { psw |= PSW_CY; }
+        return psw;
+    }
+
+    // Unpacks the byte that POP PSW loads, the fixed bits are ignored
+    pub fn from_psw(psw: u8) -> ConditionCodes {
+        return ConditionCodes {
+            z: psw & PSW_Z != 0,
+            s: psw & PSW_S != 0,
+            p: psw & PSW_P != 0,
+            cy: psw & PSW_CY != 0,
+            ac: psw & PSW_AC != 0,
+            n: false,
+        };
+    }
+
+    // The Z80's F register, like the PSW but with n in bit 1
+    pub fn to_f(&self) -> u8 {
+        let mut f = self.to_psw() & !PSW_ALWAYS_SET;
+        if self.n { f |= F_N; }
+        return f;
+    }
+
+    pub fn from_f(f: u8) -> ConditionCodes {
+        let mut codes = ConditionCodes::from_psw(f);
+        codes.n = f & F_N != 0;
+        return codes;
+    }
+}
+
+impl fmt::Display for ConditionCodes {
+    // Same order as the PSW byte, a letter when the flag is set and a dot when it is clear
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '.' };
+        return write!(
+            f,
+            "{}{}{}{}{}",
+            flag(self.s, 's'),
+            flag(self.z, 'z'),
+            flag(self.ac, 'a'),
+            flag(self.p, 'p'),
+            flag(self.cy, 'c')
+        );
+    }
 }
 
-#[derive(Debug)]
 pub struct State8080 {
     a: u8, // a.k.a. accumulator register
     b: u8,
@@ -33,21 +144,56 @@ pub struct State8080 {
     l: u8,
     sp: u16,
     pc: u16,
-    memory: Vec<u8>,
+    memory: Box<dyn Memory>,
     cc: ConditionCodes,
-    int_enable: u8,
+    int_enable: bool,
+    ei_delay: bool, // Set by EI, interrupts are held off until the instruction after it is done
+    halted: bool,   // Set by HLT, only an interrupt gets the cpu going again
+    cycles: u64,    // Total T-states run since power on
+    io: Box<dyn IoBus>,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+    variant: CpuVariant,
+    config: Option<Config>, // How the builder set this up, None when it wasn't used
+    blocks: BlockCache,     // Decoded code for emulate_block
+    trace: Option<Box<dyn TraceSink>>,
+    pins: Pins, // 8085 interrupt and serial pins
+    z80: Z80,   // The registers the Z80 adds
+}
+
+impl fmt::Debug for State8080 {
+    // Everything but memory and the io bus, which are too big or opaque to be useful here
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.debug_struct("State8080")
+            .field("a", &self.a)
+            .field("b", &self.b)
+            .field("c", &self.c)
+            .field("d", &self.d)
+            .field("e", &self.e)
+            .field("h", &self.h)
+            .field("l", &self.l)
+            .field("sp", &self.sp)
+            .field("pc", &self.pc)
+            .field("cc", &self.cc)
+            .field("int_enable", &self.int_enable)
+            .field("ei_delay", &self.ei_delay)
+            .field("halted", &self.halted)
+            .field("cycles", &self.cycles)
+            .field("variant", &self.variant)
+            .finish();
+    }
 }
 
 impl State8080 {
+    // game_data is loaded as RAM from address 0, anything past the end of it is a bus fault
     pub fn new(game_data: Vec<u8>) -> State8080 {
-        let codes = ConditionCodes {
-            z: false,
-            s: false,
-            p: false,
-            cy: false,
-            ac: false,
-            pad: false,
-        };
+        let size = game_data.len() as u32;
+        let memory = MemoryMap::new()
+            .region(0, size, Region::Ram(game_data))
+            .fault_on_unmapped();
+        return State8080::with_memory(Box::new(memory));
+    }
+
+    pub fn with_memory(memory: Box<dyn Memory>) -> State8080 {
         State8080 {
             a: 0,
             b: 0,
@@ -56,325 +202,860 @@ impl State8080 {
             e: 0,
             h: 0,
             l: 0,
-            sp: 0xf000, // Must be initialized by the program to somewhere not used to store game data or heap
+            sp: DEFAULT_STACK_POINTER, // Must be initialized by the program to somewhere not used to store game data or heap
             pc: 0,
-            memory: game_data.clone(),
-            cc: codes,
-            int_enable: 0,
+            memory,
+            cc: ConditionCodes::new(),
+            int_enable: false,
+            ei_delay: false,
+            halted: false,
+            cycles: 0,
+            io: Box::new(LoggingIoBus),
+            unknown_opcode_policy: UnknownOpcodePolicy::Trap,
+            variant: CpuVariant::Intel8080,
+            config: None,
+            blocks: BlockCache::new(),
+            trace: None,
+            pins: Pins::new(),
+            z80: Z80::new(),
+        }
+    }
+
+    // Attach the devices that answer IN and OUT, replacing whatever was there before
+    pub fn set_io(&mut self, io: Box<dyn IoBus>) {
+        self.io = io;
+    }
+
+    // Swap in a new address space, nothing cached from the old one is kept. The builder no longer
+    // owns the memory, so power_cycle leaves it alone.
+    pub fn set_memory(&mut self, memory: Box<dyn Memory>) {
+        self.memory = memory;
+        if let Some(ref mut config) = self.config {
+            config.memory = MemorySource::Replaced;
+        }
+        self.invalidate_blocks();
+    }
+
+    // Reads memory without side effects on devices, for debuggers and frontends
+    pub fn peek(&self, address: u16) -> u8 {
+        return self.memory.peek(address);
+    }
+
+    // Writes memory like the cpu would, so any code cached there is dropped
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.write_byte(address, value);
+    }
+
+    // Every instruction run from now on is handed to sink, replacing whatever was there before
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace = Some(sink);
+    }
+
+    // Stops tracing and gives the sink back
+    pub fn take_trace_sink(&mut self) -> Option<Box<dyn TraceSink>> {
+        return self.trace.take();
+    }
+
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) {
+        self.unknown_opcode_policy = policy;
+    }
+
+    pub fn pc(&self) -> u16 {
+        return self.pc;
+    }
+
+    pub fn sp(&self) -> u16 {
+        return self.sp;
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        return self.int_enable && !self.ei_delay;
+    }
+
+    // Raise an interrupt, the device puts opcode on the data bus (almost always RST n) and the cpu
+    // executes it without moving pc. Only that one byte is taken from the bus, operands read as 0.
+    // It is only accepted when interrupts are enabled and it wasn't raised right after EI,
+    // accepting it disables interrupts until the next EI. Returns whether the interrupt was
+    // accepted, a refused one is dropped. A Z80 in interrupt mode 1 or 2 uses opcode differently,
+    // see z80.rs.
+    pub fn interrupt(&mut self, opcode: u8) -> Result<bool, EmulatorError> {
+        if !self.interrupts_enabled() {
+            return Ok(false);
+        }
+        self.int_enable = false;
+        self.halted = false;
+        if self.variant == CpuVariant::Z80 {
+            self.interrupt_z80(opcode)?;
+            return Ok(true);
         }
+        let instruction = decode_for(self.variant, &[opcode], self.pc);
+        self.run(&instruction)?;
+        return Ok(true);
     }
-    fn get_at_pc(&mut self) -> u8 {
-        let value = self.memory[self.pc as usize];
-        self.pc += 1;
-        return value;
-    }
-
-    fn get_double_at_pc(&mut self) -> u16 {
-        let lower = self.get_at_pc();
-        let upper = self.get_at_pc();
-        return combine(upper, lower);
-    }
-
-    fn get_at_m(&mut self) -> u8 {
-        let m = self.m() as usize;
-        return self.memory[m];
-    }
-
-    fn m(&mut self) -> u16 {
-        return combine(self.h, self.l);
-    }
-
-    pub fn emulate_op(&mut self) {
-        let (op, _) = disassemble_op(&self.memory, self.pc as usize);
-//    debug!("{:19} pc: {:4x} sp:{:4x} a:{:2x} b:{:2x} c:{:2x} d:{:2x} e:{:2x} h:{:2x} l:{:2x} {:?}", op, self.pc, self.sp, self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.cc);
-        let z = if self.cc.z {"z"} else {"."};
-        let s = if self.cc.z {"s"} else {"."};
-        let p = if self.cc.z {"p"} else {"."};
-        let cy = if self.cc.z {"cy"} else {"."};
-        let ac = if self.cc.z {"ac"} else {"."};
-        let pad = if self.cc.z {"pad"} else {"."};
-        debug!("{:19} a:{:02x} bc:{:02x}{:02x} de:{:02x}{:02x} hl:{:02x}{:02x} pc:{:04x} sp:{:04x} {}{}{}{}{}{}", op, self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.pc, self.sp, z, s, p, cy, ac, pad);
-
-        let code = self.get_at_pc();
-
-        match code {
-            0x00 => {} // NOP
-            0x01 => { //LXI B, B <- byte 3, C <- byte 2
-                self.c = self.get_at_pc();
-                self.b = self.get_at_pc();
-            }
-            0x02 => { // STAX B
-                let destination = combine(self.b, self.c) as usize;
-                self.memory[destination] = self.a;
-            }
-            0x03 => { inx(&mut self.b, &mut self.c); }
-            0x04 => { inr(&mut self.b, &mut self.cc); }
-            0x05 => { dcr(&mut self.b, &mut self.cc); }
-            0x06 => { self.b = self.get_at_pc(); }
-            0x07 => { rlc(self) }
-            0x08 => {} // NOP
-            0x09 => { dad(combine(self.b, self.c), self); }
-            0x0a => { // LDAX B
-                let target = combine(self.b, self.c) as usize;
-                self.a = self.memory[target];
-            }
-            0x0b => { dcx(&mut self.b, &mut self.c); }
-            0x0c => { inr(&mut self.c, &mut self.cc); }
-            0x0d => { dcr(&mut self.c, &mut self.cc); }
-            0x0e => { self.c = self.get_at_pc();}
-            0x0f => { rrc(self); }
-
-            0x10 => {} // NOP
-            0x11 => {
-                self.e = self.get_at_pc();
-                self.d = self.get_at_pc();
-            }
-            0x12 => {
-                let destination = combine(self.d, self.e) as usize;
-                self.memory[destination] = self.a;
-            }
-            0x13 => { inx(&mut self.d, &mut self.e); }
-            0x14 => { inr(&mut self.d, &mut self.cc); }
-            0x15 => { dcr(&mut self.d, &mut self.cc); }
-            0x16 => { self.d = self.get_at_pc(); }
-            0x17 => { ral(self); }
-            0x18 => {} // NOP
-            0x19 => { dad(combine(self.d, self.e), self); }
-            0x1a => {
-                let target = combine(self.d, self.e) as usize;
-                self.a = self.memory[target];
-            }
-            0x1b => { dcx(&mut self.d, &mut self.e); }
-            0x1c => { inr(&mut self.e, &mut self.cc); }
-            0x1d => { dcr(&mut self.e, &mut self.cc); }
-            0x1e => { self.e = self.get_at_pc(); }
-            0x1f => { rar(self); }
-
-            0x20 => {} //NOP
-            0x21 => { // LXI H
-                self.l = self.get_at_pc();
-                self.h = self.get_at_pc();
-            }
-            0x22 => { // SHLD Store H and L Direct
-                let address = self.get_double_at_pc() as usize;
-                self.memory[address] = self.l;
-                self.memory[address + 1] = self.h;
-            }
-            0x23 => { inx(&mut self.h, &mut self.l); }
-            0x24 => { inr(&mut self.h, &mut self.cc); }
-            0x25 => { dcr(&mut self.h, &mut self.cc); }
-            0x26 => { self.h = self.get_at_pc(); }
-            // 0x27 DAA
-            0x28 => {} // NOP
-            0x29 => {
-                let num = combine(self.h, self.l);
-                dad(num, self);
-            }
-            0x2a => {
-                let address = self.get_double_at_pc() as usize;
-                self.l = self.memory[address];
-                self.h = self.memory[address + 1];
-            }
-            0x2b => { dcx(&mut self.h, &mut self.l); }
-            0x2c => { inr(&mut self.l, &mut self.cc); }
-            0x2d => { dcr(&mut self.l, &mut self.cc); }
-            0x2e => { self.l = self.get_at_pc(); }
-            0x2f => { self.a = !self.a; }
-
-            0x30 => {} // NOP
-            0x31 => { self.sp = self.get_double_at_pc(); }
-            0x32 => {
-                let m = self.m() as usize;
-                self.memory[m] = self.a;
-            }
-            0x33 => { self.sp += 1; }
-            0x34 => {
-                let m = self.m() as usize;
-                inr(&mut self.memory[m], &mut self.cc);
-            }
-            0x35 => {
-                let m = self.m() as usize;
-                dcr(&mut self.memory[m], &mut self.cc);
-            }
-            0x36 => {
-                let m = self.m() as usize;
-                self.memory[m] = self.get_at_pc(); }
-            0x37 => { self.cc.cy = true; }
-            0x38 => {} // NOP
-            0x39 => { dad(self.sp, self); }
-            0x3a => {
-                let address = self.get_double_at_pc() as usize;
-                self.a = self.memory[address];
-            }
-            0x3b => {
-                let (mut upper, mut lower) = split(self.sp);
-                dcx(&mut upper, &mut lower);
-                self.sp = combine(upper, lower);
-            }
-            0x3c => { inr(&mut self.a, &mut self.cc); }
-            0x3d => { dcr(&mut self.a, &mut self.cc); }
-            0x3e => { self.a = self.get_at_pc(); }
-            0x3f => { self.cc.cy = !self.cc.cy; }
-
-            0x40 => { self.b = self.b; }
-            0x41 => { self.b = self.c; }
-            0x42 => { self.b = self.d; }
-            0x43 => { self.b = self.e; }
-            0x44 => { self.b = self.h; }
-            0x45 => { self.b = self.l; }
-            0x46 => { self.b = self.get_at_m(); }
-            0x47 => { self.b = self.a; }
-            0x48 => { self.c = self.b; }
-            0x49 => { self.c = self.c; }
-            0x4a => { self.c = self.d; }
-            0x4b => { self.c = self.e; }
-            0x4c => { self.c = self.h; }
-            0x4d => { self.c = self.l; }
-            0x4e => { self.c = self.get_at_m(); }
-            0x4f => { self.c = self.a; }
-
-            0x50 => { self.d = self.b; }
-            0x51 => { self.d = self.c; }
-            0x52 => { self.d = self.d; }
-            0x53 => { self.d = self.e; }
-            0x54 => { self.d = self.h; }
-            0x55 => { self.d = self.l; }
-            0x56 => { self.d = self.get_at_m(); }
-            0x57 => { self.d = self.a; }
-            0x58 => { self.e = self.b; }
-            0x59 => { self.e = self.c; }
-            0x5a => { self.e = self.d; }
-            0x5b => { self.e = self.e; }
-            0x5c => { self.e = self.h; }
-            0x5d => { self.e = self.l; }
-            0x5e => { self.e = self.get_at_m(); }
-            0x5f => { self.e = self.a; }
-
-            0x60 => { self.h = self.b; }
-            0x61 => { self.h = self.c; }
-            0x62 => { self.h = self.d; }
-            0x63 => { self.h = self.e; }
-            0x64 => { self.h = self.h; }
-            0x65 => { self.h = self.l; }
-            0x66 => { self.h = self.get_at_m(); }
-            0x67 => { self.h = self.a; }
-            0x68 => { self.l = self.b; }
-            0x69 => { self.l = self.c; }
-            0x6a => { self.l = self.d; }
-            0x6b => { self.l = self.e; }
-            0x6c => { self.l = self.h; }
-            0x6d => { self.l = self.l; }
-            0x6e => { self.l = self.get_at_m(); }
-            0x6f => { self.l = self.a; }
-
-            0x70 => { self.memory[combine(self.h, self.l) as usize] = self.b; }
-            0x71 => { self.memory[combine(self.h, self.l) as usize] = self.c; }
-            0x72 => { self.memory[combine(self.h, self.l) as usize] = self.d; }
-            0x73 => { self.memory[combine(self.h, self.l) as usize] = self.e; }
-            0x74 => { self.memory[combine(self.h, self.l) as usize] = self.h; }
-            0x76 => { self.memory[combine(self.h, self.l) as usize] = self.l; }
-            0x76 => {} // HLT
-            0x77 => { self.memory[combine(self.h, self.l) as usize] = self.b; }
-            0x78 => { self.a = self.b; }
-            0x79 => { self.a = self.c; }
-            0x7a => { self.a = self.d; }
-            0x7b => { self.a = self.e; }
-            0x7c => { self.a = self.h; }
-            0x7d => { self.a = self.l; }
-            0x7e => { self.a = self.get_at_m(); }
-            0x7f => { self.a = self.a; }
-
-            0x80 => { add(self.b, self); }
-            0x81 => { add(self.c, self); }
-            0x82 => { add(self.d, self); }
-            0x83 => { add(self.e, self); }
-            0x84 => { add(self.h, self); }
-            0x85 => { add(self.l, self); }
-            0x85 => { add(self.l, self); }
-            0x86 => { // ADD M
-                let val = self.get_at_m();
-                add(val, self);
-            }
-            0x87 => { adc(self.a, self); }
-            0x88 => { adc(self.b, self); }
-            0x89 => { adc(self.c, self); }
-            0x8a => { adc(self.d, self); }
-            0x8b => { adc(self.e, self); }
-            0x8c => { adc(self.h, self); }
-            0x8d => { adc(self.l, self); }
-            0x8e => { //ADC M
-                let address = combine(self.h, self.l) as usize;
-                let val = self.memory[address];
-                add(val, self);
-            }
-            0x8f => { adc(self.a, self); }
-
-            0xc2 => { conditional_jmp(!self.cc.z, self); }
-            0xc3 => { jmp(self); }
-            0xca => { conditional_jmp(self.cc.z, self); }
-            0xcd => { call(self); }
-            0xc9 => { ret(self); }
-
-            0xd2 => { conditional_jmp(!self.cc.cy, self); }
-            0xda => { conditional_jmp(self.cc.cy, self); }
-
-            0xe2 => { conditional_jmp(!self.cc.p, self); }
-            0xea => { conditional_jmp(self.cc.p, self); }
-
-            _ => { error!("Skipped {:2x}", code); }
+
+    // Total T-states since power on, at 2 MHz that is 2,000,000 a second
+    pub fn cycles(&self) -> u64 {
+        return self.cycles;
+    }
+
+    pub fn is_halted(&self) -> bool {
+        return self.halted;
+    }
+
+    // Halted with interrupts off, nothing can ever wake the cpu up so the host should stop running.
+    // An 8085 can always be woken by TRAP and a Z80 by NMI so they never stop.
+    pub fn is_stopped(&self) -> bool {
+        return self.halted && !self.int_enable && self.variant == CpuVariant::Intel8080;
+    }
+
+    // Reads the instruction at address, on the Z80 that takes the byte after a prefix to know how
+    // long it is. Bytes past its end are 0.
+    fn fetch(&mut self, address: u16) -> [u8; 4] {
+        let mut bytes = [0; 4];
+        bytes[0] = self.memory.read(address);
+        let mut next = 1;
+        if is_prefix(self.variant, bytes[0]) {
+            bytes[1] = self.memory.read(address.wrapping_add(1));
+            next = 2;
+        }
+        for i in next..length_for(self.variant, bytes[0], bytes[1]) {
+            bytes[i as usize] = self.memory.read(address.wrapping_add(i));
+        }
+        return bytes;
+    }
+
+    // Every write the cpu makes goes through here so cached blocks never run stale code
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory.write(address, value);
+        self.blocks.invalidate(address);
+    }
+
+    // Where an operand that isn't a register is in memory, M is at HL
+    fn operand_address(&self, operand: Operand) -> u16 {
+        return match operand {
+            Operand::IX(offset) => self.z80.ix.wrapping_add(offset as u16),
+            Operand::IY(offset) => self.z80.iy.wrapping_add(offset as u16),
+            _ => self.pair(RegPair::HL),
+        };
+    }
+
+    fn get_operand(&mut self, operand: Operand) -> u8 {
+        return match operand.reg() {
+            Some(reg) => self.reg(reg),
+            None => self.memory.read(self.operand_address(operand)),
+        };
+    }
+
+    fn set_operand(&mut self, operand: Operand, value: u8) {
+        match operand.reg() {
+            Some(reg) => self.set_reg(reg, value),
+            None => self.write_byte(self.operand_address(operand), value),
+        }
+    }
+
+    fn condition(&self, condition: Condition) -> bool {
+        return match condition {
+            Condition::NZ => !self.cc.z,
+            Condition::Z => self.cc.z,
+            Condition::NC => !self.cc.cy,
+            Condition::C => self.cc.cy,
+            Condition::PO => !self.cc.p,
+            Condition::PE => self.cc.p,
+            Condition::P => !self.cc.s,
+            Condition::M => self.cc.s,
+        };
+    }
+
+    fn alu(&mut self, op: AluOp, value: u8) {
+        match op {
+            AluOp::Add => add(value, self),
+            AluOp::Adc => adc(value, self),
+            AluOp::Sub => sub(value, self),
+            AluOp::Sbb => sbb(value, self),
+            AluOp::Ana => ana(value, self),
+            AluOp::Xra => xra(value, self),
+            AluOp::Ora => ora(value, self),
+            AluOp::Cmp => cmp(value, self),
+        }
+    }
+
+    // Runs one instruction and returns how many T-states it took
+    pub fn emulate_op(&mut self) -> Result<u32, EmulatorError> {
+        if self.variant == CpuVariant::Intel8085 {
+            if let Some(cycles) = self.service_pins()? {
+                return Ok(cycles);
+            }
+        }
+        if self.halted {
+            if self.is_stopped() {
+                let address = self.pc.wrapping_sub(1);
+                return Err(EmulatorError::HaltedWithInterruptsDisabled { address });
+            }
+            // Nothing is fetched, the cpu just idles until an interrupt comes in
+            self.ei_delay = false;
+            self.cycles += HALTED_CYCLES as u64;
+            return Ok(HALTED_CYCLES);
+        }
+
+        // The instruction after EI has now run, so interrupts are really on
+        self.ei_delay = false;
+        let address = self.pc;
+        let bytes = self.fetch(address);
+        self.check_bus()?;
+        let instruction = decode_for(self.variant, &bytes, address);
+        self.pc = address.wrapping_add(instruction.length);
+        return self.run(&instruction);
+    }
+
+    // Executes an instruction that has already been fetched and keeps count of the cycles
+    fn run(&mut self, instruction: &Instruction) -> Result<u32, EmulatorError> {
+        return self.run_costing(instruction, None);
+    }
+
+    // Like run, cycles replaces what the instruction normally takes when it stands in for
+    // something else, like an 8085 pin interrupt
+    pub(crate) fn run_costing(
+        &mut self,
+        instruction: &Instruction,
+        cycles: Option<u32>,
+    ) -> Result<u32, EmulatorError> {
+        if self.trace.is_some() {
+            return self.run_traced(instruction, cycles);
+        }
+        let executed = self.execute(instruction, instruction.cycles())?;
+        let cycles = cycles.unwrap_or(executed);
+        self.check_bus()?;
+        self.cycles += cycles as u64;
+        return Ok(cycles);
+    }
+
+    fn run_traced(
+        &mut self,
+        instruction: &Instruction,
+        cycles: Option<u32>,
+    ) -> Result<u32, EmulatorError> {
+        let mut before = self.registers();
+        before.pc = instruction.address;
+        let executed = self.execute(instruction, instruction.cycles())?;
+        let cycles = cycles.unwrap_or(executed);
+        self.check_bus()?;
+        self.cycles += cycles as u64;
+        let record = TraceRecord { instruction: *instruction, before, after: self.registers(), cycles };
+        if let Some(ref mut sink) = self.trace {
+            sink.record(&record);
+        }
+        return Ok(cycles);
+    }
+
+    fn check_bus(&mut self) -> Result<(), EmulatorError> {
+        return match self.memory.take_fault() {
+            Some(address) => Err(EmulatorError::BusFault { address }),
+            None => Ok(()),
+        };
+    }
+
+    fn unknown_opcode(&mut self, code: u8, address: u16) -> Result<(), EmulatorError> {
+        match self.unknown_opcode_policy {
+            UnknownOpcodePolicy::Trap => {
+                return Err(EmulatorError::UnimplementedOpcode { opcode: code, address });
+            }
+            UnknownOpcodePolicy::Nop => {
+                warn!("Skipped {:02x} at {:04x}", code, address);
+                return Ok(());
+            }
+            UnknownOpcodePolicy::Callback(ref mut callback) => {
+                return callback(code, address);
+            }
+        }
+    }
+
+    // Runs a decoded instruction, pc already points past it. cycles is what it takes when no
+    // branch is taken, blocks look that up once when they are decoded. Returns how many T-states
+    // it took.
+    fn execute(
+        &mut self,
+        instruction: &Instruction,
+        mut cycles: u32,
+    ) -> Result<u32, EmulatorError> {
+        if self.variant == CpuVariant::Z80 {
+            self.refresh(instruction);
+        }
+        match instruction.op {
+            Op::Nop => {}
+            Op::Lxi(pair, value) => { self.set_pair(pair, value); }
+            Op::Stax(pair) => {
+                let destination = self.pair(pair);
+                self.write_byte(destination, self.a);
+            }
+            Op::Ldax(pair) => {
+                let target = self.pair(pair);
+                self.a = self.memory.read(target);
+            }
+            Op::Shld(address) => { // Store H and L Direct
+                self.write_byte(address, self.l);
+                self.write_byte(address.wrapping_add(1), self.h);
+            }
+            Op::Lhld(address) => {
+                self.l = self.memory.read(address);
+                self.h = self.memory.read(address.wrapping_add(1));
+            }
+            Op::Sta(address) => { self.write_byte(address, self.a); }
+            Op::Lda(address) => { self.a = self.memory.read(address); }
+            Op::Inx(RegPair::BC) => { inx(&mut self.b, &mut self.c); }
+            Op::Inx(RegPair::DE) => { inx(&mut self.d, &mut self.e); }
+            Op::Inx(RegPair::HL) => { inx(&mut self.h, &mut self.l); }
+            Op::Inx(pair) => { self.set_pair(pair, self.pair(pair).wrapping_add(1)); }
+            Op::Dcx(RegPair::BC) => { dcx(&mut self.b, &mut self.c); }
+            Op::Dcx(RegPair::DE) => { dcx(&mut self.d, &mut self.e); }
+            Op::Dcx(RegPair::HL) => { dcx(&mut self.h, &mut self.l); }
+            Op::Dcx(pair) => { self.set_pair(pair, self.pair(pair).wrapping_sub(1)); }
+            Op::Dad(pair) => { dad(self.pair(pair), self); }
+            Op::Inr(operand) => {
+                let mut value = self.get_operand(operand);
+                if self.variant == CpuVariant::Z80 {
+                    inr_z80(&mut value, &mut self.cc);
+                } else {
+                    inr(&mut value, &mut self.cc);
+                }
+                self.set_operand(operand, value);
+            }
+            Op::Dcr(operand) => {
+                let mut value = self.get_operand(operand);
+                if self.variant == CpuVariant::Z80 {
+                    dcr_z80(&mut value, &mut self.cc);
+                } else {
+                    dcr(&mut value, &mut self.cc);
+                }
+                self.set_operand(operand, value);
+            }
+            Op::Mvi(operand, value) => { self.set_operand(operand, value); }
+            Op::Rlc => { rlc(self); }
+            Op::Rrc => { rrc(self); }
+            Op::Ral => { ral(self); }
+            Op::Rar => { rar(self); }
+            Op::Daa => { daa(self); }
+            Op::Cma => {
+                self.a = !self.a;
+                if self.variant == CpuVariant::Z80 {
+                    self.cc.ac = true;
+                    self.cc.n = true;
+                }
+            }
+            Op::Stc => {
+                self.cc.cy = true;
+                if self.variant == CpuVariant::Z80 {
+                    self.cc.ac = false;
+                    self.cc.n = false;
+                }
+            }
+            Op::Cmc => {
+                // The Z80 leaves the old carry in h
+                if self.variant == CpuVariant::Z80 {
+                    self.cc.ac = self.cc.cy;
+                    self.cc.n = false;
+                }
+                self.cc.cy = !self.cc.cy;
+            }
+            Op::Mov(destination, source) => {
+                let value = self.get_operand(source);
+                self.set_operand(destination, value);
+            }
+            Op::Hlt => { self.halted = true; }
+            Op::Alu(op, operand) => {
+                let value = self.get_operand(operand);
+                self.alu(op, value);
+            }
+            Op::AluImmediate(op, value) => { self.alu(op, value); }
+            Op::Jmp(address) => { jmp(address, self); }
+            Op::Jcc(condition, address) => {
+                if conditional_jmp(self.condition(condition), address, self) {
+                    cycles = instruction.cycles_taken();
+                }
+            }
+            Op::Call(address) => { call(address, self); }
+            Op::Ccc(condition, address) => {
+                if conditional_call(self.condition(condition), address, self) {
+                    cycles = instruction.cycles_taken();
+                }
+            }
+            Op::Ret => { ret(self); }
+            Op::Rcc(condition) => {
+                if conditional_ret(self.condition(condition), self) {
+                    cycles = instruction.cycles_taken();
+                }
+            }
+            Op::Rst(n) => { rst(n, self); }
+            Op::Push(pair) => { push(self.pair(pair), self); }
+            Op::Pop(pair) => {
+                let value = pop(self);
+                self.set_pair(pair, value);
+            }
+            Op::Xthl => { xthl(self); }
+            Op::Pchl => { self.pc = self.pair(RegPair::HL); }
+            Op::Sphl => { self.sp = self.pair(RegPair::HL); }
+            Op::Xchg => {
+                std::mem::swap(&mut self.h, &mut self.d);
+                std::mem::swap(&mut self.l, &mut self.e);
+            }
+            Op::Out(port) => { self.io.output(port, self.a); }
+            Op::In(port) => { self.a = self.io.input(port); }
+            Op::Ei => {
+                self.int_enable = true;
+                self.ei_delay = true;
+                self.z80.iff2 = true;
+            }
+            Op::Di => {
+                self.int_enable = false;
+                self.ei_delay = false;
+                self.z80.iff2 = false;
+            }
+            Op::Rim => { self.a = self.rim(); }
+            Op::Sim => { self.sim(self.a); }
+            Op::ExAf => { self.exchange_af(); }
+            Op::Exx => { self.exx(); }
+            Op::Djnz(address) => {
+                self.b = self.b.wrapping_sub(1);
+                if conditional_jmp(self.b != 0, address, self) {
+                    cycles = instruction.cycles_taken();
+                }
+            }
+            Op::Jr(address) => { jmp(address, self); }
+            Op::Jrcc(condition, address) => {
+                if conditional_jmp(self.condition(condition), address, self) {
+                    cycles = instruction.cycles_taken();
+                }
+            }
+            Op::Rot(op, operand) => {
+                let value = self.get_operand(operand);
+                let value = rotate(op, value, self);
+                self.set_operand(operand, value);
+            }
+            Op::Bit(n, operand) => {
+                let value = self.get_operand(operand);
+                bit(n, value, self);
+            }
+            Op::Res(n, operand) => {
+                let value = self.get_operand(operand);
+                self.set_operand(operand, value & !(1 << n));
+            }
+            Op::Set(n, operand) => {
+                let value = self.get_operand(operand);
+                self.set_operand(operand, value | (1 << n));
+            }
+            Op::InC(operand) => {
+                let value = self.io.input(self.c);
+                self.set_operand(operand, value);
+                update_logical_flags(value, self);
+            }
+            Op::OutC(operand) => {
+                let value = self.get_operand(operand);
+                self.io.output(self.c, value);
+            }
+            Op::AdcHl(pair) => { adc16(self.pair(pair), self); }
+            Op::SbcHl(pair) => { sbc16(self.pair(pair), self); }
+            Op::Store16(pair, address) => {
+                let [upper, lower] = self.pair(pair).to_be_bytes();
+                self.write_byte(address, lower);
+                self.write_byte(address.wrapping_add(1), upper);
+            }
+            Op::Load16(pair, address) => {
+                let lower = self.memory.read(address);
+                let upper = self.memory.read(address.wrapping_add(1));
+                self.set_pair(pair, u16::from_be_bytes([upper, lower]));
+            }
+            Op::Neg => { neg(self); }
+            Op::Retn | Op::Reti => {
+                ret(self);
+                self.int_enable = self.z80.iff2;
+            }
+            Op::Im(mode) => { self.set_interrupt_mode(mode); }
+            Op::LdIA => { self.z80.i = self.a; }
+            Op::LdRA => { self.z80.r = self.a; }
+            Op::LdAI => { self.load_a_special(self.z80.i); }
+            Op::LdAR => { self.load_a_special(self.z80.r); }
+            Op::Rrd => { self.rotate_digits(false); }
+            Op::Rld => { self.rotate_digits(true); }
+            Op::Block(op) => {
+                if self.block(op) {
+                    cycles = instruction.cycles_taken();
+                }
+            }
+            Op::AddIndex(index, pair) => { add_pair(index, self.pair(pair), self); }
+            Op::ExSp(pair) => { exchange_top(pair, self); }
+            Op::JpIndirect(pair) => { self.pc = self.pair(pair); }
+            Op::LdSp(pair) => { self.sp = self.pair(pair); }
+            Op::Unknown(code) => { self.unknown_opcode(code, instruction.address)?; }
         }
+        return Ok(cycles);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::emulator::config::CpuVariant;
+    use crate::emulator::error::EmulatorError;
+    use crate::emulator::error::UnknownOpcodePolicy;
     use crate::emulator::test_utils::*;
+    use crate::emulator::ConditionCodes;
+    use crate::emulator::State8080;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_shld() {
         let mut state = setup_state();
-        state.memory[0] = 0x22; // SHLD op code
-        state.memory[1] = 0x11; // Lower part of address
-        state.memory[2] = 0x33; // Upper part of address
+        state.memory.write(0, 0x22); // SHLD op code
+        state.memory.write(1, 0x11); // Lower part of address
+        state.memory.write(2, 0x33); // Upper part of address
         state.h = 0xaa;
         state.l = 0xbb;
-        state.emulate_op();
-        assert_eq!(state.memory[0x3311], 0xbb);
-        assert_eq!(state.memory[0x3312], 0xaa);
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x3311), 0xbb);
+        assert_eq!(state.memory.read(0x3312), 0xaa);
     }
 
     #[test]
     fn test_cma() {
         let mut state = setup_state();
-        state.memory[0] = 0x2f; // CMA op code
+        state.memory.write(0, 0x2f); // CMA op code
         state.a = 0b01010001;
-        state.emulate_op();
+        state.emulate_op().unwrap();
         assert_eq!(state.a, 0b10101110);
     }
 
     #[test]
     fn test_dcx_sp() {
         let mut state = setup_state();
-        state.memory[0] = 0x3b; // DCX SP op code
+        state.memory.write(0, 0x3b); // DCX SP op code
         state.sp = 0xabcd;
-        state.emulate_op();
+        state.emulate_op().unwrap();
         assert_eq!(state.sp, 0xabcc);
     }
 
     #[test]
     fn test_move() {
         let mut state = setup_state();
-        state.memory[0] = 0x41; // MOV B, C op code
-        state.memory[1] = 0x4a; // MOV C, D op code
+        state.memory.write(0, 0x41); // MOV B, C op code
+        state.memory.write(1, 0x4a); // MOV C, D op code
         state.b = 0x0b;
         state.c = 0x0c;
         state.d = 0x0d;
-        state.emulate_op();
+        state.emulate_op().unwrap();
         assert_eq!(state.b, 0xc);
         assert_eq!(state.c, 0xc);
-        state.emulate_op();
+        state.emulate_op().unwrap();
         assert_eq!(state.c, 0xd);
         assert_eq!(state.d, 0xd);
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_psw_packing() {
+        let codes = ConditionCodes::from_psw(0xff);
+        assert_eq!(codes.s, true);
+        assert_eq!(codes.z, true);
+        assert_eq!(codes.ac, true);
+        assert_eq!(codes.p, true);
+        assert_eq!(codes.cy, true);
+        // bits 3 and 5 are forced low and bit 1 high
+        assert_eq!(codes.to_psw(), 0b11010111);
+
+        let codes = ConditionCodes::from_psw(0);
+        assert_eq!(codes.to_psw(), 0b00000010);
+
+        let codes = ConditionCodes::from_psw(0b01000101);
+        assert_eq!(codes.s, false);
+        assert_eq!(codes.z, true);
+        assert_eq!(codes.ac, false);
+        assert_eq!(codes.p, true);
+        assert_eq!(codes.cy, true);
+        assert_eq!(codes.to_psw(), 0b01000111);
+    }
+
+    #[test]
+    fn test_psw_round_trip() {
+        for psw in 0..=0xffu8 {
+            let packed = ConditionCodes::from_psw(psw).to_psw();
+            assert_eq!(packed, (psw & 0b11010101) | 0b00000010);
+            assert_eq!(ConditionCodes::from_psw(packed).to_psw(), packed);
+        }
+    }
+
+    #[test]
+    fn test_flag_string() {
+        assert_eq!(format!("{}", ConditionCodes::from_psw(0)), ".....");
+        assert_eq!(format!("{}", ConditionCodes::from_psw(0xff)), "szapc");
+        assert_eq!(format!("{}", ConditionCodes::from_psw(0b01000001)), ".z..c");
+    }
+
+    #[test]
+    fn test_interrupt_refused_when_disabled() {
+        let mut state = setup_state();
+        state.pc = 0x1234;
+
+        assert_eq!(state.interrupt(0xcf), Ok(false)); // RST 1
+
+        assert_eq!(state.pc, 0x1234);
+        assert_eq!(state.sp, 100);
+    }
+
+    #[test]
+    fn test_interrupt_waits_for_instruction_after_ei() {
+        let mut state = setup_state();
+        state.pc = 0x1234;
+        state.memory.write(0x1234, 0xfb); // EI op code
+        state.memory.write(0x1235, 0x00); // NOP op code
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.int_enable, true);
+        assert_eq!(state.interrupt(0xd7), Ok(false)); // RST 2
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.interrupt(0xd7), Ok(true)); // RST 2
+
+        assert_eq!(state.pc, 0x0010);
+        assert_eq!(state.sp, 98);
+        assert_eq!(state.memory.read(99), 0x12);
+        assert_eq!(state.memory.read(98), 0x36);
+        // taking the interrupt turns them off until the next EI
+        assert_eq!(state.int_enable, false);
+        assert_eq!(state.interrupt(0xd7), Ok(false));
+    }
+
+    #[test]
+    fn test_di() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xfb); // EI op code
+        state.memory.write(1, 0xf3); // DI op code
+        state.memory.write(2, 0x00); // NOP op code
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.int_enable, false);
+        assert_eq!(state.interrupt(0xcf), Ok(false));
+        assert_eq!(state.pc, 3);
+    }
+
+    #[test]
+    fn test_mov_m() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x75); // MOV M, L op code
+        state.memory.write(1, 0x77); // MOV M, A op code
+        state.h = 0x20;
+        state.l = 0x10;
+        state.a = 0xaa;
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x2010), 0x10);
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x2010), 0xaa);
+    }
+
+    #[test]
+    fn test_hlt() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x76); // HLT op code
+        state.memory.write(1, 0x3c); // INR A op code
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.is_halted(), true);
+        assert_eq!(state.is_stopped(), true);
+        assert_eq!(state.pc, 1);
+
+        // stepping doesn't fetch anything, and with interrupts off it never will
+        let halted = Err(EmulatorError::HaltedWithInterruptsDisabled { address: 0 });
+        assert_eq!(state.emulate_op(), halted);
+        assert_eq!(state.emulate_op(), halted);
+        assert_eq!(state.pc, 1);
+        assert_eq!(state.a, 0);
+    }
+
+    #[test]
+    fn test_interrupt_resumes_hlt() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xfb); // EI op code
+        state.memory.write(1, 0x76); // HLT op code
+        state.memory.write(0x08, 0x3c); // INR A op code
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.is_halted(), true);
+        assert_eq!(state.is_stopped(), false);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.interrupt(0xcf), Ok(true)); // RST 1
+        assert_eq!(state.is_halted(), false);
+        assert_eq!(state.pc, 0x08);
+        // the return address is the instruction after HLT
+        assert_eq!(state.memory.read(98), 0x02);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 1);
+    }
+
+    #[test]
+    fn test_cycles() {
+        // (op code, T-states)
+        let cases: [(u8, u32); 8] = [
+            (0x00, 4),  // NOP
+            (0x01, 10), // LXI B
+            (0x22, 16), // SHLD
+            (0x41, 5),  // MOV B, C
+            (0x46, 7),  // MOV B, M
+            (0xc3, 10), // JMP
+            (0xcd, 17), // CALL
+            (0xe3, 18), // XTHL
+        ];
+        for &(code, expected) in cases.iter() {
+            let mut state = setup_state();
+            state.memory.write(0, code);
+            assert_eq!(state.emulate_op(), Ok(expected), "op {:02x}", code);
+            assert_eq!(state.cycles(), expected as u64, "op {:02x}", code);
+        }
+    }
+
+    #[test]
+    fn test_conditional_cycles() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xc4); // CNZ op code
+        state.memory.write(1, 0x00);
+        state.memory.write(2, 0x10);
+        state.memory.write(3, 0xc4); // CNZ op code
+        state.memory.write(4, 0x00);
+        state.memory.write(5, 0x10);
+        state.memory.write(0x1000, 0xc0); // RNZ op code
+        state.memory.write(0x1001, 0xc0); // RNZ op code
+
+        state.cc.z = true;
+        assert_eq!(state.emulate_op(), Ok(11));
+        state.cc.z = false;
+        assert_eq!(state.emulate_op(), Ok(17));
+        state.cc.z = true;
+        assert_eq!(state.emulate_op(), Ok(5));
+        state.cc.z = false;
+        assert_eq!(state.emulate_op(), Ok(11));
+        assert_eq!(state.pc, 6);
+        assert_eq!(state.cycles(), 11 + 17 + 5 + 11);
+    }
+
+    #[test]
+    fn test_halted_cycles() {
+        let mut state = setup_state();
+        state.memory.write(0, 0xfb); // EI op code
+        state.memory.write(1, 0x76); // HLT op code
+
+        assert_eq!(state.emulate_op(), Ok(4));
+        assert_eq!(state.emulate_op(), Ok(7));
+        assert_eq!(state.emulate_op(), Ok(4));
+        assert_eq!(state.emulate_op(), Ok(4));
+        assert_eq!(state.cycles(), 19);
+    }
+
+    #[test]
+    fn test_unimplemented_opcode_traps() {
+        // every 8080 op code does something, the 8085's undocumented ones aren't emulated
+        let mut state = setup_state();
+        state.variant = CpuVariant::Intel8085;
+        state.pc = 0x0100;
+        state.memory.write(0x0100, 0xcb); // not implemented
+
+        let error = EmulatorError::UnimplementedOpcode { opcode: 0xcb, address: 0x0100 };
+        assert_eq!(state.emulate_op(), Err(error));
+        assert_eq!(state.cycles(), 0);
+    }
+
+    #[test]
+    fn test_unimplemented_opcode_as_nop() {
+        let mut state = setup_state();
+        state.variant = CpuVariant::Intel8085;
+        state.set_unknown_opcode_policy(UnknownOpcodePolicy::Nop);
+        state.memory.write(0, 0xcb); // not implemented
+        state.memory.write(1, 0x3c); // INR A op code
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.a, 1);
+    }
+
+    #[test]
+    fn test_unimplemented_opcode_callback() {
+        let seen = Rc::new(RefCell::new(vec![]));
+        let seen_by_callback = seen.clone();
+        let mut state = setup_state();
+        state.variant = CpuVariant::Intel8085;
+        state.set_unknown_opcode_policy(UnknownOpcodePolicy::Callback(Box::new(
+            move |opcode, address| {
+                seen_by_callback.borrow_mut().push((opcode, address));
+                if opcode == 0xcb {
+                    return Ok(());
+                }
+                return Err(EmulatorError::UnimplementedOpcode { opcode, address });
+            },
+        )));
+        state.memory.write(0, 0xcb); // not implemented
+        state.memory.write(1, 0xd9); // not implemented
+
+        assert_eq!(state.emulate_op().is_ok(), true);
+        assert_eq!(state.emulate_op().is_err(), true);
+        assert_eq!(*seen.borrow(), vec![(0xcb, 0), (0xd9, 1)]);
+    }
+
+    #[test]
+    fn test_undocumented_aliases() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x08); // NOP alias
+        state.memory.write(1, 0xdd); // CALL alias, to 0x1000
+        state.memory.write(2, 0x00);
+        state.memory.write(3, 0x10);
+        state.memory.write(0x1000, 0xd9); // RET alias
+        state.memory.write(4, 0xcb); // JMP alias, to 0x2000
+        state.memory.write(5, 0x00);
+        state.memory.write(6, 0x20);
+
+        assert_eq!(state.emulate_op(), Ok(4));
+        assert_eq!(state.emulate_op(), Ok(17));
+        assert_eq!(state.pc, 0x1000);
+        assert_eq!(state.emulate_op(), Ok(10));
+        assert_eq!(state.pc, 4);
+        assert_eq!(state.emulate_op(), Ok(10));
+        assert_eq!(state.pc, 0x2000);
+        assert_eq!(state.sp, 100);
+    }
+
+    #[test]
+    fn test_bus_fault_past_end_of_program() {
+        let mut state = State8080::new(vec![0x00, 0x00]); // NOP, NOP
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.emulate_op(), Err(EmulatorError::BusFault { address: 2 }));
+    }
+
+    #[test]
+    fn test_bus_fault_on_write() {
+        // SHLD $ffff runs off the end of a short buffer
+        let mut state = State8080::new(vec![0x22, 0xff, 0xff]);
+
+        assert_eq!(state.emulate_op(), Err(EmulatorError::BusFault { address: 0xffff }));
+    }
+
+    #[test]
+    fn test_shld_wraps_around() {
+        let mut state = setup_state();
+        state.memory.write(0, 0x22); // SHLD op code
+        state.memory.write(1, 0xff); // Lower part of address
+        state.memory.write(2, 0xff); // Upper part of address
+        state.h = 0xaa;
+        state.l = 0xbb;
+
+        state.emulate_op().unwrap();
+
+        assert_eq!(state.memory.read(0xffff), 0xbb);
+        assert_eq!(state.memory.read(0x0000), 0xaa);
+    }
+}
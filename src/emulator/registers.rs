@@ -0,0 +1,337 @@
+use crate::emulator::config::CpuVariant;
+use crate::emulator::z80::Z80Registers;
+use crate::emulator::ConditionCodes;
+use crate::emulator::State8080;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+// The 16 bit registers. BC, DE and HL are the first register in the upper byte and the second in
+// the lower, PSW is a in the upper byte and the packed flags in the lower. The Z80 calls PSW AF
+// and adds the IX and IY index registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegPair {
+    BC,
+    DE,
+    HL,
+    SP,
+    PSW,
+    IX,
+    IY,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Z,
+    S,
+    P, // Parity, the Z80 also uses it for overflow
+    CY,
+    AC, // The Z80's half carry
+    N,  // Z80 only, set by subtractions for DAA
+}
+
+// A copy of the whole register file, flags are stored packed like in the PSW. z80 is only there
+// on a Z80.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub flags: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub z80: Option<Z80Registers>,
+}
+
+impl fmt::Display for Registers {
+    // The layout the trace has always used, pairs are shown as one 16 bit value
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(
+            f,
+            "a:{:02x} bc:{:02x}{:02x} de:{:02x}{:02x} hl:{:02x}{:02x} pc:{:04x} sp:{:04x} {}",
+            self.a,
+            self.b,
+            self.c,
+            self.d,
+            self.e,
+            self.h,
+            self.l,
+            self.pc,
+            self.sp,
+            ConditionCodes::from_psw(self.flags)
+        );
+    }
+}
+
+impl ConditionCodes {
+    pub fn get(&self, flag: Flag) -> bool {
+        return match flag {
+            Flag::Z => self.z,
+            Flag::S => self.s,
+            Flag::P => self.p,
+            Flag::CY => self.cy,
+            Flag::AC => self.ac,
+            Flag::N => self.n,
+        };
+    }
+
+    pub fn set(&mut self, flag: Flag, value: bool) {
+        match flag {
+            Flag::Z => self.z = value,
+            Flag::S => self.s = value,
+            Flag::P => self.p = value,
+            Flag::CY => self.cy = value,
+            Flag::AC => self.ac = value,
+            Flag::N => self.n = value,
+        }
+    }
+}
+
+impl State8080 {
+    pub fn reg(&self, reg: Reg) -> u8 {
+        return match reg {
+            Reg::A => self.a,
+            Reg::B => self.b,
+            Reg::C => self.c,
+            Reg::D => self.d,
+            Reg::E => self.e,
+            Reg::H => self.h,
+            Reg::L => self.l,
+        };
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, value: u8) {
+        match reg {
+            Reg::A => self.a = value,
+            Reg::B => self.b = value,
+            Reg::C => self.c = value,
+            Reg::D => self.d = value,
+            Reg::E => self.e = value,
+            Reg::H => self.h = value,
+            Reg::L => self.l = value,
+        }
+    }
+
+    pub fn pair(&self, pair: RegPair) -> u16 {
+        return match pair {
+            RegPair::BC => u16::from_be_bytes([self.b, self.c]),
+            RegPair::DE => u16::from_be_bytes([self.d, self.e]),
+            RegPair::HL => u16::from_be_bytes([self.h, self.l]),
+            RegPair::SP => self.sp,
+            RegPair::PSW => u16::from_be_bytes([self.a, self.packed_flags()]),
+            RegPair::IX => self.z80.ix,
+            RegPair::IY => self.z80.iy,
+        };
+    }
+
+    // The flags as PUSH PSW stores them, the Z80 keeps n where the 8080 has a fixed 1
+    fn packed_flags(&self) -> u8 {
+        if self.variant == CpuVariant::Z80 {
+            return self.cc.to_f();
+        }
+        return self.cc.to_psw();
+    }
+
+    fn unpack_flags(&self, flags: u8) -> ConditionCodes {
+        if self.variant == CpuVariant::Z80 {
+            return ConditionCodes::from_f(flags);
+        }
+        return ConditionCodes::from_psw(flags);
+    }
+
+    // Writing PSW goes through the same packing as POP PSW, so the fixed flag bits are forced
+    pub fn set_pair(&mut self, pair: RegPair, value: u16) {
+        let [upper, lower] = value.to_be_bytes();
+        match pair {
+            RegPair::BC => {
+                self.b = upper;
+                self.c = lower;
+            }
+            RegPair::DE => {
+                self.d = upper;
+                self.e = lower;
+            }
+            RegPair::HL => {
+                self.h = upper;
+                self.l = lower;
+            }
+            RegPair::SP => self.sp = value,
+            RegPair::PSW => {
+                self.a = upper;
+                self.cc = self.unpack_flags(lower);
+            }
+            RegPair::IX => self.z80.ix = value,
+            RegPair::IY => self.z80.iy = value,
+        }
+    }
+
+    pub fn flag(&self, flag: Flag) -> bool {
+        return self.cc.get(flag);
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        self.cc.set(flag, value);
+    }
+
+    pub fn flags(&self) -> &ConditionCodes {
+        return &self.cc;
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn registers(&self) -> Registers {
+        return Registers {
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            flags: self.packed_flags(),
+            sp: self.sp,
+            pc: self.pc,
+            z80: self.z80_registers(),
+        };
+    }
+
+    pub fn restore_registers(&mut self, registers: &Registers) {
+        self.a = registers.a;
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.cc = self.unpack_flags(registers.flags);
+        self.sp = registers.sp;
+        self.pc = registers.pc;
+        // A snapshot without them, from an 8080 or an old trace, leaves the Z80's alone
+        if let Some(ref z80) = registers.z80 {
+            self.restore_z80_registers(z80);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::registers::*;
+    use crate::emulator::test_utils::*;
+
+    #[test]
+    fn test_reg() {
+        let mut state = setup_state();
+        let regs = [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::H, Reg::L];
+        for (i, reg) in regs.iter().enumerate() {
+            state.set_reg(*reg, i as u8 + 1);
+        }
+        for (i, reg) in regs.iter().enumerate() {
+            assert_eq!(state.reg(*reg), i as u8 + 1);
+        }
+        assert_eq!(state.a, 1);
+        assert_eq!(state.l, 7);
+    }
+
+    #[test]
+    fn test_pair() {
+        let mut state = setup_state();
+        state.set_pair(RegPair::BC, 0xabcd);
+        assert_eq!(state.b, 0xab);
+        assert_eq!(state.c, 0xcd);
+        assert_eq!(state.pair(RegPair::BC), 0xabcd);
+
+        state.d = 0x12;
+        state.e = 0x34;
+        assert_eq!(state.pair(RegPair::DE), 0x1234);
+
+        state.set_pair(RegPair::HL, 0x00ff);
+        assert_eq!((state.h, state.l), (0x00, 0xff));
+
+        state.set_pair(RegPair::SP, 0xf000);
+        assert_eq!(state.sp, 0xf000);
+        assert_eq!(state.pair(RegPair::SP), 0xf000);
+    }
+
+    #[test]
+    fn test_psw_pair() {
+        let mut state = setup_state();
+        state.set_pair(RegPair::PSW, 0x42ff);
+        assert_eq!(state.a, 0x42);
+        assert_eq!(state.flag(Flag::S), true);
+        assert_eq!(state.flag(Flag::Z), true);
+        assert_eq!(state.flag(Flag::AC), true);
+        assert_eq!(state.flag(Flag::P), true);
+        assert_eq!(state.flag(Flag::CY), true);
+        // the fixed bits come back the way the chip has them
+        assert_eq!(state.pair(RegPair::PSW), 0x42d7);
+    }
+
+    #[test]
+    fn test_flags() {
+        let mut state = setup_state();
+        state.set_flag(Flag::CY, true);
+        assert_eq!(state.cc.cy, true);
+        assert_eq!(state.flags().to_psw(), 0b00000011);
+        state.set_flag(Flag::CY, false);
+        state.set_flag(Flag::Z, true);
+        assert_eq!(state.flag(Flag::CY), false);
+        assert_eq!(state.flag(Flag::Z), true);
+    }
+
+    #[test]
+    fn test_registers_string() {
+        let mut state = setup_state();
+        state.set_pair(RegPair::PSW, 0x0141);
+        state.set_pair(RegPair::BC, 0x0203);
+        state.set_pair(RegPair::HL, 0xabcd);
+        state.set_pc(0x1000);
+        assert_eq!(
+            state.registers().to_string(),
+            "a:01 bc:0203 de:0000 hl:abcd pc:1000 sp:0064 .z..c"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut state = setup_state();
+        state.set_pair(RegPair::BC, 0x0102);
+        state.set_pair(RegPair::DE, 0x0304);
+        state.set_pair(RegPair::HL, 0x0506);
+        state.set_pair(RegPair::PSW, 0x07c3);
+        state.set_sp(0x2400);
+        state.set_pc(0x1000);
+        let snapshot = state.registers();
+
+        state.memory.write(0x1000, 0x3c); // INR A op code
+        state.memory.write(0x1001, 0xc5); // PUSH B op code
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_ne!(state.registers(), snapshot);
+
+        state.restore_registers(&snapshot);
+        assert_eq!(state.registers(), snapshot);
+        assert_eq!(state.a, 0x07);
+        assert_eq!(state.flags().to_psw(), 0xc3);
+        assert_eq!(state.pc(), 0x1000);
+        assert_eq!(state.sp(), 0x2400);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::decoder::decode_for;
+use crate::decoder::Instruction;
+use crate::emulator::config::CpuVariant;
+use crate::emulator::registers::Registers;
+use log::debug;
+use log::warn;
+use std::io;
+use std::io::Write;
+
+// Everything about one instruction the cpu ran. Interrupts show up as the instruction that was
+// put on the bus, with the address pc was at when it came in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub instruction: Instruction,
+    pub before: Registers,
+    pub after: Registers,
+    pub cycles: u32,
+}
+
+// Receives a record for every instruction once it has run. While no sink is attached the cpu
+// doesn't even take the register snapshots.
+pub trait TraceSink {
+    fn record(&mut self, record: &TraceRecord);
+}
+
+// Goes out through the log crate at debug level in the format emulate_op has always logged
+pub struct LogTraceSink;
+
+impl TraceSink for LogTraceSink {
+    fn record(&mut self, record: &TraceRecord) {
+        let op = format!("{:02x} {}", record.instruction.opcode, record.instruction);
+        debug!("{:19} {}", op, record.before);
+    }
+}
+
+// Writers only complain once, after that the trace is cut short quietly
+fn report(result: io::Result<()>, failed: &mut bool) {
+    if let Err(e) = result {
+        if !*failed {
+            warn!("Trace write failed, dropping the rest: {}", e);
+        }
+        *failed = true;
+    }
+}
+
+// One line per instruction, the state before it and after it:
+//  0002  81        ADD    C          a:00 bc:0010 ... sp:0064 ..... -> a:10 bc:0010 ...  4
+pub struct TextTraceSink<W: Write> {
+    writer: W,
+    failed: bool,
+}
+
+impl<W: Write> TextTraceSink<W> {
+    pub fn new(writer: W) -> TextTraceSink<W> {
+        return TextTraceSink { writer, failed: false };
+    }
+
+    pub fn into_inner(self) -> W {
+        return self.writer;
+    }
+}
+
+impl<W: Write> TraceSink for TextTraceSink<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.failed {
+            return;
+        }
+        let bytes: Vec<String> = record.instruction.encoding().iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let result = writeln!(
+            self.writer,
+            "{:04x}  {:8}  {:16}  {} -> {}  {}",
+            record.instruction.address,
+            bytes.join(" "),
+            record.instruction.to_string(),
+            record.before,
+            record.after,
+            record.cycles
+        );
+        report(result, &mut self.failed);
+    }
+}
+
+// One JSON object per line, numbers are decimal and flags are the packed PSW byte:
+//  {"address":2,"bytes":[129],"instruction":"ADD    C","before":{...},"after":{...},"cycles":4}
+pub struct JsonLinesTraceSink<W: Write> {
+    writer: W,
+    failed: bool,
+}
+
+impl<W: Write> JsonLinesTraceSink<W> {
+    pub fn new(writer: W) -> JsonLinesTraceSink<W> {
+        return JsonLinesTraceSink { writer, failed: false };
+    }
+
+    pub fn into_inner(self) -> W {
+        return self.writer;
+    }
+}
+
+fn json_registers(registers: &Registers) -> String {
+    return format!(
+        "{{\"a\":{},\"b\":{},\"c\":{},\"d\":{},\"e\":{},\"h\":{},\"l\":{},\"flags\":{},\"sp\":{},\"pc\":{}}}",
+        registers.a,
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        registers.flags,
+        registers.sp,
+        registers.pc
+    );
+}
+
+impl<W: Write> TraceSink for JsonLinesTraceSink<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.failed {
+            return;
+        }
+        let bytes: Vec<String> = record.instruction.encoding().iter()
+            .map(|byte| byte.to_string())
+            .collect();
+        // Mnemonics are only letters, digits, spaces and ,#$ so they need no escaping
+        let result = writeln!(
+            self.writer,
+            "{{\"address\":{},\"bytes\":[{}],\"instruction\":\"{}\",\"before\":{},\"after\":{},\"cycles\":{}}}",
+            record.instruction.address,
+            bytes.join(","),
+            record.instruction,
+            json_registers(&record.before),
+            json_registers(&record.after),
+            record.cycles
+        );
+        report(result, &mut self.failed);
+    }
+}
+
+// Fixed size records, multi byte values are little endian:
+//  0   address      2 bytes
+//  2   length       1 byte
+//  3   bytes        4 bytes, the ones past length are 0
+//  7   before       12 bytes, a b c d e h l flags sp pc, the Z80's extra registers aren't kept
+//  19  after        12 bytes, same layout
+//  31  cycles       1 byte
+pub const BINARY_RECORD_SIZE: usize = 32;
+
+pub struct BinaryTraceSink<W: Write> {
+    writer: W,
+    failed: bool,
+}
+
+impl<W: Write> BinaryTraceSink<W> {
+    pub fn new(writer: W) -> BinaryTraceSink<W> {
+        return BinaryTraceSink { writer, failed: false };
+    }
+
+    pub fn into_inner(self) -> W {
+        return self.writer;
+    }
+}
+
+fn write_registers(buffer: &mut [u8], registers: &Registers) {
+    buffer[..8].copy_from_slice(&[
+        registers.a,
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        registers.flags,
+    ]);
+    buffer[8..10].copy_from_slice(&registers.sp.to_le_bytes());
+    buffer[10..12].copy_from_slice(&registers.pc.to_le_bytes());
+}
+
+fn read_registers(buffer: &[u8]) -> Registers {
+    return Registers {
+        a: buffer[0],
+        b: buffer[1],
+        c: buffer[2],
+        d: buffer[3],
+        e: buffer[4],
+        h: buffer[5],
+        l: buffer[6],
+        flags: buffer[7],
+        sp: u16::from_le_bytes([buffer[8], buffer[9]]),
+        pc: u16::from_le_bytes([buffer[10], buffer[11]]),
+        z80: None,
+    };
+}
+
+impl<W: Write> TraceSink for BinaryTraceSink<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.failed {
+            return;
+        }
+        let mut buffer = [0; BINARY_RECORD_SIZE];
+        buffer[0..2].copy_from_slice(&record.instruction.address.to_le_bytes());
+        buffer[2] = record.instruction.length as u8;
+        buffer[3..7].copy_from_slice(&record.instruction.bytes);
+        write_registers(&mut buffer[7..19], &record.before);
+        write_registers(&mut buffer[19..31], &record.after);
+        buffer[31] = record.cycles as u8;
+        report(self.writer.write_all(&buffer), &mut self.failed);
+    }
+}
+
+// Turns a record written by BinaryTraceSink back into a TraceRecord, the variant isn't stored so
+// it has to be the one the trace was taken on. A length past the 4 bytes stored, from a corrupt
+// file, only decodes those 4.
+pub fn read_binary_record(variant: CpuVariant, buffer: &[u8; BINARY_RECORD_SIZE]) -> TraceRecord {
+    let address = u16::from_le_bytes([buffer[0], buffer[1]]);
+    let length = (buffer[2] as usize).min(4);
+    return TraceRecord {
+        instruction: decode_for(variant, &buffer[3..3 + length], address),
+        before: read_registers(&buffer[7..19]),
+        after: read_registers(&buffer[19..31]),
+        cycles: buffer[31] as u32,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decoder::Op;
+    use crate::emulator::i8085::InterruptPin;
+    use crate::emulator::test_utils::*;
+    use crate::emulator::trace::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct RecordingSink {
+        records: Rc<RefCell<Vec<TraceRecord>>>,
+    }
+
+    impl TraceSink for RecordingSink {
+        fn record(&mut self, record: &TraceRecord) {
+            self.records.borrow_mut().push(*record);
+        }
+    }
+
+    fn sample() -> TraceRecord {
+        let mut state = setup_state();
+        state.b = 0x10;
+        state.memory.write(0, 0x80); // ADD B op code
+        let before = state.registers();
+        state.emulate_op().unwrap();
+        return TraceRecord {
+            instruction: decode_for(CpuVariant::Intel8080, &[0x80], 0),
+            before,
+            after: state.registers(),
+            cycles: 4,
+        };
+    }
+
+    #[test]
+    fn test_records_each_instruction() {
+        let records = Rc::new(RefCell::new(vec![]));
+        let mut state = setup_state();
+        state.set_trace_sink(Box::new(RecordingSink { records: records.clone() }));
+        state.memory.write(0, 0x3e); // MVI A, 0x41
+        state.memory.write(1, 0x41);
+        state.memory.write(2, 0x3c); // INR A op code
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        let records = records.borrow();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].instruction.encoding(), &[0x3e, 0x41]);
+        assert_eq!(records[0].before.a, 0x00);
+        assert_eq!(records[0].after.a, 0x41);
+        assert_eq!(records[0].after.pc, 2);
+        assert_eq!(records[0].cycles, 7);
+        assert_eq!(records[1].before, records[0].after);
+        assert_eq!(records[1].after.a, 0x42);
+        assert_eq!(records[1].after.flags, 0b0000_0110);
+    }
+
+    #[test]
+    fn test_records_blocks_and_interrupts() {
+        let records = Rc::new(RefCell::new(vec![]));
+        let mut state = setup_state();
+        state.memory.write(0, 0xfb); // EI op code
+        state.memory.write(1, 0x00); // NOP op code
+        state.memory.write(2, 0x76); // HLT op code
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.set_trace_sink(Box::new(RecordingSink { records: records.clone() }));
+
+        state.emulate_block().unwrap();
+        state.interrupt(0xcf).unwrap(); // RST 1
+        assert_eq!(state.take_trace_sink().is_some(), true);
+        state.emulate_op().unwrap();
+
+        let records = records.borrow();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].instruction.op, Op::Hlt);
+        assert_eq!(records[1].instruction.op, Op::Rst(1));
+        assert_eq!(records[1].instruction.address, 3);
+        assert_eq!(records[1].after.pc, 0x08);
+    }
+
+    #[test]
+    fn test_records_pin_interrupts() {
+        let records = Rc::new(RefCell::new(vec![]));
+        let mut state = setup_state();
+        state.variant = CpuVariant::Intel8085;
+        state.set_trace_sink(Box::new(RecordingSink { records: records.clone() }));
+        state.set_interrupt_pin(InterruptPin::Trap, true);
+
+        assert_eq!(state.emulate_op(), Ok(12));
+
+        let records = records.borrow();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].instruction.op, Op::Call(0x24));
+        assert_eq!(records[0].instruction.address, 0);
+        assert_eq!(records[0].after.pc, 0x24);
+        assert_eq!(records[0].cycles, 12);
+    }
+
+    #[test]
+    fn test_records_z80_interrupts() {
+        let records = Rc::new(RefCell::new(vec![]));
+        let mut state = setup_state();
+        state.variant = CpuVariant::Z80;
+        state.set_trace_sink(Box::new(RecordingSink { records: records.clone() }));
+        state.set_interrupt_mode(1);
+        state.int_enable = true;
+
+        assert_eq!(state.interrupt(0xcf), Ok(true));
+        assert_eq!(state.nmi(), Ok(true));
+
+        let records = records.borrow();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].instruction.op, Op::Rst(7));
+        assert_eq!(records[0].cycles, 11 + 2);
+        assert_eq!(records[1].instruction.op, Op::Call(0x66));
+        assert_eq!(records[1].instruction.address, 0x38);
+        assert_eq!(records[1].cycles, 11);
+        assert_eq!(state.cycles(), 11 + 2 + 11);
+    }
+
+    #[test]
+    fn test_text_sink() {
+        let mut sink = TextTraceSink::new(vec![]);
+        sink.record(&sample());
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        assert_eq!(
+            text,
+            "0000  80        ADD    B          \
+             a:00 bc:1000 de:0000 hl:0000 pc:0000 sp:0064 ..... -> \
+             a:10 bc:1000 de:0000 hl:0000 pc:0001 sp:0064 .....  4\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines_sink() {
+        let mut sink = JsonLinesTraceSink::new(vec![]);
+        sink.record(&sample());
+        sink.record(&sample());
+        let text = String::from_utf8(sink.into_inner()).unwrap();
+        let line = "{\"address\":0,\"bytes\":[128],\"instruction\":\"ADD    B\",\
+            \"before\":{\"a\":0,\"b\":16,\"c\":0,\"d\":0,\"e\":0,\"h\":0,\"l\":0,\"flags\":2,\"sp\":100,\"pc\":0},\
+            \"after\":{\"a\":16,\"b\":16,\"c\":0,\"d\":0,\"e\":0,\"h\":0,\"l\":0,\"flags\":2,\"sp\":100,\"pc\":1},\
+            \"cycles\":4}\n";
+        assert_eq!(text, format!("{}{}", line, line));
+    }
+
+    #[test]
+    fn test_binary_sink_round_trip() {
+        let record = sample();
+        let mut sink = BinaryTraceSink::new(vec![]);
+        sink.record(&record);
+        let bytes = sink.into_inner();
+        assert_eq!(bytes.len(), BINARY_RECORD_SIZE);
+        assert_eq!(&bytes[..7], &[0x00, 0x00, 0x01, 0x80, 0x00, 0x00, 0x00]);
+
+        let mut buffer = [0; BINARY_RECORD_SIZE];
+        buffer.copy_from_slice(&bytes);
+        assert_eq!(read_binary_record(CpuVariant::Intel8080, &buffer), record);
+    }
+
+    #[test]
+    fn test_binary_record_with_bad_length() {
+        let mut sink = BinaryTraceSink::new(vec![]);
+        sink.record(&sample());
+        let mut buffer = [0; BINARY_RECORD_SIZE];
+        buffer.copy_from_slice(&sink.into_inner());
+        buffer[2] = 0xff;
+
+        let record = read_binary_record(CpuVariant::Intel8080, &buffer);
+        assert_eq!(record.instruction.address, 0);
+        assert_eq!(record.after, sample().after);
+    }
+}
@@ -1,17 +1,5 @@
 use crate::emulator::ConditionCodes;
 
-pub fn combine(upper: u8, lower: u8) -> u16 {
-    let mut destination = (upper as u16) << 8;
-    destination |= lower as u16;
-    return destination;
-}
-
-pub fn split(num: u16) -> (u8, u8) {
-    let lower = (num & 0xff) as u8;
-    let upper = (num >> 8) as u8;
-    return (upper, lower);
-}
-
 pub fn update_flags(value: u16, codes: &mut ConditionCodes) {
     codes.z = value as u8 & 0xff == 0;
     codes.s = value as u8 & 0x80 > 0;
@@ -19,6 +7,11 @@ pub fn update_flags(value: u16, codes: &mut ConditionCodes) {
     codes.p = parity(value as usize, 8);
 }
 
+// Auxiliary carry is the carry out of bit 3 when adding the two values and the carry in
+pub fn aux_carry(first: u8, second: u8, carry: bool) -> bool {
+    return (first & 0x0f) + (second & 0x0f) + carry as u8 > 0x0f;
+}
+
 fn parity(value_to_check: usize, size: usize) -> bool {
     let mut set_bits = 0;
     let mut mask: usize = 1;
@@ -36,9 +29,8 @@ fn parity(value_to_check: usize, size: usize) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use crate::emulator::utils::combine;
+    use crate::emulator::utils::aux_carry;
     use crate::emulator::utils::parity;
-    use crate::emulator::utils::split;
 
     #[test]
     fn test_parity() {
@@ -54,13 +46,10 @@ mod tests {
     }
 
     #[test]
-    fn test_combine_and_split() {
-        let upper1 = 0xab as u8;
-        let lower1 = 0xcd as u8;
-        let combined = combine(upper1, lower1);
-        assert_eq!(combined, 0xabcd);
-        let (upper2, lower2) = split(combined);
-        assert_eq!(upper2, 0xab);
-        assert_eq!(lower2, 0xcd);
+    fn test_aux_carry() {
+        assert_eq!(false, aux_carry(0x07, 0x08, false));
+        assert_eq!(true, aux_carry(0x07, 0x08, true));
+        assert_eq!(true, aux_carry(0x0f, 0x01, false));
+        assert_eq!(false, aux_carry(0xf0, 0xf0, true));
     }
 }
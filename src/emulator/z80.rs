@@ -0,0 +1,647 @@
+use crate::decoder::decode_for;
+use crate::decoder::is_prefix;
+use crate::decoder::BlockOp;
+use crate::decoder::Instruction;
+use crate::emulator::config::CpuVariant;
+use crate::emulator::error::EmulatorError;
+use crate::emulator::registers::RegPair;
+use crate::emulator::utils::update_flags;
+use crate::emulator::State8080;
+
+// Where NMI calls to
+const NMI_VECTOR: u16 = 0x0066;
+const NMI_CYCLES: u32 = 11;
+// Acknowledging a maskable interrupt takes 2 T-states on top of the instruction it runs
+const INTERRUPT_ACKNOWLEDGE_CYCLES: u32 = 2;
+// IM 1 always runs RST 38h
+const MODE_1_OPCODE: u8 = 0xff;
+
+// How the Z80 answers a maskable interrupt:
+//  Mode0  runs the instruction the device puts on the bus, like the 8080
+//  Mode1  runs RST 38h whatever is on the bus
+//  Mode2  calls the address stored at I * 256 + the byte on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptMode {
+    Mode0,
+    Mode1,
+    Mode2,
+}
+
+// The Z80's registers on top of the 8080's, for Registers. The alternate set is kept as pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Z80Registers {
+    pub ix: u16,
+    pub iy: u16,
+    pub i: u8,
+    pub r: u8,
+    pub iff1: bool,
+    pub iff2: bool,
+    pub mode: InterruptMode,
+    pub af_alternate: u16,
+    pub bc_alternate: u16,
+    pub de_alternate: u16,
+    pub hl_alternate: u16,
+}
+
+// Everything the Z80 has on top of the 8080's registers. IFF1 is int_enable, IFF2 is where NMI
+// keeps a copy of it. The alternate set is only reachable through EX AF,AF' and EXX.
+#[derive(Debug)]
+pub(crate) struct Z80 {
+    pub(crate) ix: u16,
+    pub(crate) iy: u16,
+    pub(crate) i: u8, // Upper byte of the IM 2 vector table
+    pub(crate) r: u8, // Memory refresh counter, counts op code fetches in its low 7 bits
+    pub(crate) iff2: bool,
+    mode: InterruptMode,
+    af_alternate: u16,
+    bc_alternate: u16,
+    de_alternate: u16,
+    hl_alternate: u16,
+}
+
+impl Z80 {
+    pub(crate) fn new() -> Z80 {
+        return Z80 {
+            ix: 0,
+            iy: 0,
+            i: 0,
+            r: 0,
+            iff2: false,
+            mode: InterruptMode::Mode0,
+            af_alternate: 0,
+            bc_alternate: 0,
+            de_alternate: 0,
+            hl_alternate: 0,
+        };
+    }
+
+    // What RESET does to them, the rest keep their values
+    pub(crate) fn reset(&mut self) {
+        self.i = 0;
+        self.r = 0;
+        self.iff2 = false;
+        self.mode = InterruptMode::Mode0;
+    }
+}
+
+impl State8080 {
+    pub fn interrupt_mode(&self) -> InterruptMode {
+        return self.z80.mode;
+    }
+
+    // None on the 8080 and 8085
+    pub(crate) fn z80_registers(&self) -> Option<Z80Registers> {
+        if self.variant != CpuVariant::Z80 {
+            return None;
+        }
+        return Some(Z80Registers {
+            ix: self.z80.ix,
+            iy: self.z80.iy,
+            i: self.z80.i,
+            r: self.z80.r,
+            iff1: self.int_enable,
+            iff2: self.z80.iff2,
+            mode: self.z80.mode,
+            af_alternate: self.z80.af_alternate,
+            bc_alternate: self.z80.bc_alternate,
+            de_alternate: self.z80.de_alternate,
+            hl_alternate: self.z80.hl_alternate,
+        });
+    }
+
+    pub(crate) fn restore_z80_registers(&mut self, registers: &Z80Registers) {
+        self.z80.ix = registers.ix;
+        self.z80.iy = registers.iy;
+        self.z80.i = registers.i;
+        self.z80.r = registers.r;
+        self.int_enable = registers.iff1;
+        self.ei_delay = false;
+        self.z80.iff2 = registers.iff2;
+        self.z80.mode = registers.mode;
+        self.z80.af_alternate = registers.af_alternate;
+        self.z80.bc_alternate = registers.bc_alternate;
+        self.z80.de_alternate = registers.de_alternate;
+        self.z80.hl_alternate = registers.hl_alternate;
+    }
+
+    // The Z80's non maskable interrupt, it is taken even with interrupts disabled and wakes the
+    // cpu from HALT. It calls 0x0066 and keeps whether interrupts were enabled for RETN to put
+    // back. It runs as a CALL so traces show it. Returns false on the 8080 and 8085, which don't
+    // have the pin.
+    pub fn nmi(&mut self) -> Result<bool, EmulatorError> {
+        if self.variant != CpuVariant::Z80 {
+            return Ok(false);
+        }
+        self.z80.iff2 = self.int_enable;
+        self.int_enable = false;
+        self.ei_delay = false;
+        self.halted = false;
+        let [lower, upper] = NMI_VECTOR.to_le_bytes();
+        let instruction = decode_for(self.variant, &[0xcd, lower, upper], self.pc);
+        self.run_costing(&instruction, Some(NMI_CYCLES))?;
+        return Ok(true);
+    }
+
+    // Runs an accepted maskable interrupt, interrupts are already off
+    pub(crate) fn interrupt_z80(&mut self, data: u8) -> Result<(), EmulatorError> {
+        self.z80.iff2 = false;
+        let instruction = match self.z80.mode {
+            InterruptMode::Mode0 => decode_for(self.variant, &[data], self.pc),
+            InterruptMode::Mode1 => decode_for(self.variant, &[MODE_1_OPCODE], self.pc),
+            InterruptMode::Mode2 => {
+                let vector = u16::from_be_bytes([self.z80.i, data]);
+                let lower = self.memory.read(vector);
+                let upper = self.memory.read(vector.wrapping_add(1));
+                // Runs as the CALL it amounts to
+                decode_for(self.variant, &[0xcd, lower, upper], self.pc)
+            }
+        };
+        // Whatever is on the bus in mode 0 is an RST or CALL in practice, so no branch can be
+        // taken that costs more
+        let cycles = instruction.cycles() + INTERRUPT_ACKNOWLEDGE_CYCLES;
+        self.run_costing(&instruction, Some(cycles))?;
+        return Ok(());
+    }
+
+    pub(crate) fn set_interrupt_mode(&mut self, mode: u8) {
+        self.z80.mode = match mode {
+            0 => InterruptMode::Mode0,
+            1 => InterruptMode::Mode1,
+            _ => InterruptMode::Mode2,
+        };
+    }
+
+    // R goes up once per op code fetch, twice for prefixed ones. Bit 7 is left alone.
+    pub(crate) fn refresh(&mut self, instruction: &Instruction) {
+        let prefixed = instruction.length > 1 && is_prefix(instruction.variant, instruction.opcode);
+        let fetches = if prefixed { 2 } else { 1 };
+        self.refresh_by(fetches);
+    }
+
+    fn refresh_by(&mut self, fetches: u8) {
+        let r = self.z80.r;
+        self.z80.r = (r & 0x80) | (r.wrapping_add(fetches) & 0x7f);
+    }
+
+    pub(crate) fn exchange_af(&mut self) {
+        let af = self.pair(RegPair::PSW);
+        self.set_pair(RegPair::PSW, self.z80.af_alternate);
+        self.z80.af_alternate = af;
+    }
+
+    pub(crate) fn exx(&mut self) {
+        let (bc, de, hl) = (self.pair(RegPair::BC), self.pair(RegPair::DE), self.pair(RegPair::HL));
+        self.set_pair(RegPair::BC, self.z80.bc_alternate);
+        self.set_pair(RegPair::DE, self.z80.de_alternate);
+        self.set_pair(RegPair::HL, self.z80.hl_alternate);
+        self.z80.bc_alternate = bc;
+        self.z80.de_alternate = de;
+        self.z80.hl_alternate = hl;
+    }
+
+    // LD A,I and LD A,R, the only loads that set flags. p shows whether interrupts were enabled.
+    pub(crate) fn load_a_special(&mut self, value: u8) {
+        self.a = value;
+        update_flags(value as u16, &mut self.cc);
+        self.cc.p = self.z80.iff2;
+        self.cc.ac = false;
+        self.cc.n = false;
+    }
+
+    // RLD and RRD rotate the three digits in the low half of a and the byte at HL, left moves the
+    // low digit of (HL) up and right moves it into a
+    pub(crate) fn rotate_digits(&mut self, left: bool) {
+        let address = self.pair(RegPair::HL);
+        let value = self.memory.read(address);
+        let (a, value) = if left {
+            ((self.a & 0xf0) | (value >> 4), (value << 4) | (self.a & 0x0f))
+        } else {
+            ((self.a & 0xf0) | (value & 0x0f), (self.a << 4) | (value >> 4))
+        };
+        self.write_byte(address, value);
+        self.a = a;
+        let carry = self.cc.cy;
+        update_flags(a as u16, &mut self.cc);
+        self.cc.cy = carry;
+        self.cc.ac = false;
+        self.cc.n = false;
+    }
+
+    // One step of a block instruction. The repeating ones go round again by moving pc back onto
+    // themselves, so interrupts still get in between steps. Returns whether it did that.
+    pub(crate) fn block(&mut self, op: BlockOp) -> bool {
+        let step = if op.decrements() { 0xffff } else { 1 };
+        let hl = self.pair(RegPair::HL);
+        let again = match op {
+            BlockOp::Ldi | BlockOp::Ldd | BlockOp::Ldir | BlockOp::Lddr => {
+                let value = self.memory.read(hl);
+                let de = self.pair(RegPair::DE);
+                self.write_byte(de, value);
+                self.set_pair(RegPair::DE, de.wrapping_add(step));
+                let bc = self.pair(RegPair::BC).wrapping_sub(1);
+                self.set_pair(RegPair::BC, bc);
+                self.cc.p = bc != 0;
+                self.cc.ac = false;
+                self.cc.n = false;
+                bc != 0
+            }
+            BlockOp::Cpi | BlockOp::Cpd | BlockOp::Cpir | BlockOp::Cpdr => {
+                let value = self.memory.read(hl);
+                let answer = self.a.wrapping_sub(value);
+                let bc = self.pair(RegPair::BC).wrapping_sub(1);
+                self.set_pair(RegPair::BC, bc);
+                self.cc.z = answer == 0;
+                self.cc.s = answer & 0x80 != 0;
+                self.cc.ac = self.a & 0x0f < value & 0x0f;
+                self.cc.p = bc != 0;
+                self.cc.n = true;
+                bc != 0 && answer != 0
+            }
+            BlockOp::Ini | BlockOp::Ind | BlockOp::Inir | BlockOp::Indr => {
+                let value = self.io.input(self.c);
+                self.write_byte(hl, value);
+                self.b = self.b.wrapping_sub(1);
+                self.cc.z = self.b == 0;
+                self.cc.n = true;
+                self.b != 0
+            }
+            BlockOp::Outi | BlockOp::Outd | BlockOp::Otir | BlockOp::Otdr => {
+                let value = self.memory.read(hl);
+                self.b = self.b.wrapping_sub(1);
+                self.io.output(self.c, value);
+                self.cc.z = self.b == 0;
+                self.cc.n = true;
+                self.b != 0
+            }
+        };
+        self.set_pair(RegPair::HL, hl.wrapping_add(step));
+        if op.repeats() && again {
+            self.pc = self.pc.wrapping_sub(2);
+            return true;
+        }
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::io::IoBus;
+    use crate::emulator::registers::Flag;
+    use crate::emulator::registers::RegPair;
+    use crate::emulator::test_utils::*;
+    use crate::emulator::z80::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn z80_state(program: &[u8]) -> State8080 {
+        let mut state = setup_state();
+        state.variant = CpuVariant::Z80;
+        for (i, byte) in program.iter().enumerate() {
+            state.memory.write(i as u16, *byte);
+        }
+        return state;
+    }
+
+    #[test]
+    fn test_relative_jumps() {
+        let mut state = z80_state(&[
+            0x06, 0x03, // LD B,3
+            0x3c,       // INC A
+            0x10, 0xfd, // DJNZ -3
+            0x18, 0x02, // JR +2
+            0x00, 0x00,
+            0x28, 0x10, // JR Z,+16
+        ]);
+        state.emulate_op().unwrap();
+        for _ in 0..3 {
+            state.emulate_op().unwrap();
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(state.a, 3);
+        assert_eq!(state.pc, 5);
+        assert_eq!(state.cycles(), 7 + 3 * 4 + 13 + 13 + 8);
+
+        assert_eq!(state.emulate_op(), Ok(12));
+        assert_eq!(state.pc, 9);
+        assert_eq!(state.emulate_op(), Ok(7));
+        assert_eq!(state.pc, 11);
+    }
+
+    #[test]
+    fn test_exchanges() {
+        let mut state = z80_state(&[0x08, 0xd9, 0x08, 0xd9]); // EX AF,AF' EXX EX AF,AF' EXX
+        state.set_pair(RegPair::PSW, 0x1203);
+        state.set_pair(RegPair::BC, 0x3456);
+        state.set_pair(RegPair::HL, 0x789a);
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.pair(RegPair::PSW), 0x0000);
+        assert_eq!(state.pair(RegPair::BC), 0x0000);
+        assert_eq!(state.pair(RegPair::HL), 0x0000);
+
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        // n survives the trip, it is bit 1 of F
+        assert_eq!(state.pair(RegPair::PSW), 0x1203);
+        assert_eq!(state.flag(Flag::N), true);
+        assert_eq!(state.pair(RegPair::BC), 0x3456);
+        assert_eq!(state.pair(RegPair::HL), 0x789a);
+    }
+
+    #[test]
+    fn test_indexed() {
+        let mut state = z80_state(&[
+            0xdd, 0x21, 0x00, 0x20, // LD IX,$2000
+            0xdd, 0x36, 0xfe, 0x41, // LD (IX-2),$41
+            0xdd, 0x34, 0xfe,       // INC (IX-2)
+            0xfd, 0x21, 0x00, 0x10, // LD IY,$1000
+            0xfd, 0x7e, 0x05,       // LD A,(IY+5)
+            0xdd, 0x86, 0xfe,       // ADD A,(IX-2)
+            0xdd, 0xe5,             // PUSH IX
+        ]);
+        state.memory.write(0x1005, 0x01);
+
+        let cycles: Vec<u32> = (0..7).map(|_| state.emulate_op().unwrap()).collect();
+
+        assert_eq!(state.memory.read(0x1ffe), 0x42);
+        assert_eq!(state.a, 0x43);
+        assert_eq!(state.pair(RegPair::IY), 0x1000);
+        assert_eq!(state.memory.read(98), 0x00);
+        assert_eq!(state.memory.read(99), 0x20);
+        assert_eq!(cycles, vec![14, 19, 23, 14, 19, 19, 15]);
+    }
+
+    #[test]
+    fn test_bit_operations() {
+        let mut state = z80_state(&[
+            0xcb, 0x7f,             // BIT 7,A
+            0xcb, 0xff,             // SET 7,A
+            0xcb, 0x7f,             // BIT 7,A
+            0xcb, 0x86,             // RES 0,(HL)
+            0xcb, 0x38,             // SRL B
+            0xdd, 0xcb, 0x01, 0x16, // RL (IX+1)
+        ]);
+        state.set_pair(RegPair::HL, 0x2000);
+        state.memory.write(0x2000, 0xff);
+        state.b = 0x03;
+        state.z80.ix = 0x2000;
+        state.memory.write(0x2001, 0x80);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.flag(Flag::Z), true);
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x80);
+        state.emulate_op().unwrap();
+        assert_eq!(state.flag(Flag::Z), false);
+        assert_eq!(state.flag(Flag::S), true);
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x2000), 0xfe);
+        state.emulate_op().unwrap();
+        assert_eq!(state.b, 0x01);
+        assert_eq!(state.flag(Flag::CY), true);
+        state.emulate_op().unwrap();
+        assert_eq!(state.memory.read(0x2001), 0x01);
+        assert_eq!(state.flag(Flag::CY), true);
+        assert_eq!(state.flag(Flag::Z), false);
+    }
+
+    #[test]
+    fn test_arithmetic_flags() {
+        let mut state = z80_state(&[
+            0x3e, 0x7f, // LD A,$7f
+            0x3c,       // INC A
+            0xd6, 0x01, // SUB 1
+            0xed, 0x44, // NEG
+            0x3e, 0x15, // LD A,$15
+            0xd6, 0x06, // SUB 6
+            0x27,       // DAA
+        ]);
+        state.emulate_op().unwrap();
+        state.cc.cy = true;
+        state.emulate_op().unwrap();
+        // INC keeps the carry and p is overflow
+        assert_eq!(state.a, 0x80);
+        assert_eq!(state.flag(Flag::P), true);
+        assert_eq!(state.flag(Flag::CY), true);
+        assert_eq!(state.flag(Flag::N), false);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x7f);
+        assert_eq!(state.flag(Flag::P), true);
+        assert_eq!(state.flag(Flag::N), true);
+        assert_eq!(state.flag(Flag::AC), true);
+        assert_eq!(state.flag(Flag::CY), false);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x81);
+        assert_eq!(state.flag(Flag::CY), true);
+
+        // 15 - 6 in BCD is 09
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x09);
+        assert_eq!(state.flag(Flag::N), true);
+    }
+
+    #[test]
+    fn test_16_bit_arithmetic() {
+        let mut state = z80_state(&[
+            0xed, 0x42,             // SBC HL,BC
+            0xed, 0x5a,             // ADC HL,DE
+            0xed, 0x43, 0x00, 0x20, // LD ($2000),BC
+            0xed, 0x5b, 0x00, 0x20, // LD DE,($2000)
+        ]);
+        state.set_pair(RegPair::HL, 0x1000);
+        state.set_pair(RegPair::BC, 0x1001);
+        state.set_pair(RegPair::DE, 0x0001);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.pair(RegPair::HL), 0xffff);
+        assert_eq!(state.flag(Flag::CY), true);
+        assert_eq!(state.flag(Flag::S), true);
+        state.emulate_op().unwrap();
+        assert_eq!(state.pair(RegPair::HL), 0x0001);
+        assert_eq!(state.flag(Flag::Z), false);
+        assert_eq!(state.flag(Flag::CY), true);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.pair(RegPair::DE), 0x1001);
+    }
+
+    #[test]
+    fn test_ldir() {
+        let mut state = z80_state(&[0xed, 0xb0]); // LDIR
+        for i in 0..4 {
+            state.memory.write(0x1000 + i, 0x10 + i as u8);
+        }
+        state.set_pair(RegPair::HL, 0x1000);
+        state.set_pair(RegPair::DE, 0x2000);
+        state.set_pair(RegPair::BC, 4);
+
+        assert_eq!(state.emulate_op(), Ok(21));
+        assert_eq!(state.pc, 0);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.emulate_op(), Ok(16));
+        assert_eq!(state.pc, 2);
+
+        for i in 0..4 {
+            assert_eq!(state.memory.read(0x2000 + i), 0x10 + i as u8);
+        }
+        assert_eq!(state.pair(RegPair::BC), 0);
+        assert_eq!(state.pair(RegPair::HL), 0x1004);
+        assert_eq!(state.flag(Flag::P), false);
+    }
+
+    #[test]
+    fn test_cpir_stops_on_match() {
+        let mut state = z80_state(&[0xed, 0xb1]); // CPIR
+        state.memory.write(0x1000, 0x01);
+        state.memory.write(0x1001, 0x02);
+        state.set_pair(RegPair::HL, 0x1000);
+        state.set_pair(RegPair::BC, 10);
+        state.a = 0x02;
+
+        while state.pc == 0 {
+            state.emulate_op().unwrap();
+        }
+
+        assert_eq!(state.flag(Flag::Z), true);
+        assert_eq!(state.pair(RegPair::HL), 0x1002);
+        assert_eq!(state.pair(RegPair::BC), 8);
+    }
+
+    struct Ports {
+        written: Rc<RefCell<Vec<(u8, u8)>>>,
+    }
+
+    impl IoBus for Ports {
+        fn input(&mut self, port: u8) -> u8 {
+            return port + 1;
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.written.borrow_mut().push((port, value));
+        }
+    }
+
+    #[test]
+    fn test_port_c() {
+        let written = Rc::new(RefCell::new(vec![]));
+        let mut state = z80_state(&[
+            0xed, 0x50, // IN D,(C)
+            0xed, 0x59, // OUT (C),E
+            0xed, 0xb3, // OTIR
+        ]);
+        state.set_io(Box::new(Ports { written: written.clone() }));
+        state.c = 0x10;
+        state.e = 0x99;
+        state.b = 2;
+        state.set_pair(RegPair::HL, 0x1000);
+        state.memory.write(0x1000, 0xaa);
+        state.memory.write(0x1001, 0xbb);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.d, 0x11);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+
+        assert_eq!(*written.borrow(), vec![(0x10, 0x99), (0x10, 0xaa), (0x10, 0xbb)]);
+        assert_eq!(state.b, 0);
+        assert_eq!(state.pc, 6);
+    }
+
+    #[test]
+    fn test_rld_rrd() {
+        let mut state = z80_state(&[0xed, 0x6f, 0xed, 0x67]); // RLD, RRD
+        state.set_pair(RegPair::HL, 0x1000);
+        state.memory.write(0x1000, 0x31);
+        state.a = 0x7a;
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x73);
+        assert_eq!(state.memory.read(0x1000), 0x1a);
+        state.emulate_op().unwrap();
+        assert_eq!(state.a, 0x7a);
+        assert_eq!(state.memory.read(0x1000), 0x31);
+    }
+
+    #[test]
+    fn test_interrupt_modes() {
+        let mut state = z80_state(&[
+            0xfb,       // EI
+            0xed, 0x56, // IM 1
+            0xfb,       // EI
+            0xed, 0x5e, // IM 2
+            0x3e, 0x30, // LD A,$30
+            0xed, 0x47, // LD I,A
+        ]);
+        state.memory.write(0x30fe, 0x00);
+        state.memory.write(0x30ff, 0x40);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.interrupt_mode(), InterruptMode::Mode1);
+
+        // whatever is on the bus, mode 1 goes to 0x38
+        assert_eq!(state.interrupt(0xcf), Ok(true));
+        assert_eq!(state.pc, 0x38);
+        assert_eq!(state.cycles(), 4 + 8 + 13);
+
+        state.set_pc(3);
+        for _ in 0..5 {
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(state.interrupt(0xfe), Ok(true));
+        assert_eq!(state.pc, 0x4000);
+        assert_eq!(state.memory.read(96), 11);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let mut state = z80_state(&[
+            0xfb,       // EI
+            0x76,       // HALT
+        ]);
+        state.memory.write(0x66, 0xed); // RETN
+        state.memory.write(0x67, 0x45);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        state.interrupt(0xff).unwrap();
+        assert_eq!(state.interrupts_enabled(), false);
+
+        // NMI gets through with interrupts off, and RETN leaves them off as they were
+        assert_eq!(state.nmi(), Ok(true));
+        assert_eq!(state.pc, 0x66);
+        state.emulate_op().unwrap();
+        assert_eq!(state.pc, 0x38);
+        assert_eq!(state.interrupts_enabled(), false);
+
+        let mut state = z80_state(&[0xfb, 0x76]); // EI, HALT
+        state.memory.write(0x66, 0xed); // RETN
+        state.memory.write(0x67, 0x45);
+        state.emulate_op().unwrap();
+        state.emulate_op().unwrap();
+        assert_eq!(state.is_stopped(), false);
+        state.nmi().unwrap();
+        assert_eq!(state.is_halted(), false);
+        state.emulate_op().unwrap();
+        assert_eq!(state.pc, 2);
+        assert_eq!(state.interrupts_enabled(), true);
+
+        let mut state = setup_state();
+        assert_eq!(state.nmi(), Ok(false));
+    }
+
+    #[test]
+    fn test_refresh_register() {
+        let mut state = z80_state(&[0x00, 0xcb, 0x00, 0xdd, 0x23, 0xed, 0x5f]); // LD A,R last
+        state.z80.r = 0x80;
+        for _ in 0..4 {
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(state.a, 0x87);
+    }
+}
@@ -0,0 +1,151 @@
+// The cabinet's buttons and joysticks, each one is a bit on port 0, 1 or 2 that is set while it
+// is held down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Coin,
+    Start1,
+    Start2,
+    Fire1,
+    Left1,
+    Right1,
+    Fire2,
+    Left2,
+    Right2,
+    Tilt,
+}
+
+// The DIP switches on the board, set once by the operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DipSwitches {
+    pub ships: u8,                // Ships per game, 3 to 6
+    pub bonus_life_at_1000: bool, // Extra ship at 1000 points instead of 1500
+    pub coin_info: bool,          // Show the coin info on the demo screen
+}
+
+impl Default for DipSwitches {
+    fn default() -> DipSwitches {
+        return DipSwitches { ships: 3, bonus_life_at_1000: false, coin_info: true };
+    }
+}
+
+pub const INPUT_PORT_0: u8 = 0;
+pub const INPUT_PORT_1: u8 = 1;
+pub const INPUT_PORT_2: u8 = 2;
+
+// Where each control is, as (port, bit)
+fn wiring(control: Control) -> (u8, u8) {
+    return match control {
+        Control::Coin => (INPUT_PORT_1, 0),
+        Control::Start2 => (INPUT_PORT_1, 1),
+        Control::Start1 => (INPUT_PORT_1, 2),
+        Control::Fire1 => (INPUT_PORT_1, 4),
+        Control::Left1 => (INPUT_PORT_1, 5),
+        Control::Right1 => (INPUT_PORT_1, 6),
+        Control::Tilt => (INPUT_PORT_2, 2),
+        Control::Fire2 => (INPUT_PORT_2, 4),
+        Control::Left2 => (INPUT_PORT_2, 5),
+        Control::Right2 => (INPUT_PORT_2, 6),
+    };
+}
+
+// What the cpu reads on ports 0 to 2, the frontend presses and releases controls as its keys
+// change
+#[derive(Debug, Clone)]
+pub struct Inputs {
+    ports: [u8; 3], // Just the controls held down
+    dip_switches: DipSwitches,
+}
+
+impl Inputs {
+    pub fn new(dip_switches: DipSwitches) -> Inputs {
+        return Inputs { ports: [0; 3], dip_switches };
+    }
+
+    pub fn press(&mut self, control: Control) {
+        let (port, bit) = wiring(control);
+        self.ports[port as usize] |= 1 << bit;
+    }
+
+    pub fn release(&mut self, control: Control) {
+        let (port, bit) = wiring(control);
+        self.ports[port as usize] &= !(1 << bit);
+    }
+
+    pub fn dip_switches(&self) -> DipSwitches {
+        return self.dip_switches;
+    }
+
+    pub fn set_dip_switches(&mut self, dip_switches: DipSwitches) {
+        self.dip_switches = dip_switches;
+    }
+
+    // The value on one of the input ports, None for any other port
+    pub fn read(&self, port: u8) -> Option<u8> {
+        return match port {
+            // Bits 1-3 are tied high, the game never reads this port but it has player 1 on it
+            INPUT_PORT_0 => Some(0x0e | (self.ports[INPUT_PORT_1 as usize] & 0x70)),
+            // Bit 3 is tied high
+            INPUT_PORT_1 => Some(0x08 | self.ports[INPUT_PORT_1 as usize]),
+            INPUT_PORT_2 => {
+                let dips = self.dip_switches;
+                let mut value = self.ports[INPUT_PORT_2 as usize];
+                value |= dips.ships.clamp(3, 6) - 3;
+                if dips.bonus_life_at_1000 {
+                    value |= 0x08;
+                }
+                // Switched on the bit is low
+                if !dips.coin_info {
+                    value |= 0x80;
+                }
+                Some(value)
+            }
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::inputs::*;
+
+    #[test]
+    fn test_nothing_pressed() {
+        let inputs = Inputs::new(DipSwitches::default());
+        assert_eq!(inputs.read(INPUT_PORT_0), Some(0x0e));
+        assert_eq!(inputs.read(INPUT_PORT_1), Some(0x08));
+        assert_eq!(inputs.read(INPUT_PORT_2), Some(0x00));
+        assert_eq!(inputs.read(3), None);
+    }
+
+    #[test]
+    fn test_press_and_release() {
+        let mut inputs = Inputs::new(DipSwitches::default());
+        inputs.press(Control::Coin);
+        inputs.press(Control::Start1);
+        inputs.press(Control::Fire1);
+        inputs.press(Control::Right2);
+        assert_eq!(inputs.read(INPUT_PORT_0), Some(0x1e));
+        assert_eq!(inputs.read(INPUT_PORT_1), Some(0x1d));
+        assert_eq!(inputs.read(INPUT_PORT_2), Some(0x40));
+
+        inputs.release(Control::Coin);
+        inputs.release(Control::Right2);
+        assert_eq!(inputs.read(INPUT_PORT_1), Some(0x1c));
+        assert_eq!(inputs.read(INPUT_PORT_2), Some(0x00));
+    }
+
+    #[test]
+    fn test_dip_switches() {
+        let mut inputs = Inputs::new(DipSwitches {
+            ships: 6,
+            bonus_life_at_1000: true,
+            coin_info: false,
+        });
+        inputs.press(Control::Tilt);
+        assert_eq!(inputs.read(INPUT_PORT_2), Some(0x8f));
+
+        inputs.set_dip_switches(DipSwitches { ships: 4, ..DipSwitches::default() });
+        assert_eq!(inputs.read(INPUT_PORT_2), Some(0x05));
+        assert_eq!(inputs.dip_switches().ships, 4);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::emulator::io::IoBus;
+use crate::invaders::inputs::Inputs;
+use crate::invaders::shifter::ShiftRegister;
+use crate::invaders::sound::SoundBoard;
+use crate::invaders::sound::SOUND_PORT_1;
+use crate::invaders::sound::SOUND_PORT_2;
+use log::info;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SHIFT_OFFSET_PORT: u8 = 2;
+pub const SHIFT_RESULT_PORT: u8 = 3;
+pub const SHIFT_DATA_PORT: u8 = 4;
+
+// The ports of the Invaders board, attach it with State8080::set_io. The frontend keeps its own
+// handle on inputs to press and release controls while the cpu runs.
+pub struct InvadersIo {
+    shifter: ShiftRegister,
+    inputs: Rc<RefCell<Inputs>>,
+    sound: Option<Rc<RefCell<SoundBoard>>>, // Without one OUT 3 and OUT 5 are just logged
+}
+
+impl InvadersIo {
+    pub fn new(inputs: Rc<RefCell<Inputs>>) -> InvadersIo {
+        return InvadersIo { shifter: ShiftRegister::new(), inputs, sound: None };
+    }
+
+    // Sends the sound latches to sound, the frontend keeps its own handle to collect the events
+    pub fn with_sound(mut self, sound: Rc<RefCell<SoundBoard>>) -> InvadersIo {
+        self.sound = Some(sound);
+        return self;
+    }
+}
+
+impl IoBus for InvadersIo {
+    fn input(&mut self, port: u8) -> u8 {
+        return match port {
+            SHIFT_RESULT_PORT => self.shifter.result(),
+            _ => match self.inputs.borrow().read(port) {
+                Some(value) => value,
+                None => {
+                    info!("IN  port {:02x}", port);
+                    0x00
+                }
+            },
+        };
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            SHIFT_OFFSET_PORT => self.shifter.set_offset(value),
+            SHIFT_DATA_PORT => self.shifter.load(value),
+            SOUND_PORT_1 | SOUND_PORT_2 if self.sound.is_some() => {
+                self.sound.as_ref().unwrap().borrow_mut().output(port, value);
+            }
+            _ => info!("OUT port {:02x} value {:02x}", port, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::io::*;
+    use crate::emulator::test_utils::*;
+    use crate::emulator::State8080;
+    use crate::invaders::inputs::Control;
+    use crate::invaders::inputs::DipSwitches;
+    use crate::invaders::inputs::INPUT_PORT_1;
+    use crate::invaders::sound::Sound;
+    use crate::invaders::sound::SoundEvent;
+
+    fn invaders_io() -> InvadersIo {
+        return InvadersIo::new(Rc::new(RefCell::new(Inputs::new(DipSwitches::default()))));
+    }
+
+    // MVI A,data then OUT port at address
+    fn out(state: &mut State8080, address: u16, port: u8, data: u8) -> u16 {
+        state.poke(address, 0x3e);
+        state.poke(address + 1, data);
+        state.poke(address + 2, 0xd3);
+        state.poke(address + 3, port);
+        return address + 4;
+    }
+
+    // IN port at address
+    fn input(state: &mut State8080, address: u16, port: u8) -> u16 {
+        state.poke(address, 0xdb);
+        state.poke(address + 1, port);
+        return address + 2;
+    }
+
+    #[test]
+    fn test_shift_sequence() {
+        // Drawing a sprite byte 3 pixels across: load the byte, then 0 to push it into the low
+        // half, set the offset and read back the top byte
+        let mut state = setup_state();
+        state.set_io(Box::new(invaders_io()));
+        let mut address = out(&mut state, 0, SHIFT_DATA_PORT, 0x00);
+        address = out(&mut state, address, SHIFT_DATA_PORT, 0xb5);
+        address = out(&mut state, address, SHIFT_OFFSET_PORT, 3);
+        address = input(&mut state, address, SHIFT_RESULT_PORT);
+        for _ in 0..7 {
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(state.pc(), address);
+        assert_eq!(state.registers().a, 0xa8); // b500 << 3
+
+        // Another byte shifts the first one into the bottom half
+        address = out(&mut state, address, SHIFT_DATA_PORT, 0x6e);
+        input(&mut state, address, SHIFT_RESULT_PORT);
+        for _ in 0..3 {
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(state.registers().a, 0x75); // 6eb5 << 3
+    }
+
+    #[test]
+    fn test_offset_zero_reads_last_byte() {
+        let mut state = setup_state();
+        state.set_io(Box::new(invaders_io()));
+        let mut address = out(&mut state, 0, SHIFT_DATA_PORT, 0x42);
+        address = out(&mut state, address, SHIFT_OFFSET_PORT, 0);
+        input(&mut state, address, SHIFT_RESULT_PORT);
+        for _ in 0..5 {
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(state.registers().a, 0x42);
+    }
+
+    #[test]
+    fn test_inputs() {
+        let inputs = Rc::new(RefCell::new(Inputs::new(DipSwitches::default())));
+        let mut state = setup_state();
+        state.set_io(Box::new(InvadersIo::new(inputs.clone())));
+        let address = input(&mut state, 0, INPUT_PORT_1);
+        input(&mut state, address, INPUT_PORT_1);
+
+        state.emulate_op().unwrap();
+        assert_eq!(state.registers().a, 0x08);
+
+        inputs.borrow_mut().press(Control::Coin);
+        state.emulate_op().unwrap();
+        assert_eq!(state.registers().a, 0x09);
+    }
+
+    #[test]
+    fn test_sound_latches() {
+        let sound = Rc::new(RefCell::new(SoundBoard::new()));
+        let mut state = setup_state();
+        state.set_io(Box::new(invaders_io().with_sound(sound.clone())));
+        let address = out(&mut state, 0, SOUND_PORT_1, 0x02);
+        out(&mut state, address, SOUND_PORT_2, 0x10);
+        for _ in 0..4 {
+            state.emulate_op().unwrap();
+        }
+        assert_eq!(
+            sound.borrow_mut().take_events(),
+            vec![SoundEvent::Start(Sound::Shot), SoundEvent::Start(Sound::UfoHit)]
+        );
+    }
+}
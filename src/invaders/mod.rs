@@ -0,0 +1,8 @@
+// The Midway Space Invaders board: the 8080 plus the hardware around it
+pub mod inputs;
+pub mod io;
+pub mod scheduler;
+pub mod shifter;
+pub mod sound;
+pub mod video;
+pub mod wav;
@@ -0,0 +1,168 @@
+use crate::emulator::cpu::Cpu;
+use crate::emulator::error::EmulatorError;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+// The Invaders board runs the 8080 at 2 MHz and the screen at 60 Hz
+pub const CPU_HZ: u64 = 2_000_000;
+pub const FRAMES_PER_SECOND: u64 = 60;
+
+// The video hardware puts these on the data bus, RST 1 when the beam is half way down the screen
+// and RST 2 when it reaches the bottom, the game draws whichever half the beam isn't on
+pub const MID_SCREEN: u8 = 0xcf;
+pub const VBLANK: u8 = 0xd7;
+
+// If the host falls this far behind it gives up catching up rather than running flat out
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+// Runs the cpu a frame at a time, raising the two video interrupts in the right places. A frame
+// isn't a whole number of cycles, so each half frame's target is worked out from how many have
+// run since the start, and the time an instruction runs past one is taken off the next.
+pub struct FrameScheduler {
+    throttle: bool, // Wait for real time to catch up after each frame
+    start_cycle: u64,
+    half_frames: u64,
+    next_frame: Option<Instant>,
+    frames: u64,
+}
+
+impl FrameScheduler {
+    // Keeps to 60 frames a second by sleeping after each frame, for frontends that don't have
+    // their own clock
+    pub fn throttled() -> FrameScheduler {
+        return FrameScheduler::new(true);
+    }
+
+    // As fast as the host can go, for running headless or when the frontend paces the frames
+    pub fn unthrottled() -> FrameScheduler {
+        return FrameScheduler::new(false);
+    }
+
+    fn new(throttle: bool) -> FrameScheduler {
+        return FrameScheduler {
+            throttle,
+            start_cycle: 0,
+            half_frames: 0,
+            next_frame: None,
+            frames: 0,
+        };
+    }
+
+    // Frames run so far
+    pub fn frames(&self) -> u64 {
+        return self.frames;
+    }
+
+    // Runs the top half of the screen, RST 1, the bottom half, then RST 2. An interrupt raised
+    // while the cpu has them disabled is lost, like on the real board.
+    pub fn run_frame(&mut self, cpu: &mut dyn Cpu) -> Result<(), EmulatorError> {
+        if self.frames == 0 {
+            self.start_cycle = cpu.cycles();
+        }
+        self.run_half_frame(cpu)?;
+        cpu.interrupt(MID_SCREEN)?;
+        self.run_half_frame(cpu)?;
+        cpu.interrupt(VBLANK)?;
+        self.frames += 1;
+        if self.throttle {
+            self.wait_for_frame();
+        }
+        return Ok(());
+    }
+
+    fn run_half_frame(&mut self, cpu: &mut dyn Cpu) -> Result<(), EmulatorError> {
+        self.half_frames += 1;
+        let end = self.start_cycle + self.half_frames * CPU_HZ / (FRAMES_PER_SECOND * 2);
+        while cpu.cycles() < end {
+            cpu.step()?;
+        }
+        return Ok(());
+    }
+
+    fn wait_for_frame(&mut self) {
+        let frame = Duration::from_nanos(1_000_000_000 / FRAMES_PER_SECOND);
+        let now = Instant::now();
+        let next_frame = match self.next_frame {
+            Some(next_frame) if now < next_frame + MAX_LAG => next_frame,
+            _ => now,
+        };
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        }
+        self.next_frame = Some(next_frame + frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::scheduler::*;
+    use crate::emulator::test_utils::*;
+    use crate::emulator::State8080;
+
+    // A main loop that spins with interrupts on, and RST 1 and RST 2 handlers that count how
+    // often they run at 0x1000 and 0x1001
+    fn setup_game() -> State8080 {
+        let mut state = setup_state();
+        state.set_sp(0x2000);
+        let program: Vec<(u16, Vec<u8>)> = vec![
+            (0x0000, vec![0xfb, 0xc3, 0x01, 0x00]), // EI, JMP 0x0001
+            (0x0008, vec![0x21, 0x00, 0x10, 0x34, 0xfb, 0xc9]), // LXI H,0x1000, INR M, EI, RET
+            (0x0010, vec![0x21, 0x01, 0x10, 0x34, 0xfb, 0xc9]), // LXI H,0x1001, INR M, EI, RET
+        ];
+        for (address, bytes) in program {
+            for (i, byte) in bytes.iter().enumerate() {
+                state.poke(address + i as u16, *byte);
+            }
+        }
+        return state;
+    }
+
+    #[test]
+    fn test_interrupts_each_frame() {
+        let mut state = setup_game();
+        let mut scheduler = FrameScheduler::unthrottled();
+        for _ in 0..3 {
+            scheduler.run_frame(&mut state).unwrap();
+        }
+        assert_eq!(scheduler.frames(), 3);
+        assert_eq!(state.peek(0x1000), 3);
+        // The last RST 2 has been taken but its handler runs at the start of the next frame
+        assert_eq!(state.pc(), 0x0010);
+        assert_eq!(state.peek(0x1001), 2);
+    }
+
+    #[test]
+    fn test_cycles_dont_drift() {
+        let mut state = setup_game();
+        let mut scheduler = FrameScheduler::unthrottled();
+        for _ in 0..60 {
+            scheduler.run_frame(&mut state).unwrap();
+        }
+        // Only the last instruction and the last RST can run past a second's worth
+        assert_eq!(state.cycles() >= CPU_HZ, true);
+        assert_eq!(state.cycles() < CPU_HZ + 30, true);
+    }
+
+    #[test]
+    fn test_disabled_interrupts_are_dropped() {
+        let mut state = setup_game();
+        state.poke(0x0000, 0x00); // NOP instead of EI
+        let mut scheduler = FrameScheduler::unthrottled();
+        scheduler.run_frame(&mut state).unwrap();
+        assert_eq!(state.peek(0x1000), 0);
+        assert_eq!(state.peek(0x1001), 0);
+    }
+
+    #[test]
+    fn test_throttled_keeps_to_real_time() {
+        let mut state = setup_game();
+        let mut scheduler = FrameScheduler::throttled();
+        let start = Instant::now();
+        for _ in 0..4 {
+            scheduler.run_frame(&mut state).unwrap();
+        }
+        // The first frame starts the clock, the next 3 each wait for their 1/60 s
+        assert_eq!(start.elapsed() >= Duration::from_millis(49), true);
+    }
+}
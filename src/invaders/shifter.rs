@@ -0,0 +1,68 @@
+// The external shift register every Midway 8080 board has, the 8080 can only shift one bit at a
+// time so sprites are moved across byte boundaries with this instead:
+//  OUT 4   the new byte goes in the top half and the old top half moves down
+//  OUT 2   how many bits to shift by, only the bottom 3 bits are used
+//  IN 3    8 bits of the register starting offset bits down from the top
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ShiftRegister {
+    value: u16,
+    offset: u8,
+}
+
+impl ShiftRegister {
+    pub fn new() -> ShiftRegister {
+        return ShiftRegister { value: 0, offset: 0 };
+    }
+
+    pub fn load(&mut self, data: u8) {
+        self.value = ((data as u16) << 8) | (self.value >> 8);
+    }
+
+    pub fn set_offset(&mut self, offset: u8) {
+        self.offset = offset & 0x07;
+    }
+
+    pub fn result(&self) -> u8 {
+        return (self.value >> (8 - self.offset)) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::shifter::*;
+
+    #[test]
+    fn test_load() {
+        let mut shifter = ShiftRegister::new();
+        shifter.load(0xaa);
+        assert_eq!(shifter.result(), 0xaa);
+        shifter.load(0xff); // Now ffaa
+        assert_eq!(shifter.result(), 0xff);
+        shifter.set_offset(4);
+        assert_eq!(shifter.result(), 0xfa);
+        shifter.load(0x12); // Now 12ff
+        assert_eq!(shifter.result(), 0x2f);
+    }
+
+    #[test]
+    fn test_offset() {
+        let mut shifter = ShiftRegister::new();
+        shifter.load(0xf0); // Now f000
+        shifter.load(0x0f); // Now 0ff0
+        shifter.set_offset(0);
+        assert_eq!(shifter.result(), 0x0f);
+        shifter.set_offset(4);
+        assert_eq!(shifter.result(), 0xff);
+        shifter.set_offset(7);
+        assert_eq!(shifter.result(), 0xf8);
+    }
+
+    #[test]
+    fn test_offset_only_uses_3_bits() {
+        let mut shifter = ShiftRegister::new();
+        shifter.load(0x00);
+        shifter.load(0x01);
+        shifter.set_offset(0x0b);
+        assert_eq!(shifter.result(), 0x08);
+    }
+}
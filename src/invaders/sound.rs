@@ -0,0 +1,431 @@
+use crate::invaders::scheduler::FRAMES_PER_SECOND;
+use crate::invaders::wav::WavWriter;
+use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::io;
+use std::io::Seek;
+use std::io::Write;
+use std::rc::Rc;
+
+pub const SOUND_PORT_1: u8 = 3;
+pub const SOUND_PORT_2: u8 = 5;
+
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE as u64 / FRAMES_PER_SECOND) as usize;
+
+// The effects the sound board makes. Their order is the numbering sample sets use, 0.wav is the
+// UFO and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sound {
+    Ufo, // Keeps going while its bit is set
+    Shot,
+    PlayerDeath,
+    InvaderDeath,
+    Fleet1, // The 4 notes of the invaders marching
+    Fleet2,
+    Fleet3,
+    Fleet4,
+    UfoHit,
+    ExtraLife,
+}
+
+pub const SOUNDS: [Sound; 10] = [
+    Sound::Ufo,
+    Sound::Shot,
+    Sound::PlayerDeath,
+    Sound::InvaderDeath,
+    Sound::Fleet1,
+    Sound::Fleet2,
+    Sound::Fleet3,
+    Sound::Fleet4,
+    Sound::UfoHit,
+    Sound::ExtraLife,
+];
+
+// Which latch bit starts each sound, as (port, bit)
+fn wiring(sound: Sound) -> (u8, u8) {
+    return match sound {
+        Sound::Ufo => (SOUND_PORT_1, 0),
+        Sound::Shot => (SOUND_PORT_1, 1),
+        Sound::PlayerDeath => (SOUND_PORT_1, 2),
+        Sound::InvaderDeath => (SOUND_PORT_1, 3),
+        Sound::ExtraLife => (SOUND_PORT_1, 4),
+        Sound::Fleet1 => (SOUND_PORT_2, 0),
+        Sound::Fleet2 => (SOUND_PORT_2, 1),
+        Sound::Fleet3 => (SOUND_PORT_2, 2),
+        Sound::Fleet4 => (SOUND_PORT_2, 3),
+        Sound::UfoHit => (SOUND_PORT_2, 4),
+    };
+}
+
+// Bit 5 of port 3 turns the amplifier on and off
+const AMPLIFIER_BIT: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundEvent {
+    Start(Sound),
+    Stop(Sound), // Only the UFO is stopped, everything else plays out
+    Amplifier(bool),
+}
+
+// The two output latches, OUT 3 and OUT 5 write them and a sound starts when its bit goes high
+#[derive(Debug, Clone, Default)]
+pub struct SoundBoard {
+    port_1: u8,
+    port_2: u8,
+    events: Vec<SoundEvent>,
+}
+
+impl SoundBoard {
+    pub fn new() -> SoundBoard {
+        return SoundBoard { port_1: 0, port_2: 0, events: vec![] };
+    }
+
+    pub fn output(&mut self, port: u8, value: u8) {
+        let old = match port {
+            SOUND_PORT_1 => self.port_1,
+            SOUND_PORT_2 => self.port_2,
+            _ => return,
+        };
+        let rising = value & !old;
+        let falling = old & !value;
+        for sound in SOUNDS.iter() {
+            let (sound_port, bit) = wiring(*sound);
+            if sound_port != port {
+                continue;
+            }
+            if rising & (1 << bit) != 0 {
+                self.events.push(SoundEvent::Start(*sound));
+            } else if falling & (1 << bit) != 0 && *sound == Sound::Ufo {
+                self.events.push(SoundEvent::Stop(*sound));
+            }
+        }
+        if port == SOUND_PORT_1 && (rising | falling) & (1 << AMPLIFIER_BIT) != 0 {
+            self.events.push(SoundEvent::Amplifier(rising & (1 << AMPLIFIER_BIT) != 0));
+        }
+        match port {
+            SOUND_PORT_1 => self.port_1 = value,
+            _ => self.port_2 = value,
+        }
+    }
+
+    // Everything that happened since the last call
+    pub fn take_events(&mut self) -> Vec<SoundEvent> {
+        return self.events.drain(..).collect();
+    }
+}
+
+// Something that is playing, how far into its sound it has got
+struct Voice {
+    sound: Sound,
+    position: usize,
+}
+
+// Turns sound events into 16 bit mono samples at SAMPLE_RATE. Each sound is synthesized to
+// something close to the board's analog circuits unless a sample has been set for it.
+pub struct Mixer {
+    sounds: Vec<Vec<i16>>, // Indexed like SOUNDS
+    voices: Vec<Voice>,
+    amplifier: bool,
+}
+
+impl Default for Mixer {
+    fn default() -> Mixer {
+        return Mixer::new();
+    }
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        return Mixer {
+            sounds: SOUNDS.iter().map(|sound| synthesize(*sound)).collect(),
+            voices: vec![],
+            amplifier: false,
+        };
+    }
+
+    // Plays samples recorded at sample_rate for sound instead of the synthesized one
+    pub fn set_sample(&mut self, sound: Sound, sample_rate: u32, samples: &[i16]) {
+        self.sounds[index(sound)] = resample(samples, sample_rate, SAMPLE_RATE);
+    }
+
+    pub fn handle(&mut self, event: SoundEvent) {
+        match event {
+            SoundEvent::Start(sound) => {
+                // Starting a sound again restarts it rather than doubling it up
+                self.voices.retain(|voice| voice.sound != sound);
+                self.voices.push(Voice { sound, position: 0 });
+            }
+            SoundEvent::Stop(sound) => self.voices.retain(|voice| voice.sound != sound),
+            SoundEvent::Amplifier(on) => self.amplifier = on,
+        }
+    }
+
+    // The next count samples
+    pub fn render(&mut self, count: usize) -> Vec<i16> {
+        let mut mixed = vec![0i32; count];
+        for voice in self.voices.iter_mut() {
+            let sound = &self.sounds[index(voice.sound)];
+            if sound.is_empty() {
+                continue;
+            }
+            for sample in mixed.iter_mut() {
+                if voice.position >= sound.len() {
+                    if voice.sound != Sound::Ufo {
+                        break;
+                    }
+                    voice.position = 0;
+                }
+                *sample += sound[voice.position] as i32;
+                voice.position += 1;
+            }
+        }
+        let sounds = &self.sounds;
+        self.voices.retain(|voice| {
+            voice.sound == Sound::Ufo || voice.position < sounds[index(voice.sound)].len()
+        });
+        if !self.amplifier {
+            return vec![0; count];
+        }
+        return mixed.iter()
+            .map(|sample| (*sample).max(i16::MIN as i32).min(i16::MAX as i32) as i16)
+            .collect();
+    }
+}
+
+// Writes everything the sound board plays to a WAV file, call frame after every frame the cpu runs
+pub struct SoundRecorder<W: Write + Seek> {
+    board: Rc<RefCell<SoundBoard>>,
+    mixer: Mixer,
+    wav: WavWriter<W>,
+}
+
+impl<W: Write + Seek> SoundRecorder<W> {
+    pub fn new(
+        board: Rc<RefCell<SoundBoard>>,
+        mixer: Mixer,
+        writer: W,
+    ) -> io::Result<SoundRecorder<W>> {
+        let wav = WavWriter::new(writer, SAMPLE_RATE)?;
+        return Ok(SoundRecorder { board, mixer, wav });
+    }
+
+    pub fn frame(&mut self) -> io::Result<()> {
+        for event in self.board.borrow_mut().take_events() {
+            self.mixer.handle(event);
+        }
+        return self.wav.write(&self.mixer.render(SAMPLES_PER_FRAME));
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        return self.wav.finish();
+    }
+}
+
+fn index(sound: Sound) -> usize {
+    return SOUNDS.iter().position(|other| *other == sound).unwrap();
+}
+
+// Loud enough that a few sounds at once rarely clip
+const VOLUME: f64 = 8000.0;
+
+// One second of samples
+const SECOND: f64 = SAMPLE_RATE as f64;
+
+// Approximations of each sound, a loop of the UFO's warble and the whole of everything else
+fn synthesize(sound: Sound) -> Vec<i16> {
+    return match sound {
+        Sound::Ufo => tone(0.25, |t| 700.0 + 200.0 * (2.0 * PI * 4.0 * t).sin(), |_| 0.6, false),
+        Sound::Shot => tone(0.3, |t| 1200.0 - 3000.0 * t, |t| 1.0 - t / 0.3, true),
+        Sound::PlayerDeath => noise(1.2),
+        Sound::InvaderDeath => noise(0.3),
+        Sound::Fleet1 => tone(0.1, |_| 98.0, |_| 1.0, false),
+        Sound::Fleet2 => tone(0.1, |_| 87.0, |_| 1.0, false),
+        Sound::Fleet3 => tone(0.1, |_| 78.0, |_| 1.0, false),
+        Sound::Fleet4 => tone(0.1, |_| 73.0, |_| 1.0, false),
+        Sound::UfoHit => tone(1.0, |t| 1500.0 - 1000.0 * t, |t| 1.0 - t, false),
+        // Three beeps
+        Sound::ExtraLife => tone(0.6, |_| 1000.0, |t| if t % 0.2 < 0.1 { 0.8 } else { 0.0 }, false),
+    };
+}
+
+// A square wave, or a sine if smooth, that lasts seconds with its frequency and volume as
+// functions of time. The phase is accumulated so frequency sweeps don't click.
+fn tone<F, A>(seconds: f64, frequency: F, amplitude: A, smooth: bool) -> Vec<i16>
+where
+    F: Fn(f64) -> f64,
+    A: Fn(f64) -> f64,
+{
+    let mut phase = 0.0;
+    return (0..(seconds * SECOND) as usize)
+        .map(|i| {
+            let t = i as f64 / SECOND;
+            phase = (phase + frequency(t).max(0.0) / SECOND) % 1.0;
+            let wave = if smooth {
+                (2.0 * PI * phase).sin()
+            } else if phase < 0.5 {
+                1.0
+            } else {
+                -1.0
+            };
+            (wave * amplitude(t) * VOLUME) as i16
+        })
+        .collect();
+}
+
+// White noise that dies away over seconds, from a 16 bit LFSR so it is the same every time
+fn noise(seconds: f64) -> Vec<i16> {
+    let length = (seconds * SECOND) as usize;
+    let mut lfsr: u16 = 0xace1;
+    return (0..length)
+        .map(|i| {
+            let bit = (lfsr ^ (lfsr >> 2) ^ (lfsr >> 3) ^ (lfsr >> 5)) & 1;
+            lfsr = (lfsr >> 1) | (bit << 15);
+            let wave = if lfsr & 1 != 0 { 1.0 } else { -1.0 };
+            let fade = 1.0 - i as f64 / length as f64;
+            (wave * fade * VOLUME) as i16
+        })
+        .collect();
+}
+
+// Nearest neighbour, good enough for these sounds
+fn resample(samples: &[i16], from: u32, to: u32) -> Vec<i16> {
+    if from == to || from == 0 {
+        return samples.to_vec();
+    }
+    let length = samples.len() as u64 * to as u64 / from as u64;
+    return (0..length)
+        .map(|i| samples[(i * from as u64 / to as u64) as usize])
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::sound::*;
+    use crate::invaders::wav::read_wav;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_rising_edges_start_sounds() {
+        let mut board = SoundBoard::new();
+        board.output(SOUND_PORT_1, 0x22); // Amplifier on, shot
+        board.output(SOUND_PORT_1, 0x22); // Still held, nothing new
+        board.output(SOUND_PORT_1, 0x2a); // Invader death
+        board.output(SOUND_PORT_2, 0x01); // Fleet note
+        board.output(SOUND_PORT_2, 0x02); // The next one
+        assert_eq!(
+            board.take_events(),
+            vec![
+                SoundEvent::Start(Sound::Shot),
+                SoundEvent::Amplifier(true),
+                SoundEvent::Start(Sound::InvaderDeath),
+                SoundEvent::Start(Sound::Fleet1),
+                SoundEvent::Start(Sound::Fleet2),
+            ]
+        );
+        assert_eq!(board.take_events(), vec![]);
+    }
+
+    #[test]
+    fn test_ufo_stops_when_its_bit_drops() {
+        let mut board = SoundBoard::new();
+        board.output(SOUND_PORT_1, 0x21);
+        board.output(SOUND_PORT_1, 0x00);
+        assert_eq!(
+            board.take_events(),
+            vec![
+                SoundEvent::Start(Sound::Ufo),
+                SoundEvent::Amplifier(true),
+                SoundEvent::Stop(Sound::Ufo),
+                SoundEvent::Amplifier(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_other_ports_ignored() {
+        let mut board = SoundBoard::new();
+        board.output(6, 0xff);
+        assert_eq!(board.take_events(), vec![]);
+    }
+
+    #[test]
+    fn test_mixer_silent_without_amplifier() {
+        let mut mixer = Mixer::new();
+        mixer.handle(SoundEvent::Start(Sound::Shot));
+        assert_eq!(mixer.render(100).iter().all(|sample| *sample == 0), true);
+    }
+
+    #[test]
+    fn test_mixer_plays_samples() {
+        let mut mixer = Mixer::new();
+        mixer.set_sample(Sound::Shot, SAMPLE_RATE, &[100, 200, 300]);
+        mixer.set_sample(Sound::Fleet1, SAMPLE_RATE, &[10, 20]);
+        mixer.handle(SoundEvent::Amplifier(true));
+        mixer.handle(SoundEvent::Start(Sound::Shot));
+        mixer.handle(SoundEvent::Start(Sound::Fleet1));
+        assert_eq!(mixer.render(2), vec![110, 220]);
+        assert_eq!(mixer.render(3), vec![300, 0, 0]);
+    }
+
+    #[test]
+    fn test_mixer_loops_ufo_until_stopped() {
+        let mut mixer = Mixer::new();
+        mixer.set_sample(Sound::Ufo, SAMPLE_RATE, &[1, 2]);
+        mixer.handle(SoundEvent::Amplifier(true));
+        mixer.handle(SoundEvent::Start(Sound::Ufo));
+        assert_eq!(mixer.render(5), vec![1, 2, 1, 2, 1]);
+        mixer.handle(SoundEvent::Stop(Sound::Ufo));
+        assert_eq!(mixer.render(2), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_mixer_clips() {
+        let mut mixer = Mixer::new();
+        mixer.set_sample(Sound::Shot, SAMPLE_RATE, &[30000]);
+        mixer.set_sample(Sound::PlayerDeath, SAMPLE_RATE, &[30000]);
+        mixer.handle(SoundEvent::Amplifier(true));
+        mixer.handle(SoundEvent::Start(Sound::Shot));
+        mixer.handle(SoundEvent::Start(Sound::PlayerDeath));
+        assert_eq!(mixer.render(1), vec![i16::MAX]);
+    }
+
+    #[test]
+    fn test_resample() {
+        let mut mixer = Mixer::new();
+        mixer.set_sample(Sound::Shot, SAMPLE_RATE / 2, &[1, 2]);
+        mixer.handle(SoundEvent::Amplifier(true));
+        mixer.handle(SoundEvent::Start(Sound::Shot));
+        assert_eq!(mixer.render(5), vec![1, 1, 2, 2, 0]);
+    }
+
+    #[test]
+    fn test_synthesized_sounds() {
+        for sound in SOUNDS.iter() {
+            let samples = synthesize(*sound);
+            assert_eq!(samples.is_empty(), false);
+            assert_eq!(samples.iter().any(|sample| *sample != 0), true);
+        }
+    }
+
+    #[test]
+    fn test_recorder() {
+        let board = Rc::new(RefCell::new(SoundBoard::new()));
+        let mut mixer = Mixer::new();
+        mixer.set_sample(Sound::Shot, SAMPLE_RATE, &[1000; 1000]);
+        let mut recorder = SoundRecorder::new(board.clone(), mixer, Cursor::new(vec![])).unwrap();
+        recorder.frame().unwrap();
+        board.borrow_mut().output(SOUND_PORT_1, 0x22);
+        recorder.frame().unwrap();
+        recorder.frame().unwrap();
+        let bytes = recorder.finish().unwrap().into_inner();
+
+        let (sample_rate, samples) = read_wav(Cursor::new(bytes)).unwrap();
+        assert_eq!(sample_rate, SAMPLE_RATE);
+        assert_eq!(samples.len(), SAMPLES_PER_FRAME * 3);
+        assert_eq!(samples[SAMPLES_PER_FRAME - 1], 0);
+        assert_eq!(samples[SAMPLES_PER_FRAME], 1000);
+        assert_eq!(samples[SAMPLES_PER_FRAME + 999], 1000);
+        assert_eq!(samples[SAMPLES_PER_FRAME + 1000], 0);
+    }
+}
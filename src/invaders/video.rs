@@ -0,0 +1,74 @@
+use crate::emulator::cpu::Cpu;
+
+// The 1 bit framebuffer, one bit per pixel with bit 0 of each byte the leftmost
+pub const VRAM_START: u16 = 0x2400;
+pub const VRAM_SIZE: usize = 0x1c00;
+
+// The monitor is mounted on its side, so the 256x224 picture in VRAM is shown turned 90° anti
+// clockwise as 224 wide and 256 high
+pub const SCREEN_WIDTH: usize = 224;
+pub const SCREEN_HEIGHT: usize = 256;
+
+// Bytes in one line of VRAM, a column of the screen
+const BYTES_PER_LINE: usize = 32;
+
+pub const PIXEL_ON: u8 = 0xff;
+pub const PIXEL_OFF: u8 = 0x00;
+
+// Fills frame with the screen as it is right now, a byte per pixel a row at a time from the top
+// left. frame has to be SCREEN_WIDTH * SCREEN_HEIGHT long.
+pub fn render(cpu: &dyn Cpu, frame: &mut [u8]) {
+    assert_eq!(frame.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+    for x in 0..SCREEN_WIDTH {
+        for byte in 0..BYTES_PER_LINE {
+            let offset = x * BYTES_PER_LINE + byte;
+            let value = cpu.peek(VRAM_START + offset as u16);
+            for bit in 0..8 {
+                // The start of a VRAM line is the bottom of the screen
+                let y = SCREEN_HEIGHT - 1 - (byte * 8 + bit);
+                frame[y * SCREEN_WIDTH + x] = if value & (1 << bit) != 0 {
+                    PIXEL_ON
+                } else {
+                    PIXEL_OFF
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::video::*;
+    use crate::emulator::test_utils::*;
+
+    fn pixel(frame: &[u8], x: usize, y: usize) -> u8 {
+        return frame[y * SCREEN_WIDTH + x];
+    }
+
+    #[test]
+    fn test_blank_screen() {
+        let state = setup_state();
+        let mut frame = vec![PIXEL_ON; SCREEN_WIDTH * SCREEN_HEIGHT];
+        render(&state, &mut frame);
+        assert_eq!(frame.iter().all(|pixel| *pixel == PIXEL_OFF), true);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut state = setup_state();
+        state.poke(VRAM_START, 0x01); // Bottom left corner
+        state.poke(VRAM_START + 31, 0x80); // Top left corner
+        state.poke(VRAM_START + 0x1c00 - 32, 0x01); // Bottom right corner
+        state.poke(VRAM_START + 0x1c00 - 1, 0x80); // Top right corner
+        state.poke(VRAM_START + 32 * 10 + 1, 0x04); // Column 10, 8 + 2 from the bottom
+        let mut frame = vec![PIXEL_OFF; SCREEN_WIDTH * SCREEN_HEIGHT];
+        render(&state, &mut frame);
+
+        assert_eq!(pixel(&frame, 0, 255), PIXEL_ON);
+        assert_eq!(pixel(&frame, 0, 0), PIXEL_ON);
+        assert_eq!(pixel(&frame, 223, 255), PIXEL_ON);
+        assert_eq!(pixel(&frame, 223, 0), PIXEL_ON);
+        assert_eq!(pixel(&frame, 10, 255 - 10), PIXEL_ON);
+        assert_eq!(frame.iter().filter(|pixel| **pixel == PIXEL_ON).count(), 5);
+    }
+}
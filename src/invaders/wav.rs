@@ -0,0 +1,177 @@
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+// Bytes before the samples in the files written here, RIFF header, fmt chunk and data chunk header
+const HEADER_SIZE: u32 = 44;
+
+// Writes 16 bit mono PCM to a WAV file as it comes, the sizes in the header are filled in by
+// finish so the whole session never has to be held in memory
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    samples: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?; // Filled in by finish
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&1u16.to_le_bytes())?; // Mono
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * 2).to_le_bytes())?; // Bytes a second
+        writer.write_all(&2u16.to_le_bytes())?; // Bytes a sample
+        writer.write_all(&16u16.to_le_bytes())?; // Bits a sample
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?; // Filled in by finish
+        return Ok(WavWriter { writer, samples: 0 });
+    }
+
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        return Ok(());
+    }
+
+    // Fills in the sizes and gives the writer back
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * 2;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        return Ok(self.writer);
+    }
+}
+
+// Reads a PCM WAV file, 8 or 16 bit, returns its sample rate and the first channel as 16 bit
+// samples. Other chunks are skipped.
+pub fn read_wav<R: Read>(mut reader: R) -> io::Result<(u32, Vec<i16>)> {
+    let mut contents = vec![];
+    reader.read_to_end(&mut contents)?;
+    if contents.len() < 12 || &contents[0..4] != b"RIFF" || &contents[8..12] != b"WAVE" {
+        return Err(invalid("Not a WAV file"));
+    }
+    let mut format: Option<(u16, u32, u16)> = None; // Channels, sample rate, bits a sample
+    let mut position = 12;
+    while position + 8 <= contents.len() {
+        let id = &contents[position..position + 4];
+        let size = u32_at(&contents, position + 4) as usize;
+        let start = position + 8;
+        let end = (start + size).min(contents.len());
+        let chunk = &contents[start..end];
+        if id == b"fmt " {
+            if chunk.len() < 16 || u16_at(chunk, 0) != 1 {
+                return Err(invalid("Only PCM WAV files are supported"));
+            }
+            format = Some((u16_at(chunk, 2), u32_at(chunk, 4), u16_at(chunk, 14)));
+        } else if id == b"data" {
+            let (channels, sample_rate, bits) = match format {
+                Some(format) => format,
+                None => return Err(invalid("WAV data before its format")),
+            };
+            if bits != 8 && bits != 16 {
+                return Err(invalid("Only 8 and 16 bit WAV files are supported"));
+            }
+            let width = (bits / 8) as usize;
+            let frame = width * channels.max(1) as usize;
+            let samples = chunk.chunks(frame)
+                .filter(|frame| frame.len() >= width)
+                .map(|frame| match bits {
+                    8 => ((frame[0] as i16) - 128) << 8,
+                    _ => i16::from_le_bytes([frame[0], frame[1]]),
+                })
+                .collect();
+            return Ok((sample_rate, samples));
+        }
+        // Chunks are padded to an even size
+        position = start + size + (size & 1);
+    }
+    return Err(invalid("WAV file has no data"));
+}
+
+fn invalid(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+fn u16_at(buffer: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes([buffer[offset], buffer[offset + 1]]);
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    return u32::from_le_bytes(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invaders::wav::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_header() {
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 44100).unwrap();
+        wav.write(&[0, 1, -1]).unwrap();
+        wav.write(&[0x1234]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 24), 44100);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 8);
+        assert_eq!(&bytes[44..], &[0x00, 0x00, 0x01, 0x00, 0xff, 0xff, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_read_what_was_written() {
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 11025).unwrap();
+        wav.write(&[100, -200, 300]).unwrap();
+        let bytes = wav.finish().unwrap().into_inner();
+
+        let (sample_rate, samples) = read_wav(Cursor::new(bytes)).unwrap();
+        assert_eq!(sample_rate, 11025);
+        assert_eq!(samples, vec![100, -200, 300]);
+    }
+
+    #[test]
+    fn test_read_8_bit_stereo() {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(b"WAVE");
+        bytes.extend_from_slice(b"LIST");
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3, 0]); // Odd sized, so padded
+        bytes.extend_from_slice(b"fmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 2, 0]); // PCM, stereo
+        bytes.extend_from_slice(&8000u32.to_le_bytes());
+        bytes.extend_from_slice(&16000u32.to_le_bytes());
+        bytes.extend_from_slice(&[2, 0, 8, 0]);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&[0x80, 0x00, 0xff, 0x00]);
+
+        let (sample_rate, samples) = read_wav(Cursor::new(bytes)).unwrap();
+        assert_eq!(sample_rate, 8000);
+        assert_eq!(samples, vec![0, 0x7f00]);
+    }
+
+    #[test]
+    fn test_read_not_a_wav() {
+        assert_eq!(read_wav(Cursor::new(b"hello".to_vec())).is_err(), true);
+    }
+}
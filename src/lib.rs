@@ -0,0 +1,41 @@
+// An emulator for the Intel 8080. State8080 is the cpu, memory and port accesses go through the
+// Memory and IoBus traits so machines can attach their own hardware.
+pub mod decoder;
+pub mod disassembler;
+pub mod emulator;
+pub mod invaders;
+pub mod loader;
+
+pub use crate::decoder::decode;
+pub use crate::decoder::decode_for;
+pub use crate::decoder::Instruction;
+pub use crate::decoder::Op;
+pub use crate::disassembler::disassemble_op;
+pub use crate::disassembler::disassemble_op_for;
+pub use crate::disassembler::disassemble_op_with;
+pub use crate::emulator::config::CpuVariant;
+pub use crate::emulator::config::State8080Builder;
+pub use crate::emulator::cpu::Cpu;
+pub use crate::emulator::error::EmulatorError;
+pub use crate::emulator::error::UnknownOpcodePolicy;
+pub use crate::emulator::i8085::InterruptPin;
+pub use crate::emulator::io::IoBus;
+pub use crate::emulator::io::LoggingIoBus;
+pub use crate::emulator::memory::Memory;
+pub use crate::emulator::memory::MemoryDevice;
+pub use crate::emulator::memory::MemoryMap;
+pub use crate::emulator::memory::Region;
+pub use crate::emulator::registers::Flag;
+pub use crate::emulator::registers::Reg;
+pub use crate::emulator::registers::RegPair;
+pub use crate::emulator::registers::Registers;
+pub use crate::emulator::trace::BinaryTraceSink;
+pub use crate::emulator::trace::JsonLinesTraceSink;
+pub use crate::emulator::trace::LogTraceSink;
+pub use crate::emulator::trace::TextTraceSink;
+pub use crate::emulator::trace::TraceRecord;
+pub use crate::emulator::trace::TraceSink;
+pub use crate::emulator::z80::InterruptMode;
+pub use crate::emulator::z80::Z80Registers;
+pub use crate::emulator::ConditionCodes;
+pub use crate::emulator::State8080;
@@ -0,0 +1,40 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Size of the 8080's whole address space
+pub const MEMORY_SIZE: usize = 65_536;
+
+// Reads a program image and places it at address 0 of a full 64K memory, the rest is zeroed
+pub fn load_program<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut memory = fs::read(path)?;
+    if memory.len() > MEMORY_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Program is bigger than 64K"));
+    }
+    memory.resize(MEMORY_SIZE, 0);
+    return Ok(memory);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::*;
+    use std::env;
+
+    #[test]
+    fn test_load_program() {
+        let path = env::temp_dir().join("rusty8080_test_load_program.bin");
+        fs::write(&path, vec![0xc3, 0x00, 0x01]).unwrap();
+
+        let memory = load_program(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(memory.len(), MEMORY_SIZE);
+        assert_eq!(&memory[0..4], &[0xc3, 0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn test_load_missing_program() {
+        let path = env::temp_dir().join("rusty8080_test_missing_program.bin");
+        assert_eq!(load_program(&path).is_err(), true);
+    }
+}
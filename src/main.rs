@@ -1,12 +1,45 @@
 use clap::App;
 use clap::Arg;
 use clap::ArgGroup;
+use log::error;
 use log::info;
+use log::log_enabled;
+use log::warn;
+use log::Level;
 use piston_window::*;
+use rusty8080::disassemble_op_with;
+use rusty8080::invaders::inputs::Control;
+use rusty8080::invaders::inputs::DipSwitches;
+use rusty8080::invaders::inputs::Inputs;
+use rusty8080::invaders::io::InvadersIo;
+use rusty8080::invaders::scheduler::FrameScheduler;
+use rusty8080::invaders::scheduler::FRAMES_PER_SECOND;
+use rusty8080::invaders::sound::Mixer;
+use rusty8080::invaders::sound::SoundBoard;
+use rusty8080::invaders::sound::SoundRecorder;
+use rusty8080::invaders::sound::SOUNDS;
+use rusty8080::invaders::video::render;
+use rusty8080::invaders::video::PIXEL_OFF;
+use rusty8080::invaders::video::SCREEN_HEIGHT;
+use rusty8080::invaders::video::SCREEN_WIDTH;
+use rusty8080::invaders::wav::read_wav;
+use rusty8080::BinaryTraceSink;
+use rusty8080::Cpu;
+use rusty8080::CpuVariant;
+use rusty8080::JsonLinesTraceSink;
+use rusty8080::LogTraceSink;
+use rusty8080::State8080;
+use rusty8080::TextTraceSink;
+use rusty8080::TraceSink;
+use std::cell::RefCell;
 use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::rc::Rc;
 
-mod disassembler;
-mod emulator;
+// Each pixel of the screen is drawn this many pixels across and down
+const SCALE: u32 = 2;
 
 fn main() {
     let args = App::new("rusty8080")
@@ -37,6 +70,11 @@ fn main() {
                 .default_value("10")
                 .help("Number of operations"),
         )
+        .arg(
+            Arg::with_name("flagUndocumented")
+                .long("flagUndocumented")
+                .help("Marks undocumented op codes when disassembling"),
+        )
         .arg(
             Arg::with_name("file")
                 .short("f")
@@ -46,6 +84,74 @@ fn main() {
                 .help("The file to emulate")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("cpu")
+                .long("cpu")
+                .possible_values(&["8080", "8085", "z80"])
+                .default_value("8080")
+                .help("The cpu the program is written for"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .short("t")
+                .long("trace")
+                .value_name("FILE")
+                .help("Writes every instruction run to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("traceFormat")
+                .long("traceFormat")
+                .possible_values(&["text", "json", "binary"])
+                .default_value("text")
+                .help("Format of the trace file"),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .long("headless")
+                .help("Emulate without a window, as fast as possible"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .default_value("3600")
+                .help("Number of frames to run headless"),
+        )
+        .arg(
+            Arg::with_name("ships")
+                .long("ships")
+                .possible_values(&["3", "4", "5", "6"])
+                .default_value("3")
+                .help("DIP switch: ships per game"),
+        )
+        .arg(
+            Arg::with_name("bonusLife")
+                .long("bonusLife")
+                .possible_values(&["1000", "1500"])
+                .default_value("1500")
+                .help("DIP switch: points for an extra ship"),
+        )
+        .arg(
+            Arg::with_name("coinInfo")
+                .long("coinInfo")
+                .possible_values(&["on", "off"])
+                .default_value("on")
+                .help("DIP switch: show the coin info on the demo screen"),
+        )
+        .arg(
+            Arg::with_name("wav")
+                .long("wav")
+                .value_name("FILE")
+                .help("Writes the sound to FILE as a WAV")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("samples")
+                .long("samples")
+                .value_name("DIR")
+                .help("Plays 0.wav to 9.wav from DIR instead of the synthesized sounds")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("logFile")
                 .short("l")
@@ -66,46 +172,208 @@ fn main() {
         .parse::<usize>()
         .unwrap_or(10);
 
+    let variant = match args.value_of("cpu").unwrap() {
+        "8085" => CpuVariant::Intel8085,
+        "z80" => CpuVariant::Z80,
+        _ => CpuVariant::Intel8080,
+    };
+
     if args.is_present("emulate") {
-        emulate(filename, num_operations);
+        let trace = trace_sink(args.value_of("trace"), args.value_of("traceFormat").unwrap());
+        let dip_switches = DipSwitches {
+            ships: args.value_of("ships").unwrap().parse::<u8>().unwrap_or(3),
+            bonus_life_at_1000: args.value_of("bonusLife").unwrap() == "1000",
+            coin_info: args.value_of("coinInfo").unwrap() == "on",
+        };
+        let headless_frames = if args.is_present("headless") {
+            Some(args.value_of("frames").unwrap().parse::<u64>().unwrap_or(3600))
+        } else {
+            None
+        };
+        let sound = args.value_of("wav").map(|path| (path, args.value_of("samples")));
+        emulate(filename, variant, dip_switches, headless_frames, sound, trace);
     } else {
-        disassemble(filename, num_operations);
+        disassemble(filename, num_operations, variant, args.is_present("flagUndocumented"));
     }
 }
 
-fn disassemble(filename: &str, requested_bytes: usize) {
+fn disassemble(
+    filename: &str,
+    requested_bytes: usize,
+    variant: CpuVariant,
+    flag_undocumented: bool,
+) {
     info!("Opening: {}", filename);
     let contents = fs::read(filename).expect("Could not open file");
     let mut program_counter: usize = 0;
     while program_counter < requested_bytes && program_counter < contents.len() {
-        let (code, byes_used) = disassembler::disassemble_op(&contents, program_counter);
+        let (code, byes_used) =
+            disassemble_op_with(variant, &contents, program_counter, flag_undocumented);
         program_counter += byes_used;
         println!("{}", code);
     }
 }
 
-fn emulate(filename: &str, num_operations: usize) {
-    let mut window: PistonWindow = WindowSettings::new("Hello Piston!", [640, 480])
+// Without a trace file instructions still go to the log when it is at debug level
+fn trace_sink(path: Option<&str>, format: &str) -> Option<Box<dyn TraceSink>> {
+    let path = match path {
+        Some(path) => path,
+        None if log_enabled!(Level::Debug) => return Some(Box::new(LogTraceSink)),
+        None => return None,
+    };
+    let writer = BufWriter::new(File::create(path).expect("Could not create trace file"));
+    return match format {
+        "json" => Some(Box::new(JsonLinesTraceSink::new(writer))),
+        "binary" => Some(Box::new(BinaryTraceSink::new(writer))),
+        _ => Some(Box::new(TextTraceSink::new(writer))),
+    };
+}
+
+fn emulate(
+    filename: &str,
+    variant: CpuVariant,
+    dip_switches: DipSwitches,
+    headless_frames: Option<u64>,
+    sound: Option<(&str, Option<&str>)>,
+    trace: Option<Box<dyn TraceSink>>,
+) {
+    info!("Opening: {}", filename);
+    let program = fs::read(filename).expect("Could not open file");
+
+    let mut state = State8080::builder().program(program).variant(variant).build();
+    let inputs = Rc::new(RefCell::new(Inputs::new(dip_switches)));
+    let mut io = InvadersIo::new(inputs.clone());
+    let mut recorder = None;
+    if let Some((path, samples)) = sound {
+        let board = Rc::new(RefCell::new(SoundBoard::new()));
+        io = io.with_sound(board.clone());
+        let writer = BufWriter::new(File::create(path).expect("Could not create sound file"));
+        let recording = SoundRecorder::new(board, mixer(samples), writer);
+        recorder = Some(recording.expect("Could not write sound file"));
+    }
+    state.set_io(Box::new(io));
+    if let Some(sink) = trace {
+        state.set_trace_sink(sink);
+    }
+
+    match headless_frames {
+        Some(frames) => run_headless(&mut state, frames, &mut recorder),
+        None => run_window(&mut state, &inputs, &mut recorder),
+    }
+    if let Some(recorder) = recorder {
+        recorder.finish().expect("Could not write sound file");
+    }
+}
+
+// Synthesized sounds, replaced by any of 0.wav to 9.wav there are in the samples directory
+fn mixer(samples: Option<&str>) -> Mixer {
+    let mut mixer = Mixer::new();
+    if let Some(dir) = samples {
+        for (i, sound) in SOUNDS.iter().enumerate() {
+            let path = Path::new(dir).join(format!("{}.wav", i));
+            match File::open(&path).and_then(read_wav) {
+                Ok((sample_rate, data)) => mixer.set_sample(*sound, sample_rate, &data),
+                Err(e) => warn!("Using the synthesized {:?}, {}: {}", sound, path.display(), e),
+            }
+        }
+    }
+    return mixer;
+}
+
+// Mixes the sound for the frame that was just run
+fn record_frame(recorder: &mut Option<SoundRecorder<BufWriter<File>>>) {
+    if let Some(recorder) = recorder {
+        recorder.frame().expect("Could not write sound file");
+    }
+}
+
+// Runs frames as fast as possible with nothing to show them on
+fn run_headless(
+    cpu: &mut dyn Cpu,
+    frames: u64,
+    recorder: &mut Option<SoundRecorder<BufWriter<File>>>,
+) {
+    let mut scheduler = FrameScheduler::unthrottled();
+    while scheduler.frames() < frames {
+        if let Err(e) = scheduler.run_frame(cpu) {
+            error!("Stopped: {}", e);
+            break;
+        }
+        record_frame(recorder);
+    }
+    info!("Ran {} frames, {} cycles", scheduler.frames(), cpu.cycles());
+}
+
+// Shows the screen and plays in real time until the window is closed
+fn run_window(
+    state: &mut State8080,
+    inputs: &Rc<RefCell<Inputs>>,
+    recorder: &mut Option<SoundRecorder<BufWriter<File>>>,
+) {
+    let size = [SCREEN_WIDTH as u32 * SCALE, SCREEN_HEIGHT as u32 * SCALE];
+    let mut window: PistonWindow = WindowSettings::new("rusty8080", size)
         .exit_on_esc(true)
         .build()
         .unwrap();
+    // Piston's update events keep to real time, sleeping in the scheduler would hold up input and
+    // rendering
+    window.set_ups(FRAMES_PER_SECOND);
+    let mut scheduler = FrameScheduler::unthrottled();
+    let mut frame = vec![PIXEL_OFF; SCREEN_WIDTH * SCREEN_HEIGHT];
+    let mut running = true;
     while let Some(event) = window.next() {
-        window.draw_2d(&event, |context, graphics| {
-            clear([1.0; 4], graphics);
-            rectangle(
-                [1.0, 0.0, 0.0, 1.0], // red
-                [0.0, 0.0, 100.0, 100.0],
-                context.transform,
-                graphics,
-            );
-        });
+        if let Some(Button::Keyboard(key)) = event.press_args() {
+            if let Some(control) = control_for(key) {
+                inputs.borrow_mut().press(control);
+            }
+        }
+        if let Some(Button::Keyboard(key)) = event.release_args() {
+            if let Some(control) = control_for(key) {
+                inputs.borrow_mut().release(control);
+            }
+        }
+        if event.update_args().is_some() && running {
+            match scheduler.run_frame(state) {
+                Ok(()) => record_frame(recorder),
+                Err(e) => {
+                    error!("Stopped: {}", e);
+                    running = false;
+                }
+            }
+        }
+        if event.render_args().is_some() {
+            render(state, &mut frame);
+            // Lit pixels are opaque white, the rest let the black background through
+            let texture = G2dTexture::from_memory_alpha(
+                &mut window.factory,
+                &frame,
+                SCREEN_WIDTH as u32,
+                SCREEN_HEIGHT as u32,
+                &TextureSettings::new(),
+            )
+            .unwrap();
+            window.draw_2d(&event, |context, graphics| {
+                clear([0.0, 0.0, 0.0, 1.0], graphics);
+                let transform = context.transform.scale(SCALE as f64, SCALE as f64);
+                image(&texture, transform, graphics);
+            });
+        }
     }
-    info!("Opening: {}", filename);
-    let mut game_memory = fs::read(filename).expect("Could not open file");
-    game_memory.resize(65_536, 0);
+}
 
-    let mut state = emulator::State8080::new(game_memory);
-    for _ in 0..num_operations {
-        state.emulate_op();
-    }
+// Player 1 uses the arrow keys and space, player 2 A, D and W
+fn control_for(key: Key) -> Option<Control> {
+    return match key {
+        Key::C => Some(Control::Coin),
+        Key::D1 => Some(Control::Start1),
+        Key::D2 => Some(Control::Start2),
+        Key::Space => Some(Control::Fire1),
+        Key::Left => Some(Control::Left1),
+        Key::Right => Some(Control::Right1),
+        Key::W => Some(Control::Fire2),
+        Key::A => Some(Control::Left2),
+        Key::D => Some(Control::Right2),
+        Key::T => Some(Control::Tilt),
+        _ => None,
+    };
 }